@@ -2,6 +2,7 @@ use proc_macro::TokenStream;
 
 mod protocol;
 mod structs;
+mod xflash;
 
 macro_rules! compile_err {
     ($at:expr, $err:literal) => {
@@ -15,3 +16,8 @@ pub(crate) use compile_err;
 pub fn da_legacy(input: TokenStream) -> TokenStream {
     protocol::da_legacy(input)
 }
+
+#[proc_macro_derive(XFlash, attributes(xflash))]
+pub fn xflash(input: TokenStream) -> TokenStream {
+    xflash::xflash(input)
+}
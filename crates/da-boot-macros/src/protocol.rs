@@ -225,7 +225,8 @@ pub fn da_legacy(input: TokenStream) -> TokenStream {
         .iter()
         .filter(|f| match &f.enum_ty {
             FieldType::Tx(t) if t.is_none() => true,
-            FieldType::Echo | FieldType::Ack(_) => true,
+            FieldType::Echo(t) if t.is_none() => true,
+            FieldType::Ack(_) => true,
             _ => false,
         })
         .collect::<Vec<_>>();
@@ -348,7 +349,19 @@ pub fn da_legacy(input: TokenStream) -> TokenStream {
                         let code = if let RxType::Status(status) = rx_ty { code.status(status) } else { code };
                         if rx_ty.is_size() { code.finalize() } else { code.store().finalize() }
                     }
-                    FieldType::Echo => Codegen::new(ty, f.ident, true).load().tx().rx().echo_status().store().finalize(),
+                    FieldType::Echo(tx_ty) => {
+                        let ident = &f.ident;
+                        let default = match tx_ty {
+                            TxType::Always(v) => Some(quote! { self.#ident = #v.try_into().unwrap(); }),
+                            TxType::None => None,
+                        };
+
+                        let code = Codegen::new(ty, f.ident, true).load().tx().rx().echo_status().store().finalize();
+                        quote! {
+                            #default
+                            #code
+                        }
+                    }
                     FieldType::Ack(ack_ty) => {
                         let code = Codegen::new(ty, f.ident.clone(), true);
                         if ack_ty.is_tx_then_rx() {
@@ -108,7 +108,7 @@ pub enum TxType {
 pub enum FieldType {
     Tx(TxType),
     Rx { ty: RxType, getter: bool },
-    Echo,
+    Echo(TxType),
     Ack(AckType),
 }
 
@@ -156,7 +156,7 @@ impl TryFrom<DarlingProtocolField> for FieldType {
                 getter: value.getter.is_some(),
             })
         } else if value.echo.is_some() {
-            Ok(Self::Echo)
+            Ok(Self::Echo(value.always.map_or(TxType::None, TxType::Always)))
         } else if value.ack.is_some() {
             Ok(Self::Ack(value.ack.unwrap()))
         } else {
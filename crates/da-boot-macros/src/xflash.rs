@@ -0,0 +1,132 @@
+use darling::{FromDeriveInput, FromField};
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Ident, Type, parse_macro_input};
+
+use crate::compile_err;
+
+#[derive(Debug, FromDeriveInput)]
+#[darling(attributes(xflash), supports(struct_named, struct_unit))]
+struct DarlingXFlashArgs {
+    command: u32,
+    /// The parameters follow the command right away, without waiting for its status
+    #[darling(default)]
+    no_ack: Option<()>,
+}
+
+#[derive(Debug, FromField)]
+#[darling(attributes(xflash))]
+struct DarlingXFlashField {
+    #[darling(default)]
+    always: Option<u64>,
+}
+
+struct Field {
+    ident: Ident,
+    ty: Type,
+    always: Option<u64>,
+}
+
+/// Parameters are sent as little-endian integers, so only those are supported
+fn is_supported(ty: &Type) -> bool {
+    match ty {
+        Type::Path(ty) => ty.path.get_ident().is_some_and(|i| ["u8", "u16", "u32", "u64"].iter().any(|t| i == t)),
+        _ => false,
+    }
+}
+
+pub fn xflash(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let struct_name = &input.ident;
+
+    let args = match DarlingXFlashArgs::from_derive_input(&input) {
+        Ok(v) => v,
+        Err(e) => return e.write_errors().into(),
+    };
+
+    let (fields, unit) = match input.data {
+        Data::Struct(data) => match data.fields {
+            Fields::Named(data) => (data.named.into_iter().collect::<Vec<_>>(), false),
+            Fields::Unit => (Vec::new(), true),
+            Fields::Unnamed(_) => return compile_err!(struct_name, "unnamed fields are not supported"),
+        },
+        _ => return compile_err!(struct_name, "only structs are supported"),
+    };
+
+    let mut parsed = Vec::with_capacity(fields.len());
+    for f in fields {
+        let attrs = match DarlingXFlashField::from_field(&f) {
+            Ok(v) => v,
+            Err(e) => return e.write_errors().into(),
+        };
+        if !is_supported(&f.ty) {
+            return compile_err!(f.ty, "only u8, u16, u32 and u64 are supported");
+        }
+
+        parsed.push(Field {
+            ident: f.ident.unwrap(),
+            ty: f.ty,
+            always: attrs.always,
+        });
+    }
+
+    // Fields without a fixed value become the constructor arguments
+    let ctor_args = parsed
+        .iter()
+        .filter(|f| f.always.is_none())
+        .map(|f| {
+            let ident = &f.ident;
+            let ty = &f.ty;
+            quote! { #ident: #ty }
+        })
+        .collect::<Vec<_>>();
+    let inits = parsed
+        .iter()
+        .map(|f| {
+            let ident = &f.ident;
+            let ty = &f.ty;
+            match f.always {
+                Some(v) => quote! { #ident: #v as #ty },
+                None => quote! { #ident },
+            }
+        })
+        .collect::<Vec<_>>();
+    let body = if unit {
+        quote! { Self }
+    } else {
+        quote! { Self { #(#inits,)* } }
+    };
+
+    let param = parsed
+        .iter()
+        .map(|f| {
+            let ident = &f.ident;
+            quote! { param.extend_from_slice(&self.#ident.to_le_bytes()); }
+        })
+        .collect::<Vec<_>>();
+
+    let command = args.command;
+    let acked = args.no_ack.is_none();
+    let expanded = quote! {
+        impl #struct_name {
+            /// Refer to fields without `#[xflash(always)]` for the explanation of the constructor arguments
+            pub fn new(#(#ctor_args,)*) -> Self {
+                #body
+            }
+        }
+
+        impl crate::commands::xflash::XFlashCommand for #struct_name {
+            const COMMAND: u32 = #command;
+            const ACKED: bool = #acked;
+
+            fn param(&self) -> Vec<u8> {
+                #[allow(unused_mut)]
+                let mut param = Vec::new();
+                #(#param)*
+                param
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
@@ -15,6 +15,7 @@ use crate::{
         },
     },
     handshake, open_port, run_payload,
+    session::event,
 };
 
 pub fn run_brom(state: &mut State, mut port: Port, device_mode: DeviceMode) -> Result<()> {
@@ -88,7 +89,7 @@ pub fn run_brom(state: &mut State, mut port: Port, device_mode: DeviceMode) -> R
                 .upload(preloader.file.upload_address(), &preloader.file)
                 .context("Error on sending Preloader")?;
 
-            println!("Jump to preloader");
+            event!("Jump to preloader");
             protocol.send_message(Message::jump(preloader.file.upload_address(), None, None))?;
             if protocol.read_response().is_ok_and(|r| r.is_nack()) {
                 anyhow::bail!("Error on jumping to Preloader");
@@ -1,4 +1,6 @@
-use acon::SoC;
+use std::ops::Range;
+
+use acon::{Memory, SoC};
 use clap::ValueEnum;
 
 use crate::boot::give_me_bytes_please;
@@ -331,3 +333,12 @@ pub fn get_for_soc(
         _ => unreachable!(),
     }
 }
+
+/// DRAM banks described by the boot argument, ranks follow each other from the DRAM start
+pub fn dram_banks(soc: SoC, dram_size_per_rank: u32, dram_ranks: u32) -> Vec<Range<u64>> {
+    let size = dram_size_per_rank as u64;
+    let start = soc.dram_start() as u64;
+    (0..dram_ranks as u64)
+        .map(|i| start + i * size..start + (i + 1) * size)
+        .collect()
+}
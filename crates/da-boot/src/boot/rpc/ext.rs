@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use da_protocol::{Message, Protocol};
+use da_protocol::{BLOCK_SIZE, EmmcPart, Message, Protocol, ProtocolError, Response};
 use kdam::{BarExt, tqdm};
 
 use crate::Port;
@@ -10,6 +10,9 @@ pub trait HostExtensions {
     fn start(&mut self) -> Result<()>;
     fn upload(&mut self, addr: u32, data: &[u8]) -> Result<()>;
     fn download(&mut self, addr: u32, len: u32) -> Result<Vec<u8>>;
+    fn read_blocks(&mut self, lba: u32, count: u32, part: EmmcPart) -> Result<Vec<u8>>;
+    fn block_count(&mut self) -> Result<u32>;
+    fn write_blocks(&mut self, lba: u32, part: EmmcPart, data: &[u8]) -> Result<()>;
 }
 
 impl HostExtensions for Protocol<Port> {
@@ -67,4 +70,58 @@ impl HostExtensions for Protocol<Port> {
 
         Ok(vec)
     }
+
+    fn read_blocks(&mut self, lba: u32, count: u32, part: EmmcPart) -> Result<Vec<u8>> {
+        self.send_message(Message::BlockRead { lba, count, part })?;
+        match self.read_response()? {
+            Response::Ack => (),
+            Response::Nack(ProtocolError::NotSupported) => {
+                anyhow::bail!("preloader storage driver can't select the {part:?} partition")
+            }
+            Response::Nack(e) => anyhow::bail!("device failed to read LBA {lba:#x}: {e}"),
+            r => anyhow::bail!("unexpected response: {r}"),
+        }
+
+        let mut data = vec![0u8; (count * BLOCK_SIZE) as usize];
+        self.io.read_exact(&mut data)?;
+
+        if self.read_response()?.is_ack() {
+            Ok(data)
+        } else {
+            anyhow::bail!("device didn't reply with ack");
+        }
+    }
+
+    fn block_count(&mut self) -> Result<u32> {
+        self.send_message(Message::BlockCount)?;
+        match self.read_response()? {
+            Response::BlockCount(count) => Ok(count),
+            Response::Nack(e) => anyhow::bail!("device failed to get block count: {e}"),
+            r => anyhow::bail!("unexpected response: {r}"),
+        }
+    }
+
+    fn write_blocks(&mut self, lba: u32, part: EmmcPart, data: &[u8]) -> Result<()> {
+        if data.is_empty() || data.len() % BLOCK_SIZE as usize != 0 {
+            anyhow::bail!("data is not a whole number of blocks");
+        }
+
+        let count = (data.len() / BLOCK_SIZE as usize) as u32;
+        self.send_message(Message::BlockWrite { lba, count, part })?;
+        match self.read_response()? {
+            Response::Ack => (),
+            Response::Nack(ProtocolError::NotSupported) => {
+                anyhow::bail!("preloader storage driver can't write the {part:?} partition")
+            }
+            Response::Nack(e) => anyhow::bail!("device can't write LBA {lba:#x}: {e}"),
+            r => anyhow::bail!("unexpected response: {r}"),
+        }
+
+        self.io.write_all(data)?;
+        match self.read_response()? {
+            Response::Ack => Ok(()),
+            Response::Nack(e) => anyhow::bail!("device failed to write LBA {lba:#x}: {e}"),
+            r => anyhow::bail!("unexpected response: {r}"),
+        }
+    }
 }
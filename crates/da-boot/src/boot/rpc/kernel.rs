@@ -0,0 +1,127 @@
+use acon::Memory;
+use anyhow::{Context, Result};
+use da_protocol::{Message, Protocol};
+
+use crate::{
+    Port, State,
+    boot::{
+        lk_arg::dram_banks,
+        rpc::{ext::HostExtensions, selector::upload_to_free_range},
+    },
+    formats::{atags::AtagsBuilder, fdt::Fdt},
+};
+
+/// zImage magic at offset 0x24
+const ZIMAGE_MAGIC: u32 = 0x016f2818;
+/// Offset of the decompressed kernel from the DRAM start
+const TEXT_OFFSET: u32 = 0x8000;
+/// Memory after the DRAM start kept for the decompressed kernel and its BSS
+const KERNEL_DECOMPRESS_SIZE: u32 = 32 * 1024 * 1024;
+/// Machine ID telling the kernel to look at the DTB
+const MACH_TYPE_DT: u32 = 0xffffffff;
+
+fn is_zimage(kernel: &[u8]) -> bool {
+    kernel
+        .get(0x24..0x28)
+        .is_some_and(|m| u32::from_le_bytes(m.try_into().unwrap()) == ZIMAGE_MAGIC)
+}
+
+/// Boot the kernel straight from the payload, LK is not involved
+///
+/// The zImage decompresses itself to the DRAM start, so this area is reserved first and
+/// everything else goes through the free range allocation. Raw Image is loaded at its
+/// link address inside the same reserved area.
+pub fn boot_kernel(state: &State, mut protocol: Protocol<Port>) -> Result<()> {
+    let kernel = state
+        .kernel
+        .as_ref()
+        .context("Kernel mode requires kernel")?;
+    let dram_start = state.soc.dram_start();
+    let decompress = dram_start..dram_start + KERNEL_DECOMPRESS_SIZE;
+
+    let kernel_addr = if is_zimage(kernel) {
+        protocol.send_message(Message::BlacklistRange(decompress.clone()))?;
+        if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+            anyhow::bail!("Failed blacklisting kernel decompression area");
+        }
+        println!(
+            "Reserved memory: {:#x}..{:#x} (kernel decompression)",
+            decompress.start, decompress.end
+        );
+
+        upload_to_free_range(&mut protocol, kernel, "zImage")?
+    } else {
+        let addr = dram_start + TEXT_OFFSET;
+        if addr + kernel.len() as u32 > decompress.end {
+            anyhow::bail!("Kernel image doesn't fit into {KERNEL_DECOMPRESS_SIZE:#x} bytes");
+        }
+
+        protocol.send_message(Message::BlacklistRange(decompress.clone()))?;
+        if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+            anyhow::bail!("Failed blacklisting kernel area");
+        }
+        println!("Reserved memory: {addr:#x} (kernel)");
+
+        println!("Not a zImage, loading raw Image to {addr:#x}");
+        protocol
+            .upload(addr, kernel)
+            .context("Failed to send kernel")?;
+
+        addr
+    };
+
+    let initrd = if let Some(ref ramdisk) = state.ramdisk {
+        let start = upload_to_free_range(&mut protocol, ramdisk, "initramfs")?;
+        Some(start..start + ramdisk.len() as u32)
+    } else {
+        None
+    };
+
+    let banks = dram_banks(state.soc, state.dram_size_per_rank, state.dram_ranks);
+    for bank in &banks {
+        println!("Kernel memory: {:#x}..{:#x}", bank.start, bank.end);
+    }
+
+    let cmdline = state.bootimg.full_cmdline("");
+    let cmdline = cmdline.as_str();
+    let (tags, machine_id) = if let Some(ref dtb) = state.dtb {
+        let mut fdt = Fdt::parse(dtb).context("Failed to parse DTB")?;
+        fdt.set_memory(&banks)
+            .context("Failed to patch /memory node")?;
+        fdt.set_chosen(
+            Some(cmdline).filter(|c| !c.is_empty()),
+            initrd.clone().map(|r| r.start as u64..r.end as u64),
+        )
+        .context("Failed to patch /chosen node")?;
+
+        let tags = upload_to_free_range(&mut protocol, &fdt.to_bytes(), "DTB")?;
+        (tags, state.machine_id.unwrap_or(MACH_TYPE_DT))
+    } else {
+        let machine_id = state
+            .machine_id
+            .context("Booting without DTB requires machine ID")?;
+
+        let mut atags = AtagsBuilder::new().memory(&banks)?;
+        if let Some(initrd) = initrd {
+            atags = atags.initrd(initrd);
+        }
+        let atags = atags.cmdline(cmdline).build();
+
+        (
+            upload_to_free_range(&mut protocol, &atags, "ATAGs")?,
+            machine_id,
+        )
+    };
+
+    println!("Booting kernel at {kernel_addr:#x} (machine ID {machine_id:#x}, tags at {tags:#x})");
+    protocol.send_message(Message::BootKernel {
+        addr: kernel_addr,
+        machine_id,
+        tags,
+    })?;
+    if protocol.read_response().is_ok_and(|r| r.is_nack()) {
+        anyhow::bail!("Error on booting kernel");
+    } else {
+        Ok(())
+    }
+}
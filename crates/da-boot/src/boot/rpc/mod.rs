@@ -1,2 +1,3 @@
 pub mod ext;
+pub mod kernel;
 pub mod selector;
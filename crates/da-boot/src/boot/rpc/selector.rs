@@ -1,4 +1,4 @@
-use std::{borrow::Cow, fs, io::Write, process::Command};
+use std::borrow::Cow;
 
 use acon::SoC;
 use anyhow::{Context, Result};
@@ -6,28 +6,53 @@ use da_params::{MAGIC, PayloadParams};
 use da_patcher::{
     Extract,
     lk::{
-        get_part::GetPart, mt_part_generic_read::MtPartGenericRead,
-        mt_part_get_partition::MtPartGetPartition,
+        boot_linux::BootLinux, get_part::GetPart, mt_part_generic_read::MtPartGenericRead,
+        mt_part_generic_write::MtPartGenericWrite, mt_part_get_partition::MtPartGetPartition,
+        printf::LkPrintf, video_printf::VideoPrintf,
     },
-    preloader::bldr_jump::BldrJump,
+    preloader::{
+        bldr_jump::BldrJump, bldr_load_part::BldrLoadPart, bldr_resume::BldrResume,
+        blkdev_get::BlkdevGet,
+    },
+};
+use da_protocol::{
+    HookId, LKRunnerParams, MAX_RAM_PARTITIONS, Message, PreloaderRunnerParams, Protocol,
+    RAM_PARTITION_NAME_LEN, RamPartition, Response, WritePolicy,
 };
-use da_protocol::{HookId, LKRunnerParams, Message, PreloaderRunnerParams, Protocol, Response};
 use hacc::Image;
 use memchr::memmem;
-use tempfile::NamedTempFile;
 
 use crate::{
     BootMode, Port, State,
-    boot::{give_me_bytes_please, lk_arg::get_for_soc, rpc::ext::HostExtensions},
+    boot::{
+        give_me_bytes_please,
+        lk_arg::{dram_banks, get_for_soc},
+        rpc::{ext::HostExtensions, kernel::boot_kernel},
+    },
+    dump::{MemoryMap, run_dump},
+    formats::{
+        bootimg::{BootImgBuilder, BootImgLayout},
+        fdt::Fdt,
+    },
     repl::run_repl,
     run_payload,
+    session::event,
+    storage::{SCRATCH_SIZE, list_partitions, read_partition, read_to_file, write_partition},
 };
 
 pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
     let (bldr_jump, da_addr) = BldrJump::new(&state.preloader.analyzer)
         .extract()
         .context("Failed to get bldr_jump fn ptr")?;
-    let pl_params = PreloaderRunnerParams::new(bldr_jump);
+    let mut pl_params = PreloaderRunnerParams::new(bldr_jump);
+    match BldrResume::new(&state.preloader.analyzer).extract() {
+        Ok(flow) => pl_params.set_boot_flow(
+            flow.load_images | 1,
+            flow.post_process | 1,
+            flow.bootarg_addr..flow.bootarg_addr + flow.bootarg_size,
+        ),
+        Err(e) => println!("Preloader boot flow not found, continue is not available: {e}"),
+    }
 
     let mut payload = pl_payload()?;
 
@@ -38,6 +63,25 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
     let mut protocol = start_rpc(port)?;
     println!("Got loader sync !");
 
+    // block reads and writes go through the preloader storage driver
+    let needs_storage = matches!(
+        state.mode,
+        BootMode::ReadLba { .. }
+            | BootMode::ReadPartition { .. }
+            | BootMode::Partitions { .. }
+            | BootMode::WritePartition { .. }
+    );
+    if needs_storage || state.mode == BootMode::REPL {
+        match BlkdevGet::new(&state.preloader.analyzer).extract() {
+            Ok((blkdev_get, boot_dev)) => {
+                let scratch = reserve_free_range(&mut protocol, SCRATCH_SIZE, "block buffer")?;
+                pl_params.set_blkdev(blkdev_get, boot_dev, scratch..scratch + SCRATCH_SIZE);
+            }
+            Err(e) if needs_storage => return Err(e.context("Failed to get blkdev_get fn ptr")),
+            Err(e) => println!("Storage driver not found, block access is not available: {e}"),
+        }
+    }
+
     protocol.send_message(Message::SetParams(da_protocol::ParamsType::Preloader(
         pl_params,
     )))?;
@@ -59,6 +103,12 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
         }
     }
 
+    if state.lk_via_preloader || state.mode == BootMode::Continue {
+        return continue_boot(state, protocol, pl_params);
+    } else if state.mode == BootMode::Kernel {
+        return boot_kernel(state, protocol);
+    }
+
     let (mut bootarg_base, mut bootarg_size) = (0, 0);
     if let Some(ref lk) = state.lk {
         let addr = lk.file.upload_address();
@@ -114,13 +164,25 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
         BootMode::LK { .. } => {
             let image = state.lk.as_ref().unwrap();
 
-            let start = {
+            let bootimg = {
                 if let Some(ref kernel) = state.kernel {
+                    let layout = state.bootimg.layout;
+                    let dtb = prepare_dtb(state, &layout)?;
+
+                    // older headers have no DTB field, the kernel expects it appended
+                    let kernel_data = match dtb {
+                        Some(ref dtb) if layout.header_version < 2 => {
+                            println!("Appending DTB to the kernel");
+                            Cow::Owned([kernel.content(), dtb].concat())
+                        }
+                        _ => Cow::Borrowed(kernel.content()),
+                    };
+
                     let mut kernel_image = Image::default();
                     kernel_image
                         .add_partition(
                             "KERNEL",
-                            kernel,
+                            &kernel_data,
                             hacc::ImageKind::Ap(hacc::ImageAPKind::APBin),
                         )
                         .context("Failed to add KERNEL partition")?;
@@ -140,80 +202,140 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
                         )
                         .context("Failed to add ROOTFS partition")?;
 
-                    let mut kernel =
-                        NamedTempFile::new().context("Failed to create kernel temp file")?;
-                    kernel
-                        .write_all(&kernel_image.data)
-                        .context("Failed to write kernel to the file")?;
-
-                    let mut ramdisk =
-                        NamedTempFile::new().context("Failed to create ramdisk temp file")?;
-                    ramdisk
-                        .write_all(&ramdisk_image.data)
-                        .context("Failed to write ramdisk to the file")?;
-
-                    let out = NamedTempFile::new().context("Failed to create output file")?;
-
-                    let cmd = Command::new("mkbootimg")
-                        .arg("--kernel")
-                        .arg(kernel.path())
-                        .arg("--ramdisk")
-                        .arg(ramdisk.path())
-                        .arg("--base")
-                        .arg("0x40000000")
-                        .arg("--kernel_offset")
-                        .arg("0x8000")
-                        .arg("--ramdisk_offset")
-                        .arg("0x4000000")
-                        .arg("-o")
-                        .arg(out.path())
-                        .output()
-                        .context("Failed to run mkbootimg")?;
-
-                    if !cmd.status.success() {
-                        anyhow::bail!("Failed to create boot image");
+                    let cmdline = state.bootimg.header_cmdline("").unwrap_or_default();
+                    let mut builder =
+                        BootImgBuilder::new(layout, &kernel_image.data, &ramdisk_image.data)
+                            .cmdline(&cmdline)
+                            .board(&state.bootimg.board);
+                    if let Some(ref second) = state.second {
+                        builder = builder.second(second);
+                    }
+                    if let Some(dtb) = dtb.as_ref().filter(|_| layout.header_version >= 2) {
+                        builder = builder.dtb(dtb);
                     }
 
-                    let boot_img = fs::read(out.path()).context("Failed to read boot.img")?;
-                    let size = boot_img.len() as u32;
+                    println!(
+                        "Building boot.img v{} (kernel: {:#x}, ramdisk: {:#x}, tags: {:#x})",
+                        layout.header_version,
+                        layout.kernel_addr(),
+                        layout.ramdisk_addr(),
+                        layout.tags_addr()
+                    );
+                    let boot_img = builder.build().context("Failed to create boot image")?;
+                    let start = upload_to_free_range(&mut protocol, &boot_img, "boot.img")?;
+
+                    Some((start, boot_img.len() as u32))
+                } else if let Some(input) = state.upload.get(0) {
+                    let start = input.upload_address();
+                    println!("Using first uploaded image as boot.img (at {start:#x})");
+                    Some((start, input.len() as u32))
+                } else {
+                    None
+                }
+            };
+
+            let mt_part_generic_read = MtPartGenericRead::new(&image.analyzer)
+                .extract()
+                .context("Failed to extract mt_part_generic_read")?;
+            // the start sector offset depends on the partition struct returned by the helper
+            let (mt_part_get_partition, part_start_offset, bootimg_name) = match &state.soc {
+                SoC::MT6572 | SoC::MT6582 => (
+                    MtPartGetPartition::new(&image.analyzer)
+                        .extract()
+                        .context("Failed to extract mt_part_get_partition")?,
+                    12,
+                    "BOOTIMG",
+                ),
+                SoC::MT6595 => (
+                    GetPart::new(&image.analyzer)
+                        .extract()
+                        .context("Failed to extract get_part")?,
+                    0,
+                    "boot",
+                ),
+                _ => unreachable!(),
+            };
+            let mut lk_params = LKRunnerParams::new(
+                mt_part_generic_read | 1,
+                mt_part_get_partition | 1,
+                part_start_offset,
+            );
+
+            let mut ram_parts = Vec::new();
+            if let Some((start, size)) = bootimg {
+                ram_parts.push((bootimg_name, start, size));
+            }
+            for part in &state.ram_parts {
+                let start = upload_to_free_range(&mut protocol, &part.content, &part.name)?;
+                ram_parts.push((part.name.as_str(), start, part.content.len() as u32));
+            }
+
+            for (name, start, size) in ram_parts {
+                let part = RamPartition::new(name, start, size).with_context(|| {
+                    format!(
+                        "Partition name {name} must be shorter than {RAM_PARTITION_NAME_LEN} bytes"
+                    )
+                })?;
+                if !lk_params.map_partition(part) {
+                    anyhow::bail!(
+                        "Too many RAM partitions, at most {MAX_RAM_PARTITIONS} are supported"
+                    );
+                }
+                println!("Serving {name} from the RAM ({size} bytes at {start:#x})");
+            }
+
+            if let Some(policy) = state.lk_write_policy {
+                let mt_part_generic_write = MtPartGenericWrite::new(&image.analyzer)
+                    .extract()
+                    .context("Failed to extract mt_part_generic_write")?;
+                lk_params.set_write_policy(mt_part_generic_write | 1, policy);
+
+                if policy == WritePolicy::Overlay {
+                    let size = state.lk_overlay_size;
                     protocol.send_message(Message::GetFreeRange { size })?;
                     let Response::Range(Some(start)) = protocol.read_response()? else {
                         anyhow::bail!("Failed to request free range for {size} bytes");
                     };
-
-                    println!("Allocated {size} bytes at {start:#x}, sending boot.img");
-                    protocol
-                        .upload(start, &boot_img)
-                        .context("Failed to send boot.img")?;
-
                     protocol.send_message(Message::BlacklistRange(start..start + size + 1))?;
                     if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
-                        anyhow::bail!("Failed to blacklist boot.img range");
+                        anyhow::bail!("Failed to blacklist write overlay range");
                     }
-                    println!("Reserved memory: {start:#x} (boot.img)");
+                    println!("Reserved memory: {start:#x} (write overlay, {size} bytes)");
 
-                    start
-                } else {
-                    let start = state.upload.get(0).context("No input")?.upload_address();
-                    println!("Using first uploaded image as boot.img (at {start:#x})");
-                    start
+                    lk_params.set_overlay(start..start + size);
                 }
-            };
+            }
 
-            let mt_part_generic_read = MtPartGenericRead::new(&image.analyzer)
-                .extract()
-                .context("Failed to extract mt_part_generic_read")?;
-            let mt_part_get_partition = match &state.soc {
-                SoC::MT6572 | SoC::MT6582 => MtPartGetPartition::new(&image.analyzer)
+            if state.bootimg.cmdline_hook {
+                let boot_linux = BootLinux::new(&image.analyzer)
                     .extract()
-                    .context("Failed to extract mt_part_get_partition")?,
-                SoC::MT6595 => GetPart::new(&image.analyzer)
+                    .context("Failed to extract boot_linux")?;
+
+                // the whole command line replaces the LK one, the appended part is added to it
+                let replace = state.bootimg.cmdline.is_some();
+                let cmdline = if replace {
+                    state.bootimg.full_cmdline("")
+                } else {
+                    state.bootimg.cmdline_append.clone().unwrap_or_default()
+                };
+                println!("Kernel command line (replace: {replace}): {cmdline:?}");
+
+                let mut bytes = cmdline.into_bytes();
+                bytes.push(0);
+                let start = upload_to_free_range(&mut protocol, &bytes, "command line")?;
+                lk_params.set_cmdline(boot_linux | 1, start, replace);
+            }
+
+            if state.lk_screen_log {
+                let lk_printf = LkPrintf::new(&image.analyzer)
                     .extract()
-                    .context("Failed to extract get_part")?,
-                _ => unreachable!(),
-            };
-            let lk_params =
-                LKRunnerParams::new(mt_part_generic_read | 1, mt_part_get_partition | 1, start);
+                    .context("Failed to extract LK printf")?;
+                let video_printf = VideoPrintf::new(&image.analyzer)
+                    .extract()
+                    .context("Failed to extract video_printf")?;
+                lk_params.set_screen_log(lk_printf | 1, video_printf | 1);
+            }
+
             protocol.send_message(Message::SetParams(da_protocol::ParamsType::LK(lk_params)))?;
             if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
                 anyhow::bail!("Failed to set LK params");
@@ -229,13 +351,95 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
                 "Replaced mt_part_generic_read ({mt_part_generic_read:#x}), helper: mt_part_get_partition ({mt_part_get_partition:#x})"
             );
 
+            if let Some(policy) = state.lk_write_policy {
+                protocol.send_message(Message::hook(HookId::MtPartGenericWrite))?;
+                if !protocol.read_response()?.is_ack() {
+                    anyhow::bail!("Error on replacing mt_part_generic_write");
+                }
+                println!("Replaced mt_part_generic_write, write policy: {policy:?}");
+            }
+
+            if state.bootimg.cmdline_hook {
+                protocol.send_message(Message::hook(HookId::BootLinux))?;
+                if !protocol.read_response()?.is_ack() {
+                    anyhow::bail!("Error on replacing boot_linux");
+                }
+                println!("Replaced boot_linux");
+            }
+
+            if state.lk_screen_log {
+                protocol.send_message(Message::hook(HookId::LkScreenLog))?;
+                if !protocol.read_response()?.is_ack() {
+                    anyhow::bail!("Error on replacing LK printf");
+                }
+                println!("Replaced LK printf, the log goes to the screen too");
+            }
+
             jump = image.file.upload_address();
             println!("Jump address set to LK entry ({jump:#x})");
         }
-        BootMode::REPL => return run_repl(protocol),
+        BootMode::REPL => {
+            state.session = run_repl(protocol, MemoryMap::new(state))?;
+            return Ok(());
+        }
+        BootMode::Dump {
+            ref regions,
+            ref out,
+            resume,
+            sparse,
+        } => {
+            let result = run_dump(
+                &mut protocol,
+                &MemoryMap::new(state),
+                regions,
+                out,
+                resume,
+                sparse,
+            );
+            state.session = Some(protocol);
+            return result;
+        }
+        BootMode::ReadLba {
+            lba,
+            count,
+            part,
+            ref out,
+        } => {
+            let result = read_to_file(&mut protocol, lba, count, part.into(), out);
+            state.session = Some(protocol);
+            return result;
+        }
+        BootMode::ReadPartition { ref name, ref out } => {
+            let result = read_partition(&mut protocol, name, out.as_deref());
+            state.session = Some(protocol);
+            return result;
+        }
+        BootMode::Partitions { ref json } => {
+            let lk = state.lk.as_ref().map(|lk| &lk.analyzer);
+            let result = list_partitions(&mut protocol, lk, json.as_deref());
+            state.session = Some(protocol);
+            return result;
+        }
+        BootMode::WritePartition {
+            ref name,
+            ref image,
+            ref backup,
+            force,
+        } => {
+            let result = write_partition(&mut protocol, name, image, backup.as_deref(), force);
+            state.session = Some(protocol);
+            return result;
+        }
+        BootMode::Continue => unreachable!("continue is handled before"),
+        BootMode::Kernel => unreachable!("kernel is handled before"),
+    }
+
+    // the jump expects ARM code
+    if jump & 1 != 0 {
+        jump = thumb_trampoline(&mut protocol, jump)?;
     }
 
-    println!("Jump to {jump:#x}");
+    event!("Jump to {jump:#x}");
     protocol.send_message(Message::jump(
         jump,
         Some(bootarg_base as u32),
@@ -248,6 +452,149 @@ pub fn run_rpc_preloader(state: &mut State, mut port: Port) -> Result<()> {
     }
 }
 
+/// Resume the preloader boot flow
+///
+/// If LK is provided, the preloader loads it instead of the eMMC one.
+fn continue_boot(
+    state: &State,
+    mut protocol: Protocol<Port>,
+    mut pl_params: PreloaderRunnerParams,
+) -> Result<()> {
+    if !pl_params.has_boot_flow() {
+        anyhow::bail!("Can't continue, preloader boot flow is unknown");
+    }
+
+    if let Some(ref lk) = state.lk {
+        let bldr_load_part = BldrLoadPart::new(&state.preloader.analyzer)
+            .extract()
+            .context("Failed to extract bldr_load_part")?;
+
+        // the preloader copies LK here by itself
+        let lk_base = lk.file.upload_address();
+        let lk_end = lk_base + lk.file.len() as u32;
+        protocol.send_message(Message::BlacklistRange(lk_base..lk_end + 1))?;
+        if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+            anyhow::bail!("Failed blacklisting {lk_base:#x}");
+        }
+        println!("Reserved memory: {lk_base:#x} (LK load address)");
+
+        let start = upload_to_free_range(&mut protocol, &lk.file, "LK")?;
+        pl_params.set_lk_image(
+            bldr_load_part | 1,
+            lk_base,
+            start..start + lk.file.len() as u32,
+        );
+        protocol.send_message(Message::SetParams(da_protocol::ParamsType::Preloader(
+            pl_params,
+        )))?;
+        if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+            anyhow::bail!("Error on setting preloader params");
+        }
+
+        protocol.send_message(Message::hook(HookId::BldrLoadPart))?;
+        if !protocol.read_response()?.is_ack() {
+            anyhow::bail!("Error on replacing bldr_load_part");
+        }
+        println!("Replaced bldr_load_part ({bldr_load_part:#x})");
+    }
+
+    println!(
+        "Resuming preloader boot flow with bldr_load_images ({:#x})",
+        pl_params.ptr_bldr_load_images
+    );
+    protocol.send_message(Message::Continue)?;
+    if protocol.read_response().is_ok_and(|r| r.is_nack()) {
+        anyhow::bail!("Error on resuming preloader");
+    } else {
+        Ok(())
+    }
+}
+
+/// Reserve `size` bytes of the free memory
+fn reserve_free_range(protocol: &mut Protocol<Port>, size: u32, what: &str) -> Result<u32> {
+    protocol.send_message(Message::GetFreeRange { size })?;
+    let Response::Range(Some(start)) = protocol.read_response()? else {
+        anyhow::bail!("Failed to request free range for {size} bytes");
+    };
+
+    protocol.send_message(Message::BlacklistRange(start..start + size + 1))?;
+    if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+        anyhow::bail!("Failed to blacklist {what} range");
+    }
+    println!("Reserved memory: {start:#x} ({what})");
+
+    Ok(start)
+}
+
+/// Upload `data` to the free memory and reserve it
+pub fn upload_to_free_range(protocol: &mut Protocol<Port>, data: &[u8], what: &str) -> Result<u32> {
+    let size = data.len() as u32;
+    protocol.send_message(Message::GetFreeRange { size })?;
+    let Response::Range(Some(start)) = protocol.read_response()? else {
+        anyhow::bail!("Failed to request free range for {size} bytes");
+    };
+
+    println!("Allocated {size} bytes at {start:#x}, sending {what}");
+    protocol
+        .upload(start, data)
+        .with_context(|| format!("Failed to send {what}"))?;
+
+    protocol.send_message(Message::BlacklistRange(start..start + size + 1))?;
+    if !protocol.read_response().is_ok_and(|r| r.is_ack()) {
+        anyhow::bail!("Failed to blacklist {what} range");
+    }
+    println!("Reserved memory: {start:#x} ({what})");
+
+    Ok(start)
+}
+
+/// Upload ARM code switching to the Thumb `entry`, returns its address
+///
+/// Only r12 is clobbered, the jump arguments reach the entry untouched.
+fn thumb_trampoline(protocol: &mut Protocol<Port>, entry: u32) -> Result<u32> {
+    println!("Entry {entry:#x} is Thumb, jumping through the trampoline");
+
+    let mut code = Vec::new();
+    // ldr r12, [pc]; bx r12; .word entry
+    for word in [0xe59fc000, 0xe12fff1c, entry] {
+        code.extend_from_slice(&u32::to_le_bytes(word));
+    }
+
+    upload_to_free_range(protocol, &code, "Thumb trampoline")
+}
+
+/// Read the DTB and patch it for the boot.img layout if requested
+fn prepare_dtb(state: &State, layout: &BootImgLayout) -> Result<Option<Vec<u8>>> {
+    let Some(ref dtb) = state.dtb else {
+        return Ok(None);
+    };
+
+    if !state.bootimg.patch_dtb {
+        return Ok(Some(dtb.to_vec()));
+    }
+
+    let mut fdt = Fdt::parse(dtb).context("Failed to parse DTB")?;
+
+    let banks = dram_banks(state.soc, state.dram_size_per_rank, state.dram_ranks);
+    fdt.set_memory(&banks)
+        .context("Failed to patch /memory node")?;
+
+    let cmdline = state.bootimg.full_cmdline("");
+    let bootargs = Some(cmdline.as_str()).filter(|c| !c.is_empty());
+    let initrd = state.ramdisk.as_ref().map(|ramdisk| {
+        let start = layout.ramdisk_addr() as u64;
+        start..start + ramdisk.len() as u64
+    });
+    fdt.set_chosen(bootargs, initrd)
+        .context("Failed to patch /chosen node")?;
+
+    for bank in &banks {
+        println!("DTB memory: {:#x}..{:#x}", bank.start, bank.end);
+    }
+
+    Ok(Some(fdt.to_bytes()))
+}
+
 pub fn start_rpc(port: Port) -> Result<Protocol<Port>> {
     let mut protocol = Protocol::new(port);
     protocol.start()?;
@@ -0,0 +1,249 @@
+use std::iter::once;
+
+use acon::{MMIO, SoC};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use clap_num::maybe_hex;
+use da_protocol::reset::{Registers, ResetTarget};
+use rustyline::{DefaultEditor, error::ReadlineError};
+
+use crate::{
+    Port, ResetMode,
+    boot::preloader::{invalidate_ready, mt6572_preloader_workaround},
+    commands::{
+        generic::{PwrInit, PwrRead16, Uart1LogEn, Uart1SetBaudrate},
+        preloader::{Read16, Read32, Write16, Write32},
+    },
+    err::Error,
+    get_hwcode, handshake,
+    identify::Identity,
+    open_port,
+    session::event,
+};
+
+/// Watchdog mode register of TOPRGU
+const WDT_MODE: u32 = 0x0;
+/// Key of the mode register, the watchdog is off with every other bit clear
+const WDT_MODE_KEY: u32 = 0x22000000;
+/// Software reset register of TOPRGU
+const WDT_SWRST: u32 = 0x14;
+/// Key of the software reset register, resets right away
+const WDT_SWRST_KEY: u32 = 0x1209;
+
+#[derive(Parser)]
+struct BromRepl {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the hwcode, versions, security flags and IDs.
+    Info,
+    /// Read `count` u32 at `addr`.
+    Read32 {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        addr: u32,
+        #[arg(value_parser=maybe_hex::<u32>, default_value = "1")]
+        count: u32,
+    },
+    /// Write u32 `value` to `addr`.
+    Write32 {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        addr: u32,
+        #[arg(value_parser=maybe_hex::<u32>)]
+        value: u32,
+    },
+    /// Read `count` u16 at `addr`.
+    Read16 {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        addr: u32,
+        #[arg(value_parser=maybe_hex::<u32>, default_value = "1")]
+        count: u32,
+    },
+    /// Write u16 `value` to `addr`.
+    Write16 {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        addr: u32,
+        #[arg(value_parser=maybe_hex::<u16>)]
+        value: u16,
+    },
+    /// Init the PMIC wrapper, needed before `pwr-read16`.
+    PwrInit,
+    /// Read PMIC register `addr`.
+    PwrRead16 {
+        #[arg(value_parser=maybe_hex::<u16>)]
+        addr: u16,
+    },
+    /// Enable the UART1 log, `baudrate` is set first if given.
+    UartLog {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        baudrate: Option<u32>,
+    },
+    /// Disable the watchdog, so the device stays in the download mode.
+    WdtDisable,
+    /// Reset the device using watchdog, booting into `target`.
+    Reset {
+        #[arg(default_value = "normal")]
+        target: ResetMode,
+    },
+}
+
+/// usbdl commands as the register access of the reset
+struct PortRegisters<'a>(&'a mut Port);
+
+impl Registers for PortRegisters<'_> {
+    type Error = Error;
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Error> {
+        let mut cmd = Read32::new(addr, 1);
+        cmd.run(self.0)?;
+        Ok(cmd.as_buf()[0])
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Error> {
+        Write32::new(addr, value).run(self.0)
+    }
+}
+
+/// Reset the device into `target`, `false` if its flags can't be set and nothing was reset
+pub(crate) fn reset_to(port: &mut Port, soc: SoC, target: ResetTarget) -> Result<bool, Error> {
+    if !target.prepare(&mut PortRegisters(port), soc)? {
+        return Ok(false);
+    }
+
+    event!("Resetting the device to {target:?}");
+    // the device is gone before the final status, so the error is expected
+    let _ = Write32::new(soc.toprgu() + WDT_SWRST, WDT_SWRST_KEY).run(port);
+    Ok(true)
+}
+
+/// Run `command`, `false` if the port can't be trusted after it
+fn run_command(port: &mut Port, soc: Option<SoC>, command: Command) -> Result<bool, Error> {
+    match command {
+        Command::Info => {
+            let identity = Identity::gather(port)?;
+            identity.print();
+            // a refused ID command may leave its answer behind
+            return Ok(identity.soc_id.is_some());
+        }
+        Command::Read32 { addr, count } => {
+            let mut cmd = Read32::new(addr, count);
+            cmd.run(port)?;
+            for (i, value) in cmd.as_buf().iter().enumerate() {
+                println!("<= {:#010x}: {value:#010x}", addr + i as u32 * 4);
+            }
+        }
+        Command::Write32 { addr, value } => {
+            Write32::new(addr, value).run(port)?;
+            println!("<= {addr:#010x} = {value:#010x}");
+        }
+        Command::Read16 { addr, count } => {
+            let mut cmd = Read16::new(addr, count);
+            cmd.run(port)?;
+            for (i, value) in cmd.as_buf().iter().enumerate() {
+                println!("<= {:#010x}: {value:#06x}", addr + i as u32 * 2);
+            }
+        }
+        Command::Write16 { addr, value } => {
+            Write16::new(addr, value).run(port)?;
+            println!("<= {addr:#010x} = {value:#06x}");
+        }
+        Command::PwrInit => {
+            PwrInit::new().run(port)?;
+            println!("<= PMIC wrapper is ready");
+        }
+        Command::PwrRead16 { addr } => {
+            let value = PwrRead16::new(addr).run_value(port)?;
+            println!("<= PMIC {addr:#06x}: {value:#06x}");
+        }
+        Command::UartLog { baudrate } => {
+            if let Some(baudrate) = baudrate {
+                Uart1SetBaudrate::new(baudrate).run(port)?;
+            }
+            Uart1LogEn::new().run(port)?;
+            println!("<= UART1 log is on");
+        }
+        Command::WdtDisable | Command::Reset { .. } => {
+            let Some(soc) = soc else {
+                eprintln!("Watchdog address is unknown for this SoC");
+                return Ok(true);
+            };
+
+            match command {
+                Command::Reset { target } => {
+                    let target = ResetTarget::from(target);
+                    if !target.is_supported(soc) {
+                        eprintln!("{target:?} reset is unknown for this SoC");
+                    } else if !reset_to(port, soc, target)? {
+                        eprintln!("PMIC wrapper doesn't answer, run `pwr-init` first");
+                    }
+                }
+                _ => {
+                    Write32::new(soc.toprgu() + WDT_MODE, WDT_MODE_KEY).run(port)?;
+                    println!("<= Watchdog is off");
+                }
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Connect to the BootROM or the preloader and sync with it
+pub(crate) fn connect() -> Result<Port> {
+    let (device_mode, mut port) = open_port()?;
+    if device_mode.is_preloader() {
+        invalidate_ready(&mut port)?;
+    }
+
+    handshake(&mut port)?;
+    mt6572_preloader_workaround(port)
+}
+
+/// Talk to the BootROM or the preloader with the usbdl commands, nothing is uploaded
+pub fn run_brom_repl() -> Result<()> {
+    let mut port = connect()?;
+    let hwcode = get_hwcode(&mut port)?;
+    event!("HW code: {hwcode:#x}");
+    let soc = SoC::try_from_hwcode(hwcode).context("Unknown SoC");
+    if let Err(ref e) = soc {
+        println!("{e}, the watchdog commands are not available");
+    }
+    let soc = soc.ok();
+
+    println!("Enter --help for help, Ctrl-C to exit");
+    let mut rl = DefaultEditor::new()?;
+    loop {
+        let line = match rl.readline("brom> ") {
+            Ok(line) => line.trim().to_owned(),
+            Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        rl.add_history_entry(&line)?;
+        match BromRepl::try_parse_from(once("brom").chain(line.split_whitespace())) {
+            Ok(repl) => {
+                let info = matches!(repl.command, Command::Info);
+                let synced = run_command(&mut port, soc, repl.command).unwrap_or_else(|e| {
+                    eprintln!("Command failed: {e}");
+                    !info
+                });
+                if !synced {
+                    println!(
+                        "Device may be out of sync after the ID commands, reconnect it to go on"
+                    );
+                    break;
+                }
+            }
+            Err(e) => {
+                e.print().ok();
+            }
+        }
+    }
+
+    Ok(())
+}
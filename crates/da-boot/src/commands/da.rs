@@ -141,6 +141,113 @@ impl DA1Setup {
     pub fn minor(&self) -> u8 {
         self.minor
     }
+
+    pub fn emmc_ret(&self) -> u32 {
+        self.emmc_ret
+    }
+}
+
+/// Start of the DA2 upload, DA1 loads it to the DRAM
+///
+/// The packets follow, each acked with 0x5a, then the final ack
+#[derive(Default, Protocol)]
+#[protocol(naked)]
+pub(crate) struct DA2Start {
+    /// DA2 address
+    #[protocol(tx)]
+    addr: u32,
+    /// DA2 length without the signature
+    #[protocol(tx)]
+    len: u32,
+    /// Size of the packets
+    #[protocol(tx)]
+    packet_size: u32,
+    #[protocol(rx, status = 0x5a)]
+    ack: u8,
+}
+
+/// Select the eMMC hardware partition for the following commands
+#[derive(Default, Protocol)]
+#[protocol(command = 0x60)]
+pub(crate) struct SwitchPart {
+    /// 1 and 2 - boot partitions, 8 - user area
+    #[protocol(tx)]
+    part: u8,
+    #[protocol(rx, status = 0x5a)]
+    ack: u8,
+}
+
+/// Start of the read back, the 64-bit address and length are split into halves
+///
+/// The packets follow, each with its checksum and acked by the host with 0x5a
+#[derive(Default, Protocol)]
+#[protocol(command = 0xd6)]
+pub(crate) struct ReadBack {
+    /// Storage type
+    #[protocol(tx)]
+    storage: u8,
+    /// Host OS, SP Flash Tool sends 2 on Linux
+    #[protocol(tx)]
+    host: u8,
+    #[protocol(tx)]
+    addr_hi: u32,
+    #[protocol(tx)]
+    addr_lo: u32,
+    #[protocol(tx)]
+    len_hi: u32,
+    #[protocol(tx)]
+    len_lo: u32,
+    /// Size of the packets
+    #[protocol(tx)]
+    packet_size: u32,
+    #[protocol(rx, status = 0x5a)]
+    ack: u8,
+}
+
+/// Start of the format, the progress reports follow until 100%
+#[derive(Default, Protocol)]
+#[protocol(command = 0xd4)]
+pub(crate) struct Format {
+    /// Storage type
+    #[protocol(tx)]
+    storage: u8,
+    /// 0 - normal format
+    #[protocol(tx, always = 0x0)]
+    format_mode: u8,
+    #[protocol(tx)]
+    addr_hi: u32,
+    #[protocol(tx)]
+    addr_lo: u32,
+    #[protocol(tx)]
+    len_hi: u32,
+    #[protocol(tx)]
+    len_lo: u32,
+    #[protocol(rx, status = 0x5a)]
+    ack: u8,
+}
+
+/// Reset the device with the watchdog once the DA is done
+#[derive(Default, Protocol)]
+#[protocol(command = 0xdb)]
+pub(crate) struct EnableWatchdog {
+    /// Watchdog timeout in ms
+    #[protocol(tx)]
+    timeout: u32,
+    /// Don't wait for the reset
+    #[protocol(tx, always = 0x1)]
+    async_reset: u8,
+    /// Boot normally instead of the download mode
+    #[protocol(tx, always = 0x0)]
+    bootup: u8,
+    /// Stay in the BootROM download mode after the reset
+    #[protocol(tx, always = 0x0)]
+    dl_bit: u8,
+    #[protocol(tx, always = 0x0)]
+    not_reset_rtc: u8,
+    #[protocol(tx, always = 0x0)]
+    not_disconnect_usb: u8,
+    #[protocol(rx, status = 0x5a)]
+    ack: u8,
 }
 
 #[derive(Default, Protocol)]
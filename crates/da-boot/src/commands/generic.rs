@@ -11,13 +11,24 @@ pub(crate) struct GetTargetConfig {
     status: u16,
 }
 
+/// Security flags from `GetTargetConfig`
+#[derive(Debug, Clone, Copy)]
+pub struct TargetConfig {
+    /// Secure boot: images are verified before they run
+    pub sbc: bool,
+    /// Serial link authentication: commands are refused until the challenge is signed
+    pub sla: bool,
+    /// Download agent authentication: only signed DAs run
+    pub daa: bool,
+}
+
 impl GetTargetConfig {
-    pub fn parse(&self) -> (bool, bool, bool) {
-        (
-            self.config & 0x1 != 0,
-            self.config & 0x2 != 0,
-            self.config & 0x4 != 0,
-        )
+    pub fn parse(&self) -> TargetConfig {
+        TargetConfig {
+            sbc: self.config & 0x1 != 0,
+            sla: self.config & 0x2 != 0,
+            daa: self.config & 0x4 != 0,
+        }
     }
 }
 
@@ -29,3 +40,96 @@ pub(crate) struct GetHwCode {
     #[protocol(rx, status = 0)]
     status: u16,
 }
+
+/// Hardware sub code, hardware and software versions of the SoC
+#[derive(Default, Protocol)]
+#[protocol(command = 0xfc, echo)]
+pub(crate) struct GetHwSwVer {
+    #[protocol(rx, getter)]
+    hw_sub_code: u16,
+    #[protocol(rx, getter)]
+    hw_version: u16,
+    #[protocol(rx, getter)]
+    sw_version: u16,
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
+
+/// Preloader version, the BootROM doesn't know the command and echoes it
+#[derive(Default, Protocol)]
+#[protocol(command = 0xfe)]
+pub(crate) struct GetBlVer {
+    #[protocol(rx)]
+    version: u8,
+}
+
+/// Usbdl protocol version
+#[derive(Default, Protocol)]
+#[protocol(command = 0xff)]
+pub(crate) struct GetVersion {
+    #[protocol(rx)]
+    version: u8,
+}
+
+/// Unique ID of the SoC, used by SLA
+#[derive(Default, Protocol)]
+#[protocol(command = 0xe1, echo)]
+pub(crate) struct GetMeId {
+    #[protocol(rx)]
+    len: u32,
+    #[protocol(rx, getter, size = len)]
+    id: Vec<u8>,
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
+
+/// SoC ID, only on the newer SoCs
+#[derive(Default, Protocol)]
+#[protocol(command = 0xe7, echo)]
+pub(crate) struct GetSocId {
+    #[protocol(rx)]
+    len: u32,
+    #[protocol(rx, getter, size = len)]
+    id: Vec<u8>,
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
+
+/// Init the PMIC wrapper for the `PwrRead16`
+#[derive(Default, Protocol)]
+#[protocol(command = 0xc4, echo)]
+pub(crate) struct PwrInit {
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
+
+/// Read a PMIC register
+#[derive(Default, Protocol)]
+#[protocol(command = 0xc6, echo)]
+pub(crate) struct PwrRead16 {
+    /// PMIC register
+    #[protocol(echo)]
+    addr: u16,
+    #[protocol(rx, status = 0)]
+    status: u16,
+    #[protocol(rx)]
+    value: u16,
+}
+
+/// Enable the UART1 log of the BootROM
+#[derive(Default, Protocol)]
+#[protocol(command = 0xdb, echo)]
+pub(crate) struct Uart1LogEn {
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
+
+/// Set the baud rate of the UART1 log
+#[derive(Default, Protocol)]
+#[protocol(command = 0xdc, echo)]
+pub(crate) struct Uart1SetBaudrate {
+    #[protocol(echo)]
+    baudrate: u32,
+    #[protocol(rx, status = 0)]
+    status: u16,
+}
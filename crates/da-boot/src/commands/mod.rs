@@ -1,3 +1,4 @@
 pub mod da;
 pub mod generic;
 pub mod preloader;
+pub mod xflash;
@@ -63,3 +63,66 @@ pub(crate) struct Read32 {
     #[protocol(rx, status = 0)]
     final_status: u16,
 }
+
+/// A command to write u32 to the memory
+#[derive(Default, Protocol)]
+#[protocol(command = 0xd4, echo)]
+pub(crate) struct Write32 {
+    /// Start address
+    #[protocol(echo)]
+    addr: u32,
+    /// Number of u32 to write, one at a time is enough for the registers
+    #[protocol(echo, always = 1)]
+    dwords: u32,
+    /// Status after sec_region_check
+    #[protocol(rx, status = 0)]
+    status: u16,
+    /// U32 to write
+    #[protocol(echo)]
+    value: u32,
+    /// Write status
+    #[protocol(rx, status = 0)]
+    final_status: u16,
+}
+
+/// A command to read u16 from the memory
+#[derive(Default, Protocol)]
+#[protocol(command = 0xd0, echo)]
+pub(crate) struct Read16 {
+    /// Start address
+    #[protocol(echo)]
+    addr: u32,
+    /// Number of u16 to read
+    #[protocol(echo)]
+    words: u32,
+    /// Status after sec_region_check
+    #[protocol(rx, status = 0)]
+    status: u16,
+    /// U16s
+    #[protocol(rx, getter, size = words)]
+    buf: Vec<u16>,
+    /// Read status
+    #[protocol(rx, status = 0)]
+    final_status: u16,
+}
+
+/// A command to write u16 to the memory
+#[derive(Default, Protocol)]
+#[protocol(command = 0xd2, echo)]
+pub(crate) struct Write16 {
+    /// Start address
+    #[protocol(echo)]
+    addr: u32,
+    /// Number of u16 to write, one at a time is enough for the registers
+    #[protocol(echo, always = 1)]
+    words: u32,
+    /// Status after sec_region_check
+    #[protocol(rx, status = 0)]
+    status: u16,
+    /// U16 to write
+    #[protocol(echo)]
+    value: u16,
+    /// Write status
+    #[protocol(rx, status = 0)]
+    final_status: u16,
+}
@@ -0,0 +1,106 @@
+use da_boot_macros::XFlash;
+
+/// Command of the XFlash DA, the parameters are sent in their own packet
+pub trait XFlashCommand {
+    const COMMAND: u32;
+    /// Whether the DA reports the status of the command before taking the parameters
+    const ACKED: bool;
+
+    fn param(&self) -> Vec<u8>;
+}
+
+/// Device control request, `DeviceCtrl` sends it and gets its data back
+pub mod ctrl {
+    pub const GET_EMMC_INFO: u32 = 0x040001;
+    pub const GET_UFS_INFO: u32 = 0x040004;
+    pub const GET_DA_VERSION: u32 = 0x040005;
+    pub const GET_PACKET_LENGTH: u32 = 0x040007;
+}
+
+/// Storage type of the read and write commands
+pub mod storage {
+    pub const EMMC: u32 = 0x1;
+    pub const UFS: u32 = 0x30;
+}
+
+/// Log settings and the host OS for DA1
+#[derive(XFlash)]
+#[xflash(command = 0x010100, no_ack)]
+pub(crate) struct SetupEnvironment {
+    /// 2 - info
+    #[xflash(always = 2)]
+    log_level: u32,
+    /// 1 - UART
+    #[xflash(always = 1)]
+    log_channel: u32,
+    /// 1 - Linux
+    #[xflash(always = 1)]
+    system_os: u32,
+    #[xflash(always = 0)]
+    ufs_provision: u32,
+    #[xflash(always = 0)]
+    reserved: u32,
+}
+
+/// Hardware init parameters for DA1, none are passed
+#[derive(XFlash)]
+#[xflash(command = 0x010101, no_ack)]
+pub(crate) struct SetupHwInitParams {
+    /// Length of the parameters
+    #[xflash(always = 0)]
+    len: u32,
+}
+
+/// Request of the `ctrl` codes
+#[derive(XFlash)]
+#[xflash(command = 0x010009)]
+pub(crate) struct DeviceCtrl {
+    ctrl: u32,
+}
+
+/// Load DA2 to `addr` and jump to it, the data follows
+#[derive(XFlash)]
+#[xflash(command = 0x010008)]
+pub(crate) struct BootTo {
+    addr: u64,
+    len: u64,
+}
+
+/// Read `len` bytes at `addr` of the hardware partition, the data packets follow
+#[derive(XFlash)]
+#[xflash(command = 0x010005)]
+pub(crate) struct ReadData {
+    storage: u32,
+    /// eMMC: 1 and 2 - boot partitions, 8 - user area; UFS: LU number
+    part: u32,
+    addr: u64,
+    len: u64,
+}
+
+/// Write `len` bytes at `addr` of the hardware partition, the data packets follow
+#[derive(XFlash)]
+#[xflash(command = 0x010004)]
+pub(crate) struct WriteData {
+    storage: u32,
+    /// eMMC: 1 and 2 - boot partitions, 8 - user area; UFS: LU number
+    part: u32,
+    addr: u64,
+    len: u64,
+}
+
+/// Reset the device once DA is done
+#[derive(XFlash)]
+#[xflash(command = 0x010007)]
+pub(crate) struct Shutdown {
+    /// The flags below are used
+    #[xflash(always = 1)]
+    has_flags: u32,
+    #[xflash(always = 0)]
+    async_reset: u32,
+    /// Stay in the BootROM download mode after the reset
+    #[xflash(always = 0)]
+    dl_bit: u32,
+    /// 0 - normal boot
+    #[xflash(always = 0)]
+    boot_mode: u32,
+}
@@ -0,0 +1,386 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+    str::FromStr,
+};
+
+use acon::{MMIO, Memory, SoC};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use clap_num::maybe_hex;
+use da_protocol::Protocol;
+use kdam::{BarExt, tqdm};
+
+use crate::{
+    Port, State,
+    boot::{lk_arg::dram_banks, rpc::ext::HostExtensions},
+};
+
+/// Bytes read at once, also the granularity of the resume and the sparse output
+const CHUNK_SIZE: u32 = 64 * 1024;
+/// Size of the MMIO blocks in the presets
+const MMIO_BLOCK_SIZE: u32 = 0x1000;
+const MANIFEST: &str = "manifest.txt";
+
+/// Memory regions known from the SoC memory map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// BootROM code, on the SoCs with a known mapping
+    Brom,
+    /// L2 SRAM
+    L2Sram,
+    /// Every DRAM rank, needs the DRAM size
+    Dram,
+    /// Watchdog registers
+    Toprgu,
+    /// UART0 registers
+    Uart0,
+}
+
+/// BootROM mapped at 0, the window covers the whole mask ROM of these SoCs
+fn brom(soc: SoC) -> Option<Range<u32>> {
+    match soc {
+        SoC::MT6572 | SoC::MT6582 | SoC::MT6595 => Some(0..0x20000),
+        _ => None,
+    }
+}
+
+/// Region from the command line: preset, `START..END` or `START+SIZE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionSpec {
+    Preset(Preset),
+    Range(Range<u32>),
+}
+
+impl FromStr for RegionSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(preset) = <Preset as ValueEnum>::from_str(s, true) {
+            return Ok(Self::Preset(preset));
+        }
+
+        let (start, end) = if let Some((start, end)) = s.split_once("..") {
+            (maybe_hex::<u32>(start)?, maybe_hex::<u32>(end)?)
+        } else if let Some((start, size)) = s.split_once('+') {
+            let start = maybe_hex::<u32>(start)?;
+            let end = start
+                .checked_add(maybe_hex::<u32>(size)?)
+                .ok_or("region is too big")?;
+            (start, end)
+        } else {
+            return Err("expected preset, START..END or START+SIZE".into());
+        };
+
+        if start >= end {
+            return Err("region is empty".into());
+        }
+
+        Ok(Self::Range(start..end))
+    }
+}
+
+/// Region resolved for the connected SoC
+pub struct Region {
+    name: String,
+    range: Range<u32>,
+}
+
+impl Region {
+    fn new(name: impl Into<String>, range: Range<u32>) -> Self {
+        Self {
+            name: name.into(),
+            range,
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.range.end - self.range.start
+    }
+}
+
+/// What is known about the device memory once the SoC is detected
+pub struct MemoryMap {
+    soc: SoC,
+    dram: Vec<Range<u64>>,
+}
+
+impl MemoryMap {
+    pub fn new(state: &State) -> Self {
+        Self {
+            soc: state.soc,
+            dram: dram_banks(state.soc, state.dram_size_per_rank, state.dram_ranks),
+        }
+    }
+
+    fn resolve(&self, spec: &RegionSpec) -> Result<Vec<Region>> {
+        let mmio = |name, base: u32| vec![Region::new(name, base..base + MMIO_BLOCK_SIZE)];
+
+        Ok(match spec {
+            RegionSpec::Range(range) => vec![Region::new(
+                format!("{:08x}-{:08x}", range.start, range.end),
+                range.clone(),
+            )],
+            RegionSpec::Preset(Preset::Brom) => {
+                let range = brom(self.soc).context("BootROM range is unknown for this SoC")?;
+                vec![Region::new("brom", range)]
+            }
+            RegionSpec::Preset(Preset::L2Sram) => vec![Region::new("l2-sram", self.soc.l2_sram())],
+            RegionSpec::Preset(Preset::Dram) => {
+                if self.dram.is_empty() {
+                    anyhow::bail!(
+                        "Unknown DRAM size. Please provide DRAM rank size and rank count"
+                    );
+                }
+
+                let mut banks = Vec::new();
+                for (i, bank) in self.dram.iter().enumerate() {
+                    let start = u32::try_from(bank.start);
+                    let end = u32::try_from(bank.end);
+                    let (Ok(start), Ok(end)) = (start, end) else {
+                        anyhow::bail!("DRAM rank {i} is above 4GiB");
+                    };
+                    banks.push(Region::new(format!("dram{i}"), start..end));
+                }
+
+                banks
+            }
+            RegionSpec::Preset(Preset::Toprgu) => mmio("toprgu", self.soc.toprgu()),
+            RegionSpec::Preset(Preset::Uart0) => mmio("uart0", self.soc.uart0()),
+        })
+    }
+}
+
+/// Chunks of one byte left out of the sparse output
+struct Fill {
+    offset: u32,
+    size: u32,
+    byte: u8,
+}
+
+/// Manifest line of one region
+///
+/// `done` bytes from the start are in the file or listed as fills, the resume goes on from there.
+struct Entry {
+    name: String,
+    range: Range<u32>,
+    done: u32,
+    fills: Vec<Fill>,
+}
+
+impl Entry {
+    fn new(region: &Region) -> Self {
+        Self {
+            name: region.name.clone(),
+            range: region.range.clone(),
+            done: 0,
+            fills: Vec::new(),
+        }
+    }
+
+    fn add_fill(&mut self, offset: u32, size: u32, byte: u8) {
+        if let Some(last) = self.fills.last_mut()
+            && last.byte == byte
+            && last.offset + last.size == offset
+        {
+            last.size += size;
+        } else {
+            self.fills.push(Fill { offset, size, byte });
+        }
+    }
+}
+
+/// List of the dumped regions kept next to the files
+///
+/// ```text
+/// region NAME START..END FILE done DONE
+/// fill NAME OFFSET+SIZE BYTE
+/// ```
+struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST);
+        let mut entries: Vec<Entry> = Vec::new();
+        if !path.exists() {
+            return Ok(Self { entries });
+        }
+
+        let text = fs::read_to_string(&path).context("Failed to read dump manifest")?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let bad_line = || format!("Bad dump manifest line {}: {line}", i + 1);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["region", name, range, _file, "done", done] => {
+                    let Ok(RegionSpec::Range(range)) = range.parse() else {
+                        anyhow::bail!(bad_line());
+                    };
+                    entries.push(Entry {
+                        name: name.to_string(),
+                        range,
+                        done: maybe_hex(done).map_err(|_| anyhow::anyhow!(bad_line()))?,
+                        fills: Vec::new(),
+                    });
+                }
+                ["fill", name, chunk, byte] => {
+                    let entry = entries
+                        .iter_mut()
+                        .find(|e| e.name == *name)
+                        .with_context(bad_line)?;
+                    let Ok(RegionSpec::Range(chunk)) = chunk.parse() else {
+                        anyhow::bail!(bad_line());
+                    };
+                    let byte = maybe_hex::<u8>(byte).map_err(|_| anyhow::anyhow!(bad_line()))?;
+                    entry.add_fill(chunk.start, chunk.end - chunk.start, byte);
+                }
+                _ => anyhow::bail!(bad_line()),
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Written after every chunk, so the dump can be resumed any time
+    fn save(&self, dir: &Path, soc: SoC) -> Result<()> {
+        let mut text = format!("# da-boot memory dump, {soc:?}\n");
+        for entry in &self.entries {
+            text += &format!(
+                "region {} {:#010x}..{:#010x} {}.bin done {:#x}\n",
+                entry.name, entry.range.start, entry.range.end, entry.name, entry.done
+            );
+            for fill in &entry.fills {
+                text += &format!(
+                    "fill {} {:#x}+{:#x} {:#04x}\n",
+                    entry.name, fill.offset, fill.size, fill.byte
+                );
+            }
+        }
+
+        // never leave a half-written manifest behind
+        let tmp = dir.join(format!("{MANIFEST}.tmp"));
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, dir.join(MANIFEST)).context("Failed to write dump manifest")
+    }
+}
+
+/// Byte the whole chunk is filled with, only 0x00 and 0xff are of interest
+fn fill_byte(data: &[u8]) -> Option<u8> {
+    let byte = *data.first()?;
+    (matches!(byte, 0x00 | 0xff) && data.iter().all(|&b| b == byte)).then_some(byte)
+}
+
+fn dump_region(
+    protocol: &mut Protocol<Port>,
+    map: &MemoryMap,
+    manifest: &mut Manifest,
+    dir: &Path,
+    region: &Region,
+    resume: bool,
+    sparse: bool,
+) -> Result<()> {
+    let name = &region.name;
+    let i = match manifest.entries.iter().position(|e| e.name == *name) {
+        Some(i) if resume => {
+            if manifest.entries[i].range != region.range {
+                anyhow::bail!("{name} was dumped from another range, can't resume");
+            }
+            i
+        }
+        Some(i) => {
+            manifest.entries[i] = Entry::new(region);
+            i
+        }
+        None => {
+            manifest.entries.push(Entry::new(region));
+            manifest.entries.len() - 1
+        }
+    };
+
+    let len = region.len();
+    let mut done = manifest.entries[i].done;
+    if done >= len {
+        println!("{name} is already dumped");
+        return Ok(());
+    } else if done != 0 {
+        println!("Resuming {name} from {:#x}", region.range.start + done);
+    }
+
+    let path = dir.join(format!("{name}.bin"));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Can't open {}", path.display()))?;
+    // anything after the recorded progress may be incomplete
+    file.set_len(done as u64)?;
+    file.seek(SeekFrom::Start(done as u64))?;
+
+    let mut pb = tqdm!(total = len as usize, desc = name.clone(), unit = "B");
+    pb.update(done as usize)?;
+
+    while done < len {
+        let addr = region.range.start + done;
+        let size = CHUNK_SIZE.min(len - done);
+        let data = protocol.download(addr, size).with_context(|| {
+            format!("Failed to read {addr:#x}, rerun with --resume to continue")
+        })?;
+
+        if sparse && let Some(byte) = fill_byte(&data) {
+            // leave a hole, the manifest tells what's there
+            file.set_len((done + size) as u64)?;
+            file.seek(SeekFrom::Start((done + size) as u64))?;
+            manifest.entries[i].add_fill(done, size, byte);
+        } else {
+            file.write_all(&data)?;
+        }
+
+        done += size;
+        manifest.entries[i].done = done;
+        manifest.save(dir, map.soc)?;
+        pb.update(size as usize)?;
+    }
+
+    println!();
+    println!(
+        "Dumped {name} ({:#x}..{:#x}) to {}",
+        region.range.start,
+        region.range.end,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Stream the regions to `dir`, one file per region, and list them in the manifest
+pub fn run_dump(
+    protocol: &mut Protocol<Port>,
+    map: &MemoryMap,
+    regions: &[RegionSpec],
+    dir: &Path,
+    resume: bool,
+    sparse: bool,
+) -> Result<()> {
+    let mut resolved = Vec::new();
+    for spec in regions {
+        resolved.extend(map.resolve(spec)?);
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("Can't create {}", dir.display()))?;
+    let mut manifest = Manifest::load(dir)?;
+
+    for region in &resolved {
+        dump_region(protocol, map, &mut manifest, dir, region, resume, sparse)?;
+    }
+
+    println!("Manifest: {}", dir.join(MANIFEST).display());
+    Ok(())
+}
@@ -6,8 +6,18 @@ use std::{
     str::FromStr,
 };
 
+use anyhow::Context;
 use clap_num::maybe_hex;
 
+use crate::formats::{
+    Segment,
+    elf::{ELF_MAGIC, Elf},
+    fit::{Fit, is_fit},
+    ihex::{IHex, is_ihex},
+    srec::{SRec, is_srec},
+    uimage::{UImage, is_uimage},
+};
+
 /// File content for extractions
 pub struct FileContent(Vec<u8>);
 
@@ -87,13 +97,6 @@ pub struct UploadFile {
 }
 
 impl UploadFile {
-    pub fn try_new(path: PathBuf, addr: u32) -> Result<Self> {
-        Ok(Self {
-            content: FileContent::try_new(path)?,
-            addr,
-        })
-    }
-
     pub fn from_content(content: FileContent, addr: u32) -> Self {
         Self { content, addr }
     }
@@ -119,14 +122,6 @@ impl UploadFile {
     }
 }
 
-impl TryFrom<UploadFileSpec> for UploadFile {
-    type Error = std::io::Error;
-
-    fn try_from(value: UploadFileSpec) -> std::result::Result<Self, Self::Error> {
-        Self::try_new(value.file.0, value.addr)
-    }
-}
-
 impl Deref for UploadFile {
     type Target = [u8];
 
@@ -136,21 +131,26 @@ impl Deref for UploadFile {
 }
 
 /// Helper for the clap
+///
+/// The address is optional for the formats carrying their own load address.
 #[derive(Clone)]
 pub struct UploadFileSpec {
     file: FileContentSpec,
-    addr: u32,
+    addr: Option<u32>,
 }
 
 impl FromStr for UploadFileSpec {
     type Err = String;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let (path, addr) = s.rsplit_once('@').ok_or("expected PATH@ADDRESS")?;
+        let (path, addr) = match s.rsplit_once('@') {
+            Some((path, addr)) => (path, Some(maybe_hex(addr)?)),
+            None => (s, None),
+        };
 
         Ok(Self {
             file: FileContentSpec::from_str(path)?,
-            addr: maybe_hex(addr)?,
+            addr,
         })
     }
 }
@@ -162,3 +162,148 @@ impl Deref for UploadFileSpec {
         &self.file
     }
 }
+
+/// Everything loaded from one `--input`
+///
+/// Raw binary is a single file at the given address, other formats carry their own load
+/// addresses and may give several files. FIT may also supply the kernel, ramdisk and DTB.
+#[derive(Default)]
+pub struct InputFile {
+    pub files: Vec<UploadFile>,
+    /// Entry point, bit 0 is set for Thumb
+    pub entry: Option<u32>,
+    pub kernel: Option<FileContent>,
+    pub ramdisk: Option<FileContent>,
+    pub dtb: Option<FileContent>,
+}
+
+impl InputFile {
+    fn from_segments(segments: Vec<Segment>, entry: Option<u32>) -> Self {
+        Self {
+            files: segments
+                .into_iter()
+                .map(|s| UploadFile::from_content(FileContent::from(s.data), s.addr))
+                .collect(),
+            entry,
+            ..Default::default()
+        }
+    }
+
+    /// Text formats are sniffed only if `text` is set, a raw binary given with `@ADDRESS` may
+    /// start with `:` or `S0` as well
+    fn parse(data: &[u8], text: bool) -> anyhow::Result<Option<(&'static str, Self)>> {
+        let parsed = if data.starts_with(ELF_MAGIC) {
+            let elf = Elf::parse(data)?;
+            ("ELF", Self::from_segments(elf.segments, Some(elf.entry)))
+        } else if is_uimage(data) {
+            let image = UImage::parse(data)?;
+            println!("Loaded uImage {:?} (type {})", image.name, image.kind);
+            (
+                "uImage",
+                Self::from_segments(vec![image.segment], Some(image.entry)),
+            )
+        } else if is_fit(data) {
+            let fit = Fit::parse(data)?;
+            println!("Loaded FIT configuration {:?}", fit.config);
+            (
+                "FIT",
+                Self {
+                    kernel: fit.kernel.map(FileContent::from),
+                    ramdisk: fit.ramdisk.map(FileContent::from),
+                    dtb: fit.fdt.map(FileContent::from),
+                    ..Self::from_segments(fit.segments, fit.entry)
+                },
+            )
+        } else if text && is_ihex(data) {
+            let hex = IHex::parse(data)?;
+            ("Intel HEX", Self::from_segments(hex.segments, hex.entry))
+        } else if text && is_srec(data) {
+            let srec = SRec::parse(data)?;
+            ("S-record", Self::from_segments(srec.segments, srec.entry))
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(parsed))
+    }
+}
+
+impl TryFrom<UploadFileSpec> for InputFile {
+    type Error = anyhow::Error;
+
+    fn try_from(value: UploadFileSpec) -> anyhow::Result<Self> {
+        let path = value.file.0;
+        let content = FileContent::try_new(path.clone())
+            .with_context(|| format!("Can't read {}", path.display()))?;
+
+        let parsed = Self::parse(&content, value.addr.is_none())
+            .with_context(|| format!("Can't parse {}", path.display()))?;
+        if let Some((format, input)) = parsed {
+            if value.addr.is_some() {
+                anyhow::bail!(
+                    "{} is {format}, it's loaded at its own addresses, drop @ADDRESS",
+                    path.display()
+                );
+            }
+
+            return Ok(input);
+        }
+
+        let addr = value
+            .addr
+            .with_context(|| format!("{} is raw binary, expected PATH@ADDRESS", path.display()))?;
+
+        Ok(Self {
+            files: vec![UploadFile::from_content(content, addr)],
+            ..Default::default()
+        })
+    }
+}
+
+/// File served as partition content from the RAM
+pub struct RamPartFile {
+    pub name: String,
+    pub content: FileContent,
+}
+
+impl TryFrom<RamPartSpec> for RamPartFile {
+    type Error = std::io::Error;
+
+    fn try_from(value: RamPartSpec) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            name: value.name,
+            content: FileContent::try_from(value.file)?,
+        })
+    }
+}
+
+/// Helper for the clap
+#[derive(Clone)]
+pub struct RamPartSpec {
+    name: String,
+    file: FileContentSpec,
+}
+
+impl Deref for RamPartSpec {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.file
+    }
+}
+
+impl FromStr for RamPartSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, path) = s.split_once('=').ok_or("expected NAME=PATH")?;
+        if name.is_empty() {
+            return Err("partition name is empty".into());
+        }
+
+        Ok(Self {
+            name: name.into(),
+            file: FileContentSpec::from_str(path)?,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+
+const ATAG_NONE: u32 = 0x00000000;
+const ATAG_CORE: u32 = 0x54410001;
+const ATAG_MEM: u32 = 0x54410002;
+const ATAG_INITRD2: u32 = 0x54420005;
+const ATAG_CMDLINE: u32 = 0x54410009;
+
+/// ATAG list for the kernels without device tree support
+///
+/// The list always starts with `ATAG_CORE`, `build` terminates it with `ATAG_NONE`.
+pub struct AtagsBuilder {
+    words: Vec<u32>,
+}
+
+impl Default for AtagsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AtagsBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self { words: Vec::new() };
+        // flags, page size, root device
+        builder.push(ATAG_CORE, &[0, 0x1000, 0]);
+        builder
+    }
+
+    /// Every tag is its size in words (header included), the tag id and the payload
+    fn push(&mut self, tag: u32, payload: &[u32]) {
+        self.words.push(payload.len() as u32 + 2);
+        self.words.push(tag);
+        self.words.extend_from_slice(payload);
+    }
+
+    /// Add DRAM banks, one `ATAG_MEM` per bank
+    pub fn memory(mut self, banks: &[Range<u64>]) -> Result<Self> {
+        for bank in banks {
+            let start = u32::try_from(bank.start).context("DRAM bank is above 4GiB")?;
+            let size = u32::try_from(bank.end - bank.start).context("DRAM bank is too big")?;
+            self.push(ATAG_MEM, &[size, start]);
+        }
+
+        Ok(self)
+    }
+
+    /// Add physical location of the initrd
+    pub fn initrd(mut self, initrd: Range<u32>) -> Self {
+        self.push(ATAG_INITRD2, &[initrd.start, initrd.end - initrd.start]);
+        self
+    }
+
+    /// Add kernel command line, skipped if empty
+    pub fn cmdline(mut self, cmdline: &str) -> Self {
+        if cmdline.is_empty() {
+            return self;
+        }
+
+        let mut bytes = cmdline.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+
+        let payload: Vec<u32> = bytes
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        self.push(ATAG_CMDLINE, &payload);
+        self
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+        self.words.extend_from_slice(&[0, ATAG_NONE]);
+        self.words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+}
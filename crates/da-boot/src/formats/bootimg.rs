@@ -0,0 +1,479 @@
+use std::{
+    mem::{offset_of, size_of},
+    ptr,
+};
+
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::boot::give_me_bytes_please;
+
+pub const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+const BOOT_NAME_SIZE: usize = 16;
+const BOOT_ARGS_SIZE: usize = 512;
+const BOOT_EXTRA_ARGS_SIZE: usize = 1024;
+/// Both cmdline fields are NUL-terminated
+const MAX_CMDLINE_LEN: usize = BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE - 2;
+
+/// Android boot image header, versions 0 to 2
+///
+/// Fields after `extra_cmdline` exist only since v1, `dtb_*` only since v2.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct BootImgHeader {
+    pub magic: [u8; 8],
+    pub kernel_size: u32,
+    pub kernel_addr: u32,
+    pub ramdisk_size: u32,
+    pub ramdisk_addr: u32,
+    pub second_size: u32,
+    pub second_addr: u32,
+    pub tags_addr: u32,
+    pub page_size: u32,
+    pub header_version: u32,
+    pub os_version: u32,
+    pub name: [u8; BOOT_NAME_SIZE],
+    pub cmdline: [u8; BOOT_ARGS_SIZE],
+    pub id: [u32; 8],
+    pub extra_cmdline: [u8; BOOT_EXTRA_ARGS_SIZE],
+
+    pub recovery_dtbo_size: u32,
+    pub recovery_dtbo_offset: u64,
+    pub header_size: u32,
+
+    pub dtb_size: u32,
+    pub dtb_addr: u64,
+}
+
+impl BootImgHeader {
+    /// Size of the header for the given `version`
+    pub const fn size_for(version: u32) -> usize {
+        match version {
+            0 => 1632,
+            1 => 1648,
+            _ => 1660,
+        }
+    }
+
+    /// Full kernel command line, `cmdline` followed by the `extra_cmdline`
+    pub fn full_cmdline(&self) -> String {
+        let mut bytes = cstr_bytes(&self.cmdline).to_vec();
+        bytes.extend_from_slice(cstr_bytes(&self.extra_cmdline));
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Product name
+    pub fn board(&self) -> String {
+        String::from_utf8_lossy(cstr_bytes(&self.name)).into_owned()
+    }
+}
+
+/// Split the command line between `cmdline` and `extra_cmdline` fields
+///
+/// mkbootimg puts the first 511 bytes to the cmdline and the rest to the extra_cmdline.
+fn split_cmdline(cmdline: &str) -> Result<([u8; BOOT_ARGS_SIZE], [u8; BOOT_EXTRA_ARGS_SIZE])> {
+    if cmdline.len() > MAX_CMDLINE_LEN {
+        anyhow::bail!("Kernel command line must not exceed {MAX_CMDLINE_LEN} bytes");
+    }
+
+    let mut args = [0; BOOT_ARGS_SIZE];
+    let mut extra_args = [0; BOOT_EXTRA_ARGS_SIZE];
+    let bytes = cmdline.as_bytes();
+    let split = bytes.len().min(BOOT_ARGS_SIZE - 1);
+    args[..split].copy_from_slice(&bytes[..split]);
+    extra_args[..bytes.len() - split].copy_from_slice(&bytes[split..]);
+
+    Ok((args, extra_args))
+}
+
+fn cstr_bytes(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Parsed boot.img, parts are borrowed from the image
+pub struct BootImg<'a> {
+    pub header: BootImgHeader,
+    pub kernel: &'a [u8],
+    pub ramdisk: &'a [u8],
+    pub second: &'a [u8],
+    pub recovery_dtbo: &'a [u8],
+    pub dtb: &'a [u8],
+}
+
+impl<'a> BootImg<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(BOOT_MAGIC) {
+            anyhow::bail!("Not a boot.img");
+        } else if data.len() < BootImgHeader::size_for(0) {
+            anyhow::bail!("boot.img header is truncated");
+        }
+
+        let mut raw = [0u8; size_of::<BootImgHeader>()];
+        let len = data.len().min(raw.len());
+        raw[..len].copy_from_slice(&data[..len]);
+        // SAFETY: the header is packed and consists of integers only
+        let mut header: BootImgHeader = unsafe { ptr::read_unaligned(raw.as_ptr().cast()) };
+
+        let version = header.header_version;
+        if version > 2 {
+            anyhow::bail!("boot.img header v{version} is not supported, only v0-v2");
+        }
+
+        // whatever follows the header of the older versions is not ours
+        if version < 1 {
+            header.recovery_dtbo_size = 0;
+            header.recovery_dtbo_offset = 0;
+            header.header_size = 0;
+        }
+        if version < 2 {
+            header.dtb_size = 0;
+            header.dtb_addr = 0;
+        }
+
+        let page_size = header.page_size as usize;
+        if !page_size.is_power_of_two() || page_size < 2048 {
+            anyhow::bail!("Invalid page size {page_size}");
+        }
+
+        let mut offset = page_size;
+        let mut next_part = |size: u32, name: &str| -> Result<&'a [u8]> {
+            let size = size as usize;
+            let part = data
+                .get(offset..offset + size)
+                .with_context(|| format!("boot.img is truncated, can't read {name}"))?;
+            offset += size.next_multiple_of(page_size);
+            Ok(part)
+        };
+
+        let kernel = next_part(header.kernel_size, "kernel")?;
+        let ramdisk = next_part(header.ramdisk_size, "ramdisk")?;
+        let second = next_part(header.second_size, "second stage bootloader")?;
+        let recovery_dtbo = next_part(header.recovery_dtbo_size, "recovery DTBO")?;
+        let dtb = next_part(header.dtb_size, "DTB")?;
+
+        Ok(Self {
+            header,
+            kernel,
+            ramdisk,
+            second,
+            recovery_dtbo,
+            dtb,
+        })
+    }
+
+    /// Replace the command line in the header of the boot.img in `data`
+    ///
+    /// The image ID covers only the parts, so it stays valid.
+    pub fn set_cmdline(data: &mut [u8], cmdline: &str) -> Result<()> {
+        if !data.starts_with(BOOT_MAGIC) {
+            anyhow::bail!("Not a boot.img");
+        } else if data.len() < BootImgHeader::size_for(0) {
+            anyhow::bail!("boot.img header is truncated");
+        }
+
+        let (args, extra_args) = split_cmdline(cmdline)?;
+        let offset = offset_of!(BootImgHeader, cmdline);
+        data[offset..offset + BOOT_ARGS_SIZE].copy_from_slice(&args);
+        let offset = offset_of!(BootImgHeader, extra_cmdline);
+        data[offset..offset + BOOT_EXTRA_ARGS_SIZE].copy_from_slice(&extra_args);
+
+        Ok(())
+    }
+}
+
+/// Load addresses of the boot.img parts
+///
+/// Offsets are relative to the `base`, the same way as in mkbootimg.
+#[derive(Debug, Clone, Copy)]
+pub struct BootImgLayout {
+    pub base: u32,
+    pub kernel_offset: u32,
+    pub ramdisk_offset: u32,
+    pub second_offset: u32,
+    pub tags_offset: u32,
+    pub dtb_offset: u32,
+    pub page_size: u32,
+    pub header_version: u32,
+}
+
+impl BootImgLayout {
+    pub fn kernel_addr(&self) -> u32 {
+        self.base.wrapping_add(self.kernel_offset)
+    }
+
+    pub fn ramdisk_addr(&self) -> u32 {
+        self.base.wrapping_add(self.ramdisk_offset)
+    }
+
+    pub fn second_addr(&self) -> u32 {
+        self.base.wrapping_add(self.second_offset)
+    }
+
+    pub fn tags_addr(&self) -> u32 {
+        self.base.wrapping_add(self.tags_offset)
+    }
+
+    pub fn dtb_addr(&self) -> u32 {
+        self.base.wrapping_add(self.dtb_offset)
+    }
+}
+
+/// boot.img builder, a replacement for the mkbootimg
+pub struct BootImgBuilder<'a> {
+    layout: BootImgLayout,
+    kernel: &'a [u8],
+    ramdisk: &'a [u8],
+    second: &'a [u8],
+    dtb: &'a [u8],
+    cmdline: &'a str,
+    board: &'a str,
+}
+
+impl<'a> BootImgBuilder<'a> {
+    pub fn new(layout: BootImgLayout, kernel: &'a [u8], ramdisk: &'a [u8]) -> Self {
+        Self {
+            layout,
+            kernel,
+            ramdisk,
+            second: &[],
+            dtb: &[],
+            cmdline: "",
+            board: "",
+        }
+    }
+
+    /// Set second stage bootloader
+    pub fn second(mut self, second: &'a [u8]) -> Self {
+        self.second = second;
+        self
+    }
+
+    /// Set device tree blob, stored in the image since header v2
+    pub fn dtb(mut self, dtb: &'a [u8]) -> Self {
+        self.dtb = dtb;
+        self
+    }
+
+    /// Set kernel command line
+    pub fn cmdline(mut self, cmdline: &'a str) -> Self {
+        self.cmdline = cmdline;
+        self
+    }
+
+    /// Set product name
+    pub fn board(mut self, board: &'a str) -> Self {
+        self.board = board;
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let layout = &self.layout;
+        let version = layout.header_version;
+        let page_size = layout.page_size as usize;
+
+        if version > 2 {
+            anyhow::bail!("boot.img header v{version} is not supported, only v0-v2");
+        } else if !page_size.is_power_of_two() || page_size < 2048 {
+            anyhow::bail!("Invalid page size {page_size}");
+        } else if self.board.len() >= BOOT_NAME_SIZE {
+            anyhow::bail!("Board name must be shorter than {BOOT_NAME_SIZE} bytes");
+        } else if version < 2 && !self.dtb.is_empty() {
+            anyhow::bail!("DTB field requires boot.img header v2");
+        }
+
+        let mut name = [0; BOOT_NAME_SIZE];
+        name[..self.board.len()].copy_from_slice(self.board.as_bytes());
+
+        let (cmdline, extra_cmdline) = split_cmdline(self.cmdline)?;
+
+        let header = BootImgHeader {
+            magic: *BOOT_MAGIC,
+            kernel_size: self.kernel.len() as u32,
+            kernel_addr: layout.kernel_addr(),
+            ramdisk_size: self.ramdisk.len() as u32,
+            ramdisk_addr: layout.ramdisk_addr(),
+            second_size: self.second.len() as u32,
+            second_addr: layout.second_addr(),
+            tags_addr: layout.tags_addr(),
+            page_size: layout.page_size,
+            header_version: version,
+            os_version: 0,
+            name,
+            cmdline,
+            id: self.id(),
+            extra_cmdline,
+            recovery_dtbo_size: 0,
+            recovery_dtbo_offset: 0,
+            header_size: if version > 0 {
+                BootImgHeader::size_for(version) as u32
+            } else {
+                0
+            },
+            dtb_size: self.dtb.len() as u32,
+            dtb_addr: if version > 1 {
+                layout.dtb_addr() as u64
+            } else {
+                0
+            },
+        };
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&give_me_bytes_please(&header)[..BootImgHeader::size_for(version)]);
+        for part in [self.kernel, self.ramdisk, self.second, self.dtb] {
+            image.resize(image.len().next_multiple_of(page_size), 0);
+            image.extend_from_slice(part);
+        }
+        image.resize(image.len().next_multiple_of(page_size), 0);
+
+        Ok(image)
+    }
+
+    /// SHA-1 of the parts and their sizes, mkbootimg-compatible
+    fn id(&self) -> [u32; 8] {
+        let mut sha = Sha1::new();
+        let mut parts = vec![self.kernel, self.ramdisk, self.second];
+        if self.layout.header_version > 0 {
+            // recovery DTBO is never set
+            parts.push(&[]);
+        }
+        if self.layout.header_version > 1 {
+            parts.push(self.dtb);
+        }
+
+        for part in parts {
+            sha.update(part);
+            sha.update((part.len() as u32).to_le_bytes());
+        }
+
+        let digest = sha.finalize();
+        let mut id = [0; 8];
+        for (i, chunk) in digest.chunks(4).enumerate() {
+            id[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CMDLINE: &str = "console=ttyMT0,921600n1";
+
+    fn layout(header_version: u32) -> BootImgLayout {
+        BootImgLayout {
+            base: 0x10000000,
+            kernel_offset: 0x8000,
+            ramdisk_offset: 0x1000000,
+            second_offset: 0xf00000,
+            tags_offset: 0x100,
+            dtb_offset: 0x1f00000,
+            page_size: 2048,
+            header_version,
+        }
+    }
+
+    fn build(header_version: u32, dtb: &[u8]) -> Vec<u8> {
+        BootImgBuilder::new(layout(header_version), b"kernel", b"ramdisk")
+            .dtb(dtb)
+            .cmdline(CMDLINE)
+            .board("mt6572")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for version in 0..=2 {
+            let dtb: &[u8] = if version == 2 { b"dtb" } else { b"" };
+            let image = BootImgBuilder::new(layout(version), b"kernel", b"ramdisk")
+                .second(b"second")
+                .dtb(dtb)
+                .cmdline(CMDLINE)
+                .board("mt6572")
+                .build()
+                .unwrap();
+            let parsed = BootImg::parse(&image).unwrap();
+            let header = parsed.header;
+
+            assert_eq!(
+                (parsed.kernel, parsed.ramdisk, parsed.second, parsed.dtb),
+                (&b"kernel"[..], &b"ramdisk"[..], &b"second"[..], dtb)
+            );
+            assert_eq!({ header.header_version }, version);
+            assert_eq!({ header.kernel_addr }, 0x10008000);
+            assert_eq!({ header.ramdisk_addr }, 0x11000000);
+            assert_eq!({ header.second_addr }, 0x10f00000);
+            assert_eq!({ header.tags_addr }, 0x10000100);
+            assert_eq!(header.full_cmdline(), CMDLINE);
+            assert_eq!(header.board(), "mt6572");
+            let header_size = if version > 0 {
+                BootImgHeader::size_for(version)
+            } else {
+                0
+            };
+            assert_eq!({ header.header_size } as usize, header_size);
+            let dtb_addr = if version > 1 { 0x11f00000 } else { 0 };
+            assert_eq!({ header.dtb_addr }, dtb_addr);
+        }
+    }
+
+    /// SHA-1 of the whole image and its ID made by mkbootimg from the same inputs
+    #[test]
+    fn mkbootimg_layout() {
+        let image = build(0, b"");
+        assert_eq!(image.len(), 3 * 2048);
+        assert_eq!(&image[2048..2054], b"kernel");
+        assert_eq!(&image[4096..4103], b"ramdisk");
+        assert_eq!({ BootImg::parse(&image).unwrap().header.id }[5..], [0; 3]);
+        assert_eq!(
+            format!("{:x}", Sha1::digest(&image)),
+            "4faea796ac505dc8622eb0172bf0d26313d7f051"
+        );
+
+        let image = build(2, b"dtb");
+        let id: Vec<u8> = { BootImg::parse(&image).unwrap().header.id }
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert_eq!(
+            &id[..20],
+            &hex_bytes("7bfbb9fb2b9e8de49aea126a957fba6c0418c856")
+        );
+        assert_eq!(
+            format!("{:x}", Sha1::digest(&image)),
+            "eadf0b318aeb5fe36d69aeb959e3fe573d581c11"
+        );
+    }
+
+    fn hex_bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn set_cmdline() {
+        let mut image = build(1, b"");
+        BootImg::set_cmdline(&mut image, &"a".repeat(600)).unwrap();
+        let header = BootImg::parse(&image).unwrap().header;
+        assert_eq!(header.full_cmdline(), "a".repeat(600));
+        assert!(BootImg::set_cmdline(&mut image, &"a".repeat(MAX_CMDLINE_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut image = build(0, b"");
+        assert!(BootImg::parse(&image[..1024]).is_err());
+        assert!(BootImg::parse(&image[..4096]).is_err());
+
+        image[offset_of!(BootImgHeader, header_version)] = 3;
+        assert!(BootImg::parse(&image).is_err());
+
+        let builder = BootImgBuilder::new(layout(1), b"kernel", b"ramdisk");
+        assert!(builder.dtb(b"dtb").build().is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+
+/// Identifier at the start of the SP Flash Tool DA file
+pub const DA_FILE_ID: &[u8] = b"MTK_DOWNLOAD_AGENT";
+const COUNT_OFFSET: usize = 0x68;
+const ENTRIES_OFFSET: usize = 0x6c;
+const ENTRY_SIZE: usize = 0xdc;
+const ENTRY_MAGIC: u16 = 0xdada;
+const REGIONS_OFFSET: usize = 0x14;
+const REGION_SIZE: usize = 0x14;
+const MAX_REGIONS: usize = 10;
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Part of the DA loaded to its own address, the signature is at its end
+pub struct DaRegion {
+    pub offset: u32,
+    pub len: u32,
+    pub addr: u32,
+    pub sig_len: u32,
+}
+
+/// DA for one SoC
+pub struct DaEntry {
+    pub hw_code: u16,
+    pub hw_sub_code: u16,
+    pub hw_version: u16,
+    pub sw_version: u16,
+    /// Header, DA1 loaded to the SRAM by the BootROM or preloader, DA2 loaded to the DRAM by DA1
+    pub regions: Vec<DaRegion>,
+}
+
+impl DaEntry {
+    /// DA1, run by `SendDA`/`JumpDA`
+    pub fn da1(&self) -> Result<&DaRegion> {
+        self.regions.get(1).context("DA has no DA1 region")
+    }
+
+    /// DA2, uploaded through DA1
+    pub fn da2(&self) -> Result<&DaRegion> {
+        self.regions.get(2).context("DA has no DA2 region")
+    }
+}
+
+/// `MTK_AllInOne_DA.bin` of SP Flash Tool with the DAs for many SoCs
+pub struct DaFile {
+    data: Vec<u8>,
+    pub entries: Vec<DaEntry>,
+}
+
+impl DaFile {
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        if !data.starts_with(DA_FILE_ID) {
+            anyhow::bail!("Not a MediaTek DA file");
+        }
+
+        let count = le32(
+            data.get(..ENTRIES_OFFSET)
+                .context("DA header is truncated")?,
+            COUNT_OFFSET,
+        ) as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = ENTRIES_OFFSET + i * ENTRY_SIZE;
+            let entry = data
+                .get(start..start + ENTRY_SIZE)
+                .with_context(|| format!("DA entry {i} is truncated"))?;
+            if le16(entry, 0) != ENTRY_MAGIC {
+                anyhow::bail!("DA entry {i} has no magic");
+            }
+
+            let region_count = (le16(entry, 0x12) as usize).min(MAX_REGIONS);
+            let mut regions = Vec::with_capacity(region_count);
+            for r in 0..region_count {
+                let region = REGIONS_OFFSET + r * REGION_SIZE;
+                let (len, sig_len) = (le32(entry, region + 4), le32(entry, region + 16));
+                if sig_len > len {
+                    anyhow::bail!("DA entry {i} region {r} has a signature longer than itself");
+                }
+
+                regions.push(DaRegion {
+                    offset: le32(entry, region),
+                    len,
+                    addr: le32(entry, region + 8),
+                    sig_len,
+                });
+            }
+
+            entries.push(DaEntry {
+                hw_code: le16(entry, 2),
+                hw_sub_code: le16(entry, 4),
+                hw_version: le16(entry, 6),
+                sw_version: le16(entry, 8),
+                regions,
+            });
+        }
+
+        Ok(Self { data, entries })
+    }
+
+    /// DA for the SoC with `hw_code`
+    pub fn find(&self, hw_code: u16) -> Option<&DaEntry> {
+        self.entries.iter().find(|e| e.hw_code == hw_code)
+    }
+
+    /// Content of the region, signature included
+    pub fn region_data(&self, region: &DaRegion) -> Result<&[u8]> {
+        let start = region.offset as usize;
+        self.data
+            .get(start..start + region.len as usize)
+            .context("DA region is out of the file")
+    }
+}
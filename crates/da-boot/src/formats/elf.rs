@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+
+use crate::formats::Segment;
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+
+/// 32-bit little endian ARM ELF, only what is needed to load it
+pub struct Elf {
+    /// Entry point, bit 0 is set for Thumb
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+}
+
+fn le16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).context("ELF is truncated")?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn le32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("ELF is truncated")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !data.starts_with(ELF_MAGIC) {
+            anyhow::bail!("Not an ELF file");
+        } else if data.get(4) != Some(&ELFCLASS32) || data.get(5) != Some(&ELFDATA2LSB) {
+            anyhow::bail!("Only 32-bit little endian ELF is supported");
+        } else if le16(data, 0x12)? != EM_ARM {
+            anyhow::bail!("ELF is not for ARM");
+        }
+
+        let entry = le32(data, 0x18)?;
+        let phoff = le32(data, 0x1c)? as usize;
+        let phentsize = le16(data, 0x2a)? as usize;
+        let phnum = le16(data, 0x2c)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if le32(data, ph)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = le32(data, ph + 4)? as usize;
+            let paddr = le32(data, ph + 12)?;
+            let filesz = le32(data, ph + 16)? as usize;
+            let memsz = le32(data, ph + 20)? as usize;
+            if memsz == 0 {
+                continue;
+            } else if filesz > memsz {
+                anyhow::bail!("Segment {i} is bigger in the file than in the memory");
+            }
+
+            let mut content = data
+                .get(offset..offset + filesz)
+                .with_context(|| format!("ELF is truncated, can't read segment {i}"))?
+                .to_vec();
+            // BSS
+            content.resize(memsz, 0);
+
+            segments.push(Segment {
+                addr: paddr,
+                data: content,
+            });
+        }
+
+        if segments.is_empty() {
+            anyhow::bail!("ELF has no loadable segments");
+        }
+
+        Ok(Self { entry, segments })
+    }
+}
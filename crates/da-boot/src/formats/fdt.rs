@@ -0,0 +1,482 @@
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Flattened device tree, just enough to patch the nodes and write it back
+#[derive(Debug, Clone)]
+pub struct Fdt {
+    pub boot_cpuid: u32,
+    pub reserved: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Node {
+    pub name: String,
+    pub props: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Device tree is truncated")?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn be64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(((be32(data, offset)? as u64) << 32) | be32(data, offset + 4)? as u64)
+}
+
+fn cstr(data: &[u8], offset: usize) -> Result<&str> {
+    let bytes = data.get(offset..).context("Device tree is truncated")?;
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .context("Unterminated string in the device tree")?;
+    std::str::from_utf8(&bytes[..len]).context("Invalid string in the device tree")
+}
+
+/// Encode `value` as `cells` 32-bit big endian cells
+fn push_cells(out: &mut Vec<u8>, value: u64, cells: u32) -> Result<()> {
+    match cells {
+        1 => out.extend_from_slice(
+            &u32::try_from(value)
+                .context("Value doesn't fit into one cell")?
+                .to_be_bytes(),
+        ),
+        2 => out.extend_from_slice(&value.to_be_bytes()),
+        _ => anyhow::bail!("Unsupported cell count {cells}"),
+    }
+    Ok(())
+}
+
+impl Fdt {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            anyhow::bail!("Not a device tree blob");
+        }
+
+        let total_size = be32(data, 4)? as usize;
+        let data = data.get(..total_size).context("Device tree is truncated")?;
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let off_rsvmap = be32(data, 16)? as usize;
+        let version = be32(data, 20)?;
+        let boot_cpuid = be32(data, 28)?;
+        if version < FDT_LAST_COMP_VERSION {
+            anyhow::bail!("Device tree version {version} is too old");
+        }
+
+        let mut reserved = Vec::new();
+        let mut offset = off_rsvmap;
+        loop {
+            let (addr, size) = (be64(data, offset)?, be64(data, offset + 8)?);
+            if addr == 0 && size == 0 {
+                break;
+            }
+            reserved.push((addr, size));
+            offset += 16;
+        }
+
+        let strings = data
+            .get(off_strings..)
+            .context("Device tree is truncated")?;
+        let mut offset = off_struct;
+        let root = loop {
+            match be32(data, offset)? {
+                FDT_NOP => offset += 4,
+                FDT_BEGIN_NODE => break Self::parse_node(data, strings, &mut offset)?,
+                token => anyhow::bail!("Unexpected device tree token {token:#x}"),
+            }
+        };
+
+        Ok(Self {
+            boot_cpuid,
+            reserved,
+            root,
+        })
+    }
+
+    /// Parse node starting at the FDT_BEGIN_NODE token
+    fn parse_node(data: &[u8], strings: &[u8], offset: &mut usize) -> Result<Node> {
+        let name = cstr(data, *offset + 4)?;
+        *offset = (*offset + 4 + name.len() + 1).next_multiple_of(4);
+
+        let mut node = Node {
+            name: name.into(),
+            ..Default::default()
+        };
+        loop {
+            match be32(data, *offset)? {
+                FDT_BEGIN_NODE => node.children.push(Self::parse_node(data, strings, offset)?),
+                FDT_END_NODE => {
+                    *offset += 4;
+                    return Ok(node);
+                }
+                FDT_PROP => {
+                    let len = be32(data, *offset + 4)? as usize;
+                    let name = cstr(strings, be32(data, *offset + 8)? as usize)?;
+                    let start = *offset + 12;
+                    let value = data
+                        .get(start..start + len)
+                        .context("Device tree is truncated")?;
+                    node.props.push(Property {
+                        name: name.into(),
+                        value: value.to_vec(),
+                    });
+                    *offset = (start + len).next_multiple_of(4);
+                }
+                FDT_NOP => *offset += 4,
+                token => anyhow::bail!("Unexpected device tree token {token:#x}"),
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        Self::write_node(&self.root, &mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let mut rsvmap = Vec::new();
+        for (addr, size) in self.reserved.iter().chain([&(0, 0)]) {
+            rsvmap.extend_from_slice(&addr.to_be_bytes());
+            rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+
+        let off_rsvmap = FDT_HEADER_SIZE.next_multiple_of(8);
+        let off_struct = off_rsvmap + rsvmap.len();
+        let off_strings = off_struct + structure.len();
+        let total_size = off_strings + strings.len();
+
+        let mut out = Vec::with_capacity(total_size);
+        for value in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.resize(off_rsvmap, 0);
+        out.extend_from_slice(&rsvmap);
+        out.extend_from_slice(&structure);
+        out.extend_from_slice(&strings);
+
+        out
+    }
+
+    fn write_node(node: &Node, out: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        out.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        out.extend_from_slice(node.name.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+
+        for prop in &node.props {
+            let name_offset = Self::string_offset(strings, &prop.name);
+            out.extend_from_slice(&FDT_PROP.to_be_bytes());
+            out.extend_from_slice(&(prop.value.len() as u32).to_be_bytes());
+            out.extend_from_slice(&name_offset.to_be_bytes());
+            out.extend_from_slice(&prop.value);
+            out.resize(out.len().next_multiple_of(4), 0);
+        }
+
+        for child in &node.children {
+            Self::write_node(child, out, strings);
+        }
+
+        out.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    }
+
+    /// Find `name` in the string table or append it
+    fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in strings.split(|&b| b == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+
+        let offset = strings.len();
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+        offset as u32
+    }
+
+    /// Root `#address-cells` and `#size-cells`
+    fn root_cells(&self) -> (u32, u32) {
+        (
+            self.root.prop_u32("#address-cells").unwrap_or(2),
+            self.root.prop_u32("#size-cells").unwrap_or(1),
+        )
+    }
+
+    /// Replace `reg` of the `/memory` node with the DRAM banks, other memory nodes are removed
+    pub fn set_memory(&mut self, banks: &[Range<u64>]) -> Result<()> {
+        let (address_cells, size_cells) = self.root_cells();
+
+        let mut reg = Vec::new();
+        for bank in banks {
+            push_cells(&mut reg, bank.start, address_cells)?;
+            push_cells(&mut reg, bank.end - bank.start, size_cells)?;
+        }
+
+        // the first memory node gets all the banks, stale ranges of the others must not stay
+        let is_memory = |n: &Node| n.name == "memory" || n.name.starts_with("memory@");
+        let memory = self.root.children.iter().position(is_memory);
+        let memory = if let Some(i) = memory {
+            let mut index = 0;
+            self.root.children.retain(|n| {
+                let keep = index == i || !is_memory(n);
+                index += 1;
+                keep
+            });
+            &mut self.root.children[i]
+        } else {
+            self.root.child_or_insert("memory")
+        };
+        memory.set_prop("device_type", b"memory\0".to_vec());
+        memory.set_prop("reg", reg);
+
+        Ok(())
+    }
+
+    /// Set `/chosen` bootargs and initrd location
+    pub fn set_chosen(&mut self, bootargs: Option<&str>, initrd: Option<Range<u64>>) -> Result<()> {
+        let chosen = self.root.child_or_insert("chosen");
+
+        if let Some(bootargs) = bootargs {
+            let mut value = bootargs.as_bytes().to_vec();
+            value.push(0);
+            chosen.set_prop("bootargs", value);
+        }
+
+        if let Some(initrd) = initrd {
+            // the kernel accepts both 32 and 64 bit values here
+            let cells = if initrd.end > u32::MAX as u64 { 2 } else { 1 };
+            for (name, value) in [
+                ("linux,initrd-start", initrd.start),
+                ("linux,initrd-end", initrd.end),
+            ] {
+                let mut bytes = Vec::new();
+                push_cells(&mut bytes, value, cells)?;
+                chosen.set_prop(name, bytes);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Node {
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.value.as_slice())
+    }
+
+    /// String property, up to the first NUL
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        let value = self.prop(name)?;
+        let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        std::str::from_utf8(&value[..len]).ok()
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|n| n.name == name)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let value = self.prop(name)?;
+        Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+    }
+
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        if let Some(prop) = self.props.iter_mut().find(|p| p.name == name) {
+            prop.value = value;
+        } else {
+            self.props.push(Property {
+                name: name.into(),
+                value,
+            });
+        }
+    }
+
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Node {
+        let i = if let Some(i) = self.children.iter().position(|n| n.name == name) {
+            i
+        } else {
+            self.children.push(Node {
+                name: name.into(),
+                ..Default::default()
+            });
+            self.children.len() - 1
+        };
+
+        &mut self.children[i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+
+    fn cells(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn tree() -> Fdt {
+        let mut fdt = Fdt {
+            boot_cpuid: 0,
+            reserved: vec![(0x80000000, 0x100000)],
+            root: Node::default(),
+        };
+        fdt.root.set_prop("#address-cells", cells(&[1]));
+        fdt.root.set_prop("#size-cells", cells(&[1]));
+        fdt.root.set_prop("model", b"MT6572\0".to_vec());
+        for (name, reg) in [
+            ("memory@80000000", [0x80000000, 0x10000000]),
+            ("memory@90000000", [0x90000000, 0x10000000]),
+        ] {
+            let memory = fdt.root.child_or_insert(name);
+            memory.set_prop("device_type", b"memory\0".to_vec());
+            memory.set_prop("reg", cells(&reg));
+        }
+        fdt.root
+            .child_or_insert("soc")
+            .child_or_insert("serial@11005000")
+            .set_prop("status", b"okay\0".to_vec());
+
+        fdt
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = tree().to_bytes();
+        let fdt = Fdt::parse(&bytes).unwrap();
+        assert_eq!(fdt.reserved, [(0x80000000, 0x100000)]);
+        assert_eq!(fdt.root.prop_str("model"), Some("MT6572"));
+        assert_eq!(fdt.root.prop_u32("#address-cells"), Some(1));
+        let serial = fdt.root.child("soc").unwrap().child("serial@11005000");
+        assert_eq!(serial.unwrap().prop_str("status"), Some("okay"));
+        assert_eq!(fdt.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn parse_skips_nops() {
+        let mut bytes = tree().to_bytes();
+        let off_struct = be32(&bytes, 8).unwrap() as usize;
+        bytes.splice(off_struct..off_struct, FDT_NOP.to_be_bytes());
+        // total size, strings offset and struct size grow, the struct starts with the NOP
+        for offset in [4, 12, 36] {
+            let value = be32(&bytes, offset).unwrap() + 4;
+            bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        }
+
+        let fdt = Fdt::parse(&bytes).unwrap();
+        assert_eq!(fdt.to_bytes(), tree().to_bytes());
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        let bytes = tree().to_bytes();
+        assert!(Fdt::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Fdt::parse(&bytes[4..]).is_err());
+        assert!(Fdt::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn set_memory_replaces_all_nodes() {
+        let mut fdt = tree();
+        fdt.set_memory(&[0x80000000..0xa0000000, 0xc0000000..0xc8000000])
+            .unwrap();
+
+        let fdt = Fdt::parse(&fdt.to_bytes()).unwrap();
+        let memory: Vec<&Node> = fdt
+            .root
+            .children
+            .iter()
+            .filter(|n| n.name.starts_with("memory"))
+            .collect();
+        assert_eq!(memory.len(), 1);
+        assert_eq!(memory[0].prop_str("device_type"), Some("memory"));
+        assert_eq!(
+            memory[0].prop("reg"),
+            Some(&cells(&[0x80000000, 0x20000000, 0xc0000000, 0x8000000])[..])
+        );
+        assert!(fdt.root.child("soc").is_some());
+    }
+
+    #[test]
+    fn set_memory_adds_node() {
+        let mut fdt = Fdt {
+            boot_cpuid: 0,
+            reserved: Vec::new(),
+            root: Node::default(),
+        };
+        // default cells are 2 for the address and 1 for the size
+        fdt.set_memory(slice::from_ref(&(0x80000000..0x90000000)))
+            .unwrap();
+        let memory = fdt.root.child("memory").unwrap();
+        assert_eq!(
+            memory.prop("reg"),
+            Some(&cells(&[0, 0x80000000, 0x10000000])[..])
+        );
+        assert!(fdt.set_memory(slice::from_ref(&(0..0x100000000))).is_err());
+    }
+
+    #[test]
+    fn set_chosen() {
+        let mut fdt = tree();
+        fdt.set_chosen(Some("console=ttyMT0"), Some(0x84000000..0x84100000))
+            .unwrap();
+        let chosen = fdt.root.child("chosen").unwrap();
+        assert_eq!(chosen.prop_str("bootargs"), Some("console=ttyMT0"));
+        assert_eq!(chosen.prop_u32("linux,initrd-start"), Some(0x84000000));
+        assert_eq!(chosen.prop_u32("linux,initrd-end"), Some(0x84100000));
+
+        // bootargs are kept without a new value, initrd gets 64 bit cells above 4G
+        fdt.set_chosen(None, Some(0xf0000000..0x100100000)).unwrap();
+        fdt.set_chosen(Some("root=/dev/ram0"), None).unwrap();
+        let fdt = Fdt::parse(&fdt.to_bytes()).unwrap();
+        let chosen = fdt.root.child("chosen").unwrap();
+        assert_eq!(chosen.props.len(), 3);
+        assert_eq!(chosen.prop_str("bootargs"), Some("root=/dev/ram0"));
+        assert_eq!(
+            chosen.prop("linux,initrd-start"),
+            Some(&0xf0000000u64.to_be_bytes()[..])
+        );
+        assert_eq!(
+            chosen.prop("linux,initrd-end"),
+            Some(&0x100100000u64.to_be_bytes()[..])
+        );
+    }
+}
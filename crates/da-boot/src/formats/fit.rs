@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+
+use crate::formats::{
+    Segment,
+    fdt::{Fdt, Node},
+};
+
+/// U-Boot Flattened Image Tree
+///
+/// Only the selected configuration is loaded: kernel, ramdisk and FDT are kept for the
+/// kernel-capable modes, firmware and loadables go to their load addresses.
+pub struct Fit {
+    pub config: String,
+    pub kernel: Option<Vec<u8>>,
+    pub ramdisk: Option<Vec<u8>>,
+    pub fdt: Option<Vec<u8>>,
+    pub entry: Option<u32>,
+    pub segments: Vec<Segment>,
+}
+
+/// Device tree with the `/images` node
+pub fn is_fit(data: &[u8]) -> bool {
+    Fdt::parse(data).is_ok_and(|fdt| fdt.root.child("images").is_some())
+}
+
+/// `load` and `entry` may take one or two cells, only 32-bit addresses make sense here
+fn address(image: &Node, name: &str) -> Option<u32> {
+    let value = image.prop(name)?;
+    let low = value.get(value.len().checked_sub(4)?..)?;
+    Some(u32::from_be_bytes(low.try_into().ok()?))
+}
+
+/// Image content, embedded or placed after the tree
+fn image_data(data: &[u8], image: &Node) -> Result<Vec<u8>> {
+    let name = &image.name;
+    match image.prop_str("compression") {
+        None | Some("none") => (),
+        Some(compression) => anyhow::bail!("Image {name} is compressed ({compression})"),
+    }
+
+    if let Some(content) = image.prop("data") {
+        return Ok(content.to_vec());
+    }
+
+    let size = image
+        .prop_u32("data-size")
+        .with_context(|| format!("Image {name} has no data"))? as usize;
+    let start = if let Some(position) = image.prop_u32("data-position") {
+        position as usize
+    } else {
+        let offset = image
+            .prop_u32("data-offset")
+            .with_context(|| format!("Image {name} has no data"))?;
+        // external data starts right after the tree, aligned to 4
+        let total_size = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        total_size
+            .next_multiple_of(4)
+            .checked_add(offset as usize)
+            .with_context(|| format!("Image {name} data offset overflows"))?
+    };
+
+    start
+        .checked_add(size)
+        .and_then(|end| data.get(start..end))
+        .map(|d| d.to_vec())
+        .with_context(|| format!("FIT is truncated, can't read image {name}"))
+}
+
+impl Fit {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let fdt = Fdt::parse(data).context("Failed to parse FIT")?;
+        let images = fdt.root.child("images").context("FIT has no images")?;
+        let image = |name: &str| -> Result<&Node> {
+            images
+                .child(name)
+                .with_context(|| format!("FIT has no image {name}"))
+        };
+
+        let configs = fdt.root.child("configurations");
+        let config = configs.and_then(|c| {
+            c.prop_str("default")
+                .and_then(|d| c.child(d))
+                .or(c.children.first())
+        });
+
+        // name lists of the kernel, ramdisk, fdt, firmware and loadables
+        let mut selected: [Vec<&Node>; 5] = Default::default();
+        let config_name = if let Some(config) = config {
+            for (i, kind) in ["kernel", "ramdisk", "fdt", "firmware", "loadables"]
+                .into_iter()
+                .enumerate()
+            {
+                let Some(names) = config.prop(kind) else {
+                    continue;
+                };
+                for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+                    selected[i].push(image(&String::from_utf8_lossy(name))?);
+                }
+            }
+            config.name.clone()
+        } else {
+            // no configurations, take the images by their type
+            for node in &images.children {
+                let i = match node.prop_str("type") {
+                    Some("kernel") => 0,
+                    Some("ramdisk") => 1,
+                    Some("flat_dt") => 2,
+                    Some("firmware") => 3,
+                    Some("loadable" | "standalone") => 4,
+                    _ => continue,
+                };
+                selected[i].push(node);
+            }
+            String::new()
+        };
+
+        let [kernel, ramdisk, fdt_images, firmware, loadables] = selected;
+        let first = |images: &[&Node]| -> Result<Option<Vec<u8>>> {
+            images.first().map(|i| image_data(data, i)).transpose()
+        };
+
+        let mut entry = None;
+        let mut segments = Vec::new();
+        for node in firmware.iter().chain(&loadables) {
+            let addr = address(node, "load")
+                .with_context(|| format!("Image {} has no load address", node.name))?;
+            segments.push(Segment {
+                addr,
+                data: image_data(data, node)?,
+            });
+            entry = entry.or(address(node, "entry"));
+        }
+
+        Ok(Self {
+            config: config_name,
+            kernel: first(&kernel)?,
+            ramdisk: first(&ramdisk)?,
+            fdt: first(&fdt_images)?,
+            entry,
+            segments,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fit() -> Fdt {
+        let mut fdt = Fdt {
+            boot_cpuid: 0,
+            reserved: Vec::new(),
+            root: Node::default(),
+        };
+
+        let images = fdt.root.child_or_insert("images");
+        let kernel = images.child_or_insert("kernel-1");
+        kernel.set_prop("type", b"kernel\0".to_vec());
+        kernel.set_prop("data", b"zImage".to_vec());
+        let firmware = images.child_or_insert("firmware-1");
+        firmware.set_prop("type", b"firmware\0".to_vec());
+        firmware.set_prop("data", b"u-boot".to_vec());
+        firmware.set_prop("load", 0x41e00000u32.to_be_bytes().to_vec());
+        firmware.set_prop("entry", [0, 0, 0, 0, 0x41, 0xe0, 0, 0].to_vec());
+
+        fdt
+    }
+
+    #[test]
+    fn by_type() {
+        let fit = Fit::parse(&fit().to_bytes()).unwrap();
+        assert_eq!(fit.config, "");
+        assert_eq!(fit.kernel.as_deref(), Some(&b"zImage"[..]));
+        assert_eq!(fit.entry, Some(0x41e00000));
+        assert_eq!(fit.segments.len(), 1);
+        assert_eq!(fit.segments[0].addr, 0x41e00000);
+        assert_eq!(fit.segments[0].data, b"u-boot");
+    }
+
+    #[test]
+    fn default_config() {
+        let mut fdt = fit();
+        let configs = fdt.root.child_or_insert("configurations");
+        configs.set_prop("default", b"conf-2\0".to_vec());
+        configs
+            .child_or_insert("conf-1")
+            .set_prop("firmware", b"firmware-1\0".to_vec());
+        configs
+            .child_or_insert("conf-2")
+            .set_prop("kernel", b"kernel-1\0".to_vec());
+
+        let fit = Fit::parse(&fdt.to_bytes()).unwrap();
+        assert_eq!(fit.config, "conf-2");
+        assert!(fit.kernel.is_some());
+        assert!(fit.segments.is_empty());
+    }
+
+    #[test]
+    fn external_data() {
+        let mut fdt = fit();
+        let kernel = fdt
+            .root
+            .child_or_insert("images")
+            .child_or_insert("kernel-1");
+        kernel.props.retain(|p| p.name != "data");
+        kernel.set_prop("data-offset", 0u32.to_be_bytes().to_vec());
+        kernel.set_prop("data-size", 6u32.to_be_bytes().to_vec());
+
+        let mut data = fdt.to_bytes();
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend_from_slice(b"zImage");
+        assert_eq!(
+            Fit::parse(&data).unwrap().kernel.as_deref(),
+            Some(&b"zImage"[..])
+        );
+
+        data.pop();
+        assert!(Fit::parse(&data).is_err());
+    }
+
+    #[test]
+    fn huge_data_offset() {
+        for (name, offset) in [("data-offset", 0xfffffffcu32), ("data-position", u32::MAX)] {
+            let mut fdt = fit();
+            let kernel = fdt
+                .root
+                .child_or_insert("images")
+                .child_or_insert("kernel-1");
+            kernel.props.retain(|p| p.name != "data");
+            kernel.set_prop(name, offset.to_be_bytes().to_vec());
+            kernel.set_prop("data-size", u32::MAX.to_be_bytes().to_vec());
+            assert!(Fit::parse(&fdt.to_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_image() {
+        let mut fdt = fit();
+        fdt.root
+            .child_or_insert("configurations")
+            .child_or_insert("conf-1")
+            .set_prop("kernel", b"kernel-2\0".to_vec());
+        assert!(Fit::parse(&fdt.to_bytes()).is_err());
+    }
+}
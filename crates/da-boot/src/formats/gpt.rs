@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use da_protocol::EmmcPart;
+
+use crate::formats::Partition;
+
+pub const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Primary header follows the protective MBR
+pub const GPT_HEADER_LBA: u32 = 1;
+/// Header up to the entries CRC, the rest of the block is reserved
+const HEADER_MIN_SIZE: usize = 92;
+const ENTRY_NAME_OFFSET: usize = 56;
+const ENTRY_MIN_SIZE: usize = 128;
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUID partition table header, only what is needed to read the entries
+pub struct GptHeader {
+    pub entries_lba: u64,
+    pub entries_count: u32,
+    pub entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    pub fn parse(block: &[u8]) -> Result<Self> {
+        if !block.starts_with(GPT_SIGNATURE) {
+            anyhow::bail!("No GPT signature");
+        }
+
+        let size = le32(block.get(..0x10).context("GPT header is truncated")?, 0x0c) as usize;
+        if size < HEADER_MIN_SIZE {
+            anyhow::bail!("GPT header size {size} is too small");
+        }
+        let header = block.get(..size).context("GPT header is truncated")?;
+        let mut zeroed = header.to_vec();
+        zeroed[0x10..0x14].fill(0);
+        if crc32fast::hash(&zeroed) != le32(header, 0x10) {
+            anyhow::bail!("GPT header CRC mismatch");
+        }
+
+        let entry_size = le32(header, 0x54);
+        if (entry_size as usize) < ENTRY_MIN_SIZE {
+            anyhow::bail!("GPT entry size {entry_size} is too small");
+        }
+
+        Ok(Self {
+            entries_lba: le64(header, 0x48),
+            entries_count: le32(header, 0x50),
+            entry_size,
+            entries_crc: le32(header, 0x58),
+        })
+    }
+
+    /// Size of the entry array in bytes
+    pub fn entries_size(&self) -> usize {
+        self.entries_count as usize * self.entry_size as usize
+    }
+
+    /// Parse the entry array, unused entries are skipped
+    pub fn entries(&self, data: &[u8]) -> Result<Vec<Partition>> {
+        let data = data
+            .get(..self.entries_size())
+            .context("GPT entries are truncated")?;
+        if crc32fast::hash(data) != self.entries_crc {
+            anyhow::bail!("GPT entries CRC mismatch");
+        }
+
+        let mut partitions = Vec::new();
+        for entry in data.chunks_exact(self.entry_size as usize) {
+            // zero type GUID marks unused entry
+            if entry[..16].iter().all(|&b| b == 0) {
+                continue;
+            }
+
+            let name: Vec<u16> = entry[ENTRY_NAME_OFFSET..ENTRY_MIN_SIZE]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            let (first, last) = (le64(entry, 32), le64(entry, 40));
+
+            partitions.push(Partition {
+                name: String::from_utf16_lossy(&name),
+                part: EmmcPart::User,
+                start: first,
+                size: (last + 1).saturating_sub(first),
+                attributes: le64(entry, 48),
+            });
+        }
+
+        Ok(partitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, first: u64, last: u64) -> Vec<u8> {
+        let mut entry = vec![0; ENTRY_MIN_SIZE];
+        entry[..16].fill(0xaa);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        entry[48..56].copy_from_slice(&4u64.to_le_bytes());
+        for (i, c) in name.encode_utf16().enumerate() {
+            let offset = ENTRY_NAME_OFFSET + i * 2;
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entry
+    }
+
+    fn header(entries: &[u8], count: u32) -> Vec<u8> {
+        let mut block = vec![0; 512];
+        block[..8].copy_from_slice(GPT_SIGNATURE);
+        block[0x0c..0x10].copy_from_slice(&92u32.to_le_bytes());
+        block[0x48..0x50].copy_from_slice(&2u64.to_le_bytes());
+        block[0x50..0x54].copy_from_slice(&count.to_le_bytes());
+        block[0x54..0x58].copy_from_slice(&(ENTRY_MIN_SIZE as u32).to_le_bytes());
+        block[0x58..0x5c].copy_from_slice(&crc32fast::hash(entries).to_le_bytes());
+        let crc = crc32fast::hash(&block[..92]);
+        block[0x10..0x14].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn parse() {
+        let entries = [
+            entry("boot", 0x800, 0x87ff),
+            vec![0; ENTRY_MIN_SIZE],
+            entry("userdata", 0x8800, 0xffff),
+        ]
+        .concat();
+        let header = GptHeader::parse(&header(&entries, 3)).unwrap();
+        assert_eq!(header.entries_lba, 2);
+        assert_eq!(header.entries_size(), 3 * ENTRY_MIN_SIZE);
+
+        let partitions = header.entries(&entries).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].name, "boot");
+        assert_eq!(partitions[0].part, EmmcPart::User);
+        assert_eq!((partitions[0].start, partitions[0].size), (0x800, 0x8000));
+        assert_eq!(partitions[0].attributes, 4);
+        assert_eq!(partitions[1].name, "userdata");
+    }
+
+    #[test]
+    fn bad_header_size() {
+        let entries = entry("boot", 0x800, 0x87ff);
+        for size in [0u32, 0x10, 91, 513] {
+            let mut block = header(&entries, 1);
+            block[0x0c..0x10].copy_from_slice(&size.to_le_bytes());
+            assert!(GptHeader::parse(&block).is_err());
+        }
+        assert!(GptHeader::parse(GPT_SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn header_crc_mismatch() {
+        let entries = entry("boot", 0x800, 0x87ff);
+        let mut block = header(&entries, 1);
+        block[0x50] = 2;
+        assert!(GptHeader::parse(&block).is_err());
+    }
+
+    #[test]
+    fn entries_crc_mismatch() {
+        let mut entries = entry("boot", 0x800, 0x87ff);
+        let header = GptHeader::parse(&header(&entries, 1)).unwrap();
+        entries[ENTRY_NAME_OFFSET] = b'B';
+        assert!(header.entries(&entries).is_err());
+        assert!(header.entries(&entries[..64]).is_err());
+    }
+}
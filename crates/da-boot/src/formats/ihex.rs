@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+
+use crate::formats::{Segment, hex_bytes, push_data};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Intel HEX image
+pub struct IHex {
+    pub entry: Option<u32>,
+    pub segments: Vec<Segment>,
+}
+
+/// Text starting with the record mark
+pub fn is_ihex(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b":")
+}
+
+impl IHex {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data).context("Intel HEX is not a text")?;
+
+        let mut base = 0u32;
+        let mut entry = None;
+        let mut segments = Vec::new();
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+
+            let record = line
+                .strip_prefix(':')
+                .with_context(|| format!("Line {i} is not a record"))?;
+            let bytes = hex_bytes(record).with_context(|| format!("Invalid record at line {i}"))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                anyhow::bail!("Invalid record length at line {i}");
+            } else if bytes.iter().fold(0u8, |a, &b| a.wrapping_add(b)) != 0 {
+                anyhow::bail!("Checksum mismatch at line {i}");
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let payload = &bytes[4..bytes.len() - 1];
+            let word = |len: usize| -> Result<u32> {
+                let bytes = payload
+                    .get(..len)
+                    .with_context(|| format!("Record at line {i} is too short"))?;
+                Ok(bytes.iter().fold(0, |a, &b| (a << 8) | b as u32))
+            };
+
+            match bytes[3] {
+                DATA => push_data(&mut segments, base.wrapping_add(offset), payload),
+                END_OF_FILE => break,
+                EXTENDED_SEGMENT_ADDRESS => base = word(2)? << 4,
+                EXTENDED_LINEAR_ADDRESS => base = word(2)? << 16,
+                START_SEGMENT_ADDRESS => {
+                    let cs_ip = word(4)?;
+                    entry = Some(((cs_ip >> 16) << 4) + (cs_ip & 0xffff));
+                }
+                START_LINEAR_ADDRESS => entry = Some(word(4)?),
+                kind => anyhow::bail!("Unknown record type {kind:#x} at line {i}"),
+            }
+        }
+
+        if segments.is_empty() {
+            anyhow::bail!("Intel HEX has no data");
+        }
+
+        Ok(Self { entry, segments })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let hex = IHex::parse(
+            b":020000041000EA\n\
+              :0400000001020304F2\n\
+              :020004000506EF\n\
+              :0400000510000101E5\n\
+              :00000001FF\n",
+        )
+        .unwrap();
+
+        assert_eq!(hex.entry, Some(0x10000101));
+        assert_eq!(hex.segments.len(), 1);
+        assert_eq!(hex.segments[0].addr, 0x10000000);
+        assert_eq!(hex.segments[0].data, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn gap_starts_segment() {
+        let hex = IHex::parse(b":0100000011EE\n:0100100022CD\n:00000001FF\n").unwrap();
+        assert_eq!(hex.segments.len(), 2);
+        assert_eq!(hex.segments[1].addr, 0x10);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        assert!(IHex::parse(b":0100000011EF\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn no_data() {
+        assert!(IHex::parse(b":00000001FF\n").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const ENTRIES_OFFSET: usize = 0x1be;
+const ENTRY_SIZE: usize = 16;
+/// CHS and LBA addressed extended partitions
+const EXTENDED_TYPES: [u8; 2] = [0x05, 0x0f];
+
+/// Entry of the MBR or EBR, addresses are in blocks
+pub struct MbrEntry {
+    /// Slot of the entry, 0-3
+    pub slot: usize,
+    pub kind: u8,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl MbrEntry {
+    pub fn is_extended(&self) -> bool {
+        EXTENDED_TYPES.contains(&self.kind)
+    }
+}
+
+/// Used entries of the MBR or EBR sector
+pub fn parse_mbr(block: &[u8]) -> Result<Vec<MbrEntry>> {
+    if block.get(0x1fe..0x200) != Some(&BOOT_SIGNATURE) {
+        anyhow::bail!("No MBR signature");
+    }
+
+    let entries = block[ENTRIES_OFFSET..ENTRIES_OFFSET + 4 * ENTRY_SIZE]
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+        .filter(|(_, e)| e[4] != 0)
+        .map(|(slot, e)| MbrEntry {
+            slot,
+            kind: e[4],
+            start: u32::from_le_bytes(e[8..12].try_into().unwrap()),
+            size: u32::from_le_bytes(e[12..16].try_into().unwrap()),
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(entries: &[(usize, u8, u32, u32)]) -> Vec<u8> {
+        let mut block = vec![0; 512];
+        for &(slot, kind, start, size) in entries {
+            let entry = &mut block[ENTRIES_OFFSET + slot * ENTRY_SIZE..][..ENTRY_SIZE];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+        }
+        block[0x1fe..].copy_from_slice(&BOOT_SIGNATURE);
+        block
+    }
+
+    #[test]
+    fn parse() {
+        let entries = parse_mbr(&block(&[
+            (0, 0x83, 0x800, 0x1000),
+            (2, 0x05, 0x1800, 0x8000),
+        ]))
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].slot, 0);
+        assert_eq!((entries[0].start, entries[0].size), (0x800, 0x1000));
+        assert!(!entries[0].is_extended());
+        assert_eq!(entries[1].slot, 2);
+        assert!(entries[1].is_extended());
+    }
+
+    #[test]
+    fn no_signature() {
+        let mut data = block(&[(0, 0x83, 0x800, 0x1000)]);
+        data[0x1ff] = 0;
+        assert!(parse_mbr(&data).is_err());
+        assert!(parse_mbr(&data[..0x1fe]).is_err());
+    }
+}
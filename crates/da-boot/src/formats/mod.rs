@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use da_protocol::EmmcPart;
+use serde::Serialize;
+
+pub mod atags;
+pub mod bootimg;
+pub mod da;
+pub mod elf;
+pub mod fdt;
+pub mod fit;
+pub mod gpt;
+pub mod ihex;
+pub mod mbr;
+pub mod pmt;
+pub mod srec;
+pub mod uimage;
+
+/// Part of the image loaded to its own address
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// Partition of the boot device, addresses are in blocks
+#[derive(Serialize)]
+pub struct Partition {
+    pub name: String,
+    pub part: EmmcPart,
+    pub start: u64,
+    pub size: u64,
+    /// GPT attributes, PMT flags or MBR type
+    pub attributes: u64,
+}
+
+/// Append `data` at `addr` to the last segment if it's contiguous, start a new one otherwise
+fn push_data(segments: &mut Vec<Segment>, addr: u32, data: &[u8]) {
+    if let Some(last) = segments.last_mut()
+        && last.addr.wrapping_add(last.data.len() as u32) == addr
+    {
+        last.data.extend_from_slice(data);
+    } else {
+        segments.push(Segment {
+            addr,
+            data: data.to_vec(),
+        });
+    }
+}
+
+/// Decode hex digits of the text records
+fn hex_bytes(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits");
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .with_context(|| format!("Invalid hex digits at {i}"))
+        })
+        .collect()
+}
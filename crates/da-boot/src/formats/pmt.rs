@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use da_protocol::{BLOCK_SIZE, EmmcPart};
+
+use crate::formats::Partition;
+
+/// "PTv1" to "PTv3", later versions changed the entry layout
+const PT_SIGNATURES: [u32; 3] = [0x50547631, 0x50547632, 0x50547633];
+const NAME_LEN: usize = 64;
+/// Name, size, offset and flags
+const ENTRY_SIZE: usize = NAME_LEN + 3 * 8;
+/// The table is kept 1 MiB before the end of the user area
+pub const PMT_END_OFFSET: u32 = 0x100000 / BLOCK_SIZE;
+/// Size of the table region in blocks
+pub const PMT_BLOCKS: u32 = 0x1000 / BLOCK_SIZE;
+/// Partitions starting the user area, everything before them is in the boot partition
+const USER_AREA_START: [&str; 2] = ["MBR", "PGPT"];
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// MediaTek partition management table of the older eMMC devices
+///
+/// Offsets are linear, the boot partition comes first and the user area follows it.
+/// `user_blocks` is the size of the user area, it ends the partition with zero size.
+pub fn parse_pmt(data: &[u8], user_blocks: u32) -> Result<Vec<Partition>> {
+    let signature = data
+        .get(..4)
+        .map(|s| u32::from_le_bytes(s.try_into().unwrap()))
+        .context("PMT is truncated")?;
+    if !PT_SIGNATURES.contains(&signature) {
+        anyhow::bail!("No PMT signature");
+    }
+
+    // name, size and linear offset in bytes, flags
+    let mut entries = Vec::new();
+    for entry in data[4..].chunks_exact(ENTRY_SIZE) {
+        let name = &entry[..NAME_LEN];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN)];
+        if name.is_empty() {
+            break;
+        }
+
+        let name = String::from_utf8_lossy(name).into_owned();
+        entries.push((
+            name,
+            le64(entry, NAME_LEN),
+            le64(entry, NAME_LEN + 8),
+            le64(entry, NAME_LEN + 16),
+        ));
+    }
+
+    if entries.is_empty() {
+        anyhow::bail!("PMT is empty");
+    }
+
+    let user_base = entries
+        .iter()
+        .find(|(name, ..)| USER_AREA_START.contains(&name.as_str()))
+        .map_or(0, |(_, _, offset, _)| *offset);
+    let block_size = BLOCK_SIZE as u64;
+
+    let partitions = entries
+        .into_iter()
+        .map(|(name, size, offset, flags)| {
+            let (part, start) = if offset < user_base {
+                (EmmcPart::Boot1, offset / block_size)
+            } else {
+                (EmmcPart::User, (offset - user_base) / block_size)
+            };
+            let size = if size == 0 && part == EmmcPart::User {
+                (user_blocks as u64).saturating_sub(start)
+            } else {
+                size / block_size
+            };
+
+            Partition {
+                name,
+                part,
+                start,
+                size,
+                attributes: flags,
+            }
+        })
+        .collect();
+
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64, offset: u64) -> Vec<u8> {
+        let mut entry = vec![0; ENTRY_SIZE];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        entry[NAME_LEN..NAME_LEN + 8].copy_from_slice(&size.to_le_bytes());
+        entry[NAME_LEN + 8..NAME_LEN + 16].copy_from_slice(&offset.to_le_bytes());
+        entry[NAME_LEN + 16..].copy_from_slice(&1u64.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn parse() {
+        let data = [
+            &PT_SIGNATURES[0].to_le_bytes()[..],
+            &entry("PRELOADER", 0x40000, 0),
+            &entry("MBR", 0x80000, 0x400000),
+            &entry("BOOTIMG", 0x600000, 0x480000),
+            &entry("FAT", 0, 0xa80000),
+            &[0; ENTRY_SIZE],
+            &entry("IGNORED", 0x1000, 0),
+        ]
+        .concat();
+
+        let partitions = parse_pmt(&data, 0x10000).unwrap();
+        assert_eq!(partitions.len(), 4);
+
+        assert_eq!(partitions[0].name, "PRELOADER");
+        assert_eq!(partitions[0].part, EmmcPart::Boot1);
+        assert_eq!((partitions[0].start, partitions[0].size), (0, 0x200));
+
+        assert_eq!(partitions[1].part, EmmcPart::User);
+        assert_eq!(partitions[1].start, 0);
+
+        assert_eq!((partitions[2].start, partitions[2].size), (0x400, 0x3000));
+        assert_eq!(partitions[2].attributes, 1);
+
+        // zero size takes the rest of the user area
+        assert_eq!((partitions[3].start, partitions[3].size), (0x3400, 0xcc00));
+    }
+
+    #[test]
+    fn no_signature() {
+        assert!(parse_pmt(&[0; 4 + ENTRY_SIZE], 0x10000).is_err());
+        assert!(parse_pmt(&[], 0x10000).is_err());
+    }
+
+    #[test]
+    fn empty() {
+        let data = [&PT_SIGNATURES[2].to_le_bytes()[..], &[0; ENTRY_SIZE]].concat();
+        assert!(parse_pmt(&data, 0x10000).is_err());
+    }
+}
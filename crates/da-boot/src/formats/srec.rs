@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+
+use crate::formats::{Segment, hex_bytes, push_data};
+
+/// Motorola S-record image
+pub struct SRec {
+    pub entry: Option<u32>,
+    pub segments: Vec<Segment>,
+}
+
+/// Text starting with the `S0`-`S9` record
+pub fn is_srec(data: &[u8]) -> bool {
+    matches!(data.trim_ascii_start(), [b'S', b'0'..=b'9', ..])
+}
+
+impl SRec {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data).context("S-record is not a text")?;
+
+        let mut entry = None;
+        let mut segments = Vec::new();
+        for (i, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (kind, record) = line
+                .strip_prefix('S')
+                .and_then(|l| l.split_at_checked(1))
+                .with_context(|| format!("Line {i} is not a record"))?;
+            let bytes = hex_bytes(record).with_context(|| format!("Invalid record at line {i}"))?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                anyhow::bail!("Invalid record length at line {i}");
+            } else if bytes.iter().fold(0u8, |a, &b| a.wrapping_add(b)) != 0xff {
+                anyhow::bail!("Checksum mismatch at line {i}");
+            }
+
+            // address width for the data and the start address records
+            let address_len = match kind {
+                "1" | "9" => 2,
+                "2" | "8" => 3,
+                "3" | "7" => 4,
+                // header and record counts
+                "0" | "5" | "6" => continue,
+                _ => anyhow::bail!("Unknown record type S{kind} at line {i}"),
+            };
+
+            let payload = &bytes[1..bytes.len() - 1];
+            if payload.len() < address_len {
+                anyhow::bail!("Record at line {i} is too short");
+            }
+            let addr = payload[..address_len]
+                .iter()
+                .fold(0, |a, &b| (a << 8) | b as u32);
+
+            match kind {
+                "1" | "2" | "3" => push_data(&mut segments, addr, &payload[address_len..]),
+                _ => entry = Some(addr),
+            }
+        }
+
+        if segments.is_empty() {
+            anyhow::bail!("S-record has no data");
+        }
+
+        Ok(Self { entry, segments })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let srec = SRec::parse(
+            b"S00600004844521B\n\
+              S3090010000000010203E0\n\
+              S307001000040506D9\n\
+              S70500100001E9\n",
+        )
+        .unwrap();
+
+        assert_eq!(srec.entry, Some(0x00100001));
+        assert_eq!(srec.segments.len(), 1);
+        assert_eq!(srec.segments[0].addr, 0x00100000);
+        assert_eq!(srec.segments[0].data, [0, 1, 2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn short_address() {
+        let srec = SRec::parse(b"S1051000AABB85\n").unwrap();
+        assert_eq!(srec.segments[0].addr, 0x1000);
+        assert_eq!(srec.entry, None);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        assert!(SRec::parse(b"S1051000AABB86\n").is_err());
+    }
+}
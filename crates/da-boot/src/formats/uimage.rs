@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+
+use crate::formats::Segment;
+
+pub const UIMAGE_MAGIC: u32 = 0x27051956;
+const HEADER_SIZE: usize = 64;
+const IH_ARCH_ARM: u8 = 2;
+const IH_TYPE_MULTI: u8 = 4;
+const IH_COMP_NONE: u8 = 0;
+
+/// Legacy U-Boot image
+pub struct UImage {
+    pub name: String,
+    pub kind: u8,
+    pub entry: u32,
+    pub segment: Segment,
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn is_uimage(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && be32(data, 0) == UIMAGE_MAGIC
+}
+
+impl UImage {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !is_uimage(data) {
+            anyhow::bail!("Not a uImage");
+        }
+
+        // the header CRC is computed with its own field zeroed
+        let mut header = data[..HEADER_SIZE].to_vec();
+        header[4..8].fill(0);
+        if crc32fast::hash(&header) != be32(data, 4) {
+            anyhow::bail!("uImage header CRC mismatch");
+        }
+
+        let size = be32(data, 12) as usize;
+        let load = be32(data, 16);
+        let entry = be32(data, 20);
+        let (arch, kind, comp) = (data[29], data[30], data[31]);
+        let name = &data[32..HEADER_SIZE];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+        if arch != IH_ARCH_ARM {
+            anyhow::bail!("uImage is not for ARM (arch {arch})");
+        } else if kind == IH_TYPE_MULTI {
+            anyhow::bail!("Multi-file uImage is not supported");
+        } else if comp != IH_COMP_NONE {
+            anyhow::bail!("Compressed uImage is not supported (compression {comp})");
+        }
+
+        let payload = data
+            .get(HEADER_SIZE..HEADER_SIZE + size)
+            .context("uImage is truncated")?;
+        if crc32fast::hash(payload) != be32(data, 24) {
+            anyhow::bail!("uImage data CRC mismatch");
+        }
+
+        Ok(Self {
+            name: String::from_utf8_lossy(name).into_owned(),
+            kind,
+            entry,
+            segment: Segment {
+                addr: load,
+                data: payload.to_vec(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..4].copy_from_slice(&UIMAGE_MAGIC.to_be_bytes());
+        data[12..16].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+        data[16..20].copy_from_slice(&0x80008000u32.to_be_bytes());
+        data[20..24].copy_from_slice(&0x80008040u32.to_be_bytes());
+        data[24..28].copy_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        (data[29], data[30], data[31]) = (IH_ARCH_ARM, kind, IH_COMP_NONE);
+        data[32..36].copy_from_slice(b"test");
+
+        let hcrc = crc32fast::hash(&data);
+        data[4..8].copy_from_slice(&hcrc.to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let image = UImage::parse(&image(2, b"kernel")).unwrap();
+        assert_eq!(image.name, "test");
+        assert_eq!(image.entry, 0x80008040);
+        assert_eq!(image.segment.addr, 0x80008000);
+        assert_eq!(image.segment.data, b"kernel");
+    }
+
+    #[test]
+    fn header_crc_mismatch() {
+        let mut data = image(2, b"kernel");
+        data[20] ^= 1;
+        assert!(UImage::parse(&data).is_err());
+    }
+
+    #[test]
+    fn data_crc_mismatch() {
+        let mut data = image(2, b"kernel");
+        data[HEADER_SIZE] ^= 1;
+        assert!(UImage::parse(&data).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = image(2, b"kernel");
+        data.pop();
+        assert!(UImage::parse(&data).is_err());
+    }
+
+    #[test]
+    fn multi_file() {
+        assert!(UImage::parse(&image(IH_TYPE_MULTI, b"kernel")).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use acon::SoC;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    Port,
+    brom_repl::connect,
+    commands::generic::{GetBlVer, GetHwSwVer, GetMeId, GetSocId, GetTargetConfig, GetVersion},
+    err::Error,
+    get_hwcode,
+};
+
+/// `GetBlVer` answer of the BootROM, it echoes the unknown command
+const NO_PRELOADER: u8 = 0xfe;
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Everything the BootROM or the preloader tells about the device
+#[derive(Serialize)]
+pub struct Identity {
+    /// SoC name, `None` if it's unknown to da-boot
+    pub soc: Option<String>,
+    pub hwcode: u16,
+    pub hw_sub_code: u16,
+    pub hw_version: u16,
+    pub sw_version: u16,
+    /// Usbdl version of the BootROM
+    pub brom_version: u8,
+    /// `None` when connected to the BootROM
+    pub preloader_version: Option<u8>,
+    pub sbc: bool,
+    pub sla: bool,
+    pub daa: bool,
+    /// Hex, `None` if the device refused the command
+    pub me_id: Option<String>,
+    /// Hex, `None` on the older SoCs or without ME ID
+    pub soc_id: Option<String>,
+}
+
+impl Identity {
+    /// Query the device with the read-only usbdl commands
+    ///
+    /// The port may be out of sync when ME ID or SoC ID is missing, don't send anything after.
+    pub fn gather(port: &mut Port) -> Result<Self, Error> {
+        let hwcode = get_hwcode(port)?;
+        let mut ver = GetHwSwVer::new();
+        ver.run(port)?;
+
+        let preloader_version = match GetBlVer::new().run_version(port)? {
+            NO_PRELOADER => None,
+            version => Some(version),
+        };
+        let brom_version = GetVersion::new().run_version(port)?;
+
+        let mut config = GetTargetConfig::new();
+        config.run(port)?;
+        let config = config.parse();
+
+        // older BootROMs don't know the ID commands, they're the last ones so the rest of the
+        // report is read already. A refused command may leave its answer behind, nothing else
+        // is sent after it and the caller has to drop the connection.
+        let mut me_id = GetMeId::new();
+        let me_id = me_id.run(port).ok().map(|()| hex(me_id.as_id()));
+        let soc_id = me_id.as_ref().and_then(|_| {
+            let mut soc_id = GetSocId::new();
+            soc_id.run(port).ok().map(|()| hex(soc_id.as_id()))
+        });
+
+        Ok(Self {
+            soc: SoC::try_from_hwcode(hwcode)
+                .context("Unknown SoC")
+                .ok()
+                .map(|soc| format!("{soc:?}")),
+            hwcode,
+            hw_sub_code: ver.hw_sub_code(),
+            hw_version: ver.hw_version(),
+            sw_version: ver.sw_version(),
+            brom_version,
+            preloader_version,
+            sbc: config.sbc,
+            sla: config.sla,
+            daa: config.daa,
+            me_id,
+            soc_id,
+        })
+    }
+
+    pub fn print(&self) {
+        println!(
+            "SoC: {} (hwcode {:#x})",
+            self.soc.as_deref().unwrap_or("unknown"),
+            self.hwcode
+        );
+        println!(
+            "HW sub code: {:#x}, HW version: {:#x}, SW version: {:#x}",
+            self.hw_sub_code, self.hw_version, self.sw_version
+        );
+        println!("BootROM version: {}", self.brom_version);
+        match self.preloader_version {
+            Some(version) => println!("Preloader version: {version}"),
+            None => println!("Preloader version: none, connected to the BootROM"),
+        }
+        println!("SBC: {}, SLA: {}, DAA: {}", self.sbc, self.sla, self.daa);
+        println!(
+            "ME ID: {}",
+            self.me_id.as_deref().unwrap_or("not available")
+        );
+        println!(
+            "SoC ID: {}",
+            self.soc_id.as_deref().unwrap_or("not available")
+        );
+    }
+}
+
+/// Print the identity of the connected device, nothing is changed on it
+pub fn run_identify(json: bool) -> Result<()> {
+    let mut port = connect()?;
+    let identity = Identity::gather(&mut port)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&identity)?);
+    } else {
+        identity.print();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use hacc::{Image, Preloader, TryRead};
+
+use crate::{
+    Cli, LK_BSS_SIZE,
+    file_ext::InputFile,
+    formats::bootimg::{BOOT_MAGIC, BootImg},
+    load_lk, load_preloader, preloader_header_size,
+};
+
+/// Memory the loaded images must not overlap
+struct Reservation {
+    name: String,
+    range: Range<u32>,
+}
+
+fn overlaps(a: &Range<u32>, b: &Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Collect the memory taken by the preloader, LK and input files from the CLI
+fn reservations(cli: &Cli) -> Result<Vec<Reservation>> {
+    let mut reserved = Vec::new();
+
+    if let Some(ref preloader) = cli.preloader {
+        let pl = load_preloader(preloader.clone(), cli.preloader_addr)?;
+        let start = pl.file.upload_address();
+        reserved.push(Reservation {
+            name: "Preloader".into(),
+            range: start..start + pl.file.len() as u32,
+        });
+
+        if let Some(ref lk) = cli.lk {
+            let lk = load_lk(lk.clone(), &pl)?;
+            let start = lk.file.upload_address();
+            reserved.push(Reservation {
+                name: "LK (with BSS)".into(),
+                range: start..start + lk.file.len() as u32 + LK_BSS_SIZE,
+            });
+        }
+    } else if cli.lk.is_some() {
+        println!("LK base is extracted from the preloader, provide it to check LK overlaps");
+    }
+
+    for spec in &cli.input {
+        let input = InputFile::try_from(spec.clone())?;
+        for file in input.files {
+            let start = file.upload_address();
+            reserved.push(Reservation {
+                name: spec.display().to_string(),
+                range: start..start + file.len() as u32,
+            });
+        }
+    }
+
+    Ok(reserved)
+}
+
+/// Print the MTK partition header if the part has one, return the content without it
+fn print_mtk_part<'a>(part: &'a [u8]) -> Option<&'a [u8]> {
+    let mtk = Image::partitions_from_slice(part).next()?;
+    println!(
+        "    MTK header: {} ({} bytes of content)",
+        mtk.header.name(),
+        mtk.content.len()
+    );
+    Some(mtk.content)
+}
+
+fn write_part(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+    let path = dir.join(name);
+    fs::write(&path, data).with_context(|| format!("Can't write {}", path.display()))?;
+    println!("Unpacked {}", path.display());
+    Ok(())
+}
+
+fn inspect_bootimg(data: &[u8], reserved: &[Reservation], unpack: Option<&Path>) -> Result<()> {
+    let image = BootImg::parse(data)?;
+    let header = image.header;
+    let (version, page_size) = (header.header_version, header.page_size);
+
+    println!("Android boot.img v{version}, page size {page_size}");
+    println!("  Board: {:?}", header.board());
+    println!("  Cmdline: {:?}", header.full_cmdline());
+    println!("  Tags: {:#x}", { header.tags_addr });
+
+    let parts = [
+        ("kernel", image.kernel, header.kernel_addr),
+        ("ramdisk", image.ramdisk, header.ramdisk_addr),
+        ("second", image.second, header.second_addr),
+        ("recovery_dtbo", image.recovery_dtbo, 0),
+        ("dtb", image.dtb, header.dtb_addr as u32),
+    ];
+
+    let mut loaded = Vec::new();
+    for (name, content, addr) in parts {
+        if content.is_empty() {
+            continue;
+        }
+
+        println!("  {name}: {} bytes at {addr:#x}", content.len());
+        let inner = print_mtk_part(content);
+
+        if let Some(dir) = unpack {
+            write_part(dir, name, content)?;
+            if let Some(inner) = inner {
+                write_part(dir, &format!("{name}.bin"), inner)?;
+            }
+        }
+
+        // recovery DTBO is never loaded by the bootloader
+        if addr != 0 {
+            loaded.push((name, addr..addr.saturating_add(content.len() as u32)));
+        }
+    }
+
+    if let Some(dir) = unpack {
+        write_part(dir, "cmdline", header.full_cmdline().as_bytes())?;
+    }
+
+    for (i, (name, range)) in loaded.iter().enumerate() {
+        for reservation in reserved {
+            if overlaps(range, &reservation.range) {
+                println!(
+                    "Warning: {name} ({:#x}..{:#x}) overlaps {} ({:#x}..{:#x})",
+                    range.start,
+                    range.end,
+                    reservation.name,
+                    reservation.range.start,
+                    reservation.range.end
+                );
+            }
+        }
+
+        for (other, other_range) in &loaded[i + 1..] {
+            if overlaps(range, other_range) {
+                println!("Warning: {name} overlaps {other}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect_preloader(data: &[u8], unpack: Option<&Path>) -> Result<bool> {
+    let Ok(pl) = Preloader::try_read(&data) else {
+        return Ok(false);
+    };
+
+    let file_info = pl.gfh().file_info();
+    println!("MTK preloader");
+    println!("  Load address: {:#x}", file_info.load_addr());
+    println!(
+        "  Entry: {:#x}",
+        file_info.load_addr() + file_info.jump_offset()
+    );
+    println!("  Content: {} bytes", pl.content().len());
+
+    if let Some(dir) = unpack {
+        let code = &data[preloader_header_size(data)?..];
+        write_part(
+            dir,
+            "preloader.bin",
+            &code[..code.len().min(pl.content().len())],
+        )?;
+    }
+
+    Ok(true)
+}
+
+fn inspect_mtk_image(data: &[u8], unpack: Option<&Path>) -> Result<bool> {
+    let mut found = false;
+    for part in Image::partitions_from_slice(data) {
+        if !found {
+            println!("MTK image");
+            found = true;
+        }
+
+        let name = part.header.name();
+        println!("  {name}: {} bytes", part.content.len());
+        if let Some(dir) = unpack {
+            write_part(dir, &format!("{name}.bin"), part.content)?;
+        }
+    }
+
+    Ok(found)
+}
+
+pub fn run_inspect(cli: &Cli, files: &[PathBuf], unpack: Option<&Path>) -> Result<()> {
+    let reserved = reservations(cli)?;
+
+    for file in files {
+        let data = fs::read(file).with_context(|| format!("Can't read {}", file.display()))?;
+        println!();
+        println!("{} ({} bytes)", file.display(), data.len());
+
+        let dir = if let Some(unpack) = unpack {
+            let stem = file.file_stem().context("File has no name")?;
+            let dir = unpack.join(stem);
+            fs::create_dir_all(&dir).with_context(|| format!("Can't create {}", dir.display()))?;
+            Some(dir)
+        } else {
+            None
+        };
+        let dir = dir.as_deref();
+
+        if data.starts_with(BOOT_MAGIC) {
+            inspect_bootimg(&data, &reserved, dir)?;
+        } else if !inspect_preloader(&data, dir)? && !inspect_mtk_image(&data, dir)? {
+            println!("Unknown format");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,398 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use clap_num::maybe_hex;
+use da_protocol::EmmcPart;
+use kdam::{BarExt, tqdm};
+use simpleport::{SimpleRead, SimpleWrite};
+
+use crate::{
+    Port,
+    boot::preloader::{invalidate_ready, mt6572_preloader_workaround},
+    commands::{
+        da::{DA1Setup, DA2Start, EnableWatchdog, Format, ReadBack, SwitchPart},
+        preloader::{JumpDA, SendDA},
+    },
+    formats::da::{DaEntry, DaFile},
+    get_hwcode, handshake, open_port,
+    session::event,
+    storage::StoragePart,
+};
+
+const ACK: u8 = 0x5a;
+/// Size of the DA2 upload packets
+const DA2_PACKET_SIZE: u32 = 0x1000;
+/// Size of the read back packets
+const READ_PACKET_SIZE: u32 = 0x10000;
+/// eMMC in the storage field of the read back and format commands
+const STORAGE_EMMC: u8 = 0x0c;
+/// Host OS in the read back command, as SP Flash Tool sends it on Linux
+const HOST_LINUX: u8 = 0x2;
+/// Delay before the watchdog reset
+const REBOOT_TIMEOUT_MS: u32 = 1000;
+
+/// Flash info sent by DA2 after it starts, only the eMMC part is kept
+const NOR_INFO_SIZE: usize = 0x1c;
+const NAND_INFO_SIZE: usize = 0x11;
+const NAND_INFO2_SIZE: usize = 0x9;
+const EMMC_INFO_SIZE: usize = 0x5c;
+const SDC_INFO_SIZE: usize = 0x1c;
+const CONFIG_INFO_SIZE: usize = 0x26;
+const PASS_INFO_SIZE: usize = 0xa;
+
+/// Operation done through the SP Flash Tool DA
+#[derive(Clone, PartialEq, Eq, Subcommand)]
+pub enum DaOp {
+    /// Print the DA version and the eMMC layout
+    Info,
+    /// Read `LEN` bytes at `ADDR` of the eMMC partition to a file
+    Read {
+        #[arg(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        #[arg(value_parser=maybe_hex::<u64>)]
+        len: u64,
+        /// eMMC hardware partition
+        #[arg(long, default_value = "user")]
+        part: StoragePart,
+        /// Output file
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Erase `LEN` bytes at `ADDR` of the eMMC user area
+    Format {
+        #[arg(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        #[arg(value_parser=maybe_hex::<u64>)]
+        len: u64,
+    },
+    /// Reset the device with the watchdog
+    Reboot,
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn split(value: u64) -> (u32, u32) {
+    ((value >> 32) as u32, value as u32)
+}
+
+/// eMMC layout reported by DA2, sizes are in bytes
+struct EmmcInfo {
+    ret: u32,
+    boot1: u64,
+    boot2: u64,
+    rpmb: u64,
+    gp: [u64; 4],
+    user: u64,
+    cid: [u8; 16],
+}
+
+impl EmmcInfo {
+    fn parse(data: &[u8]) -> Self {
+        Self {
+            ret: be32(data, 0),
+            boot1: be64(data, 4),
+            boot2: be64(data, 12),
+            rpmb: be64(data, 20),
+            gp: [28, 36, 44, 52].map(|offset| be64(data, offset)),
+            user: be64(data, 60),
+            cid: data[68..84].try_into().unwrap(),
+        }
+    }
+}
+
+/// Device running DA2 of the SP Flash Tool DA
+struct LegacyDa {
+    port: Port,
+    version: (u8, u8),
+    emmc: EmmcInfo,
+}
+
+impl LegacyDa {
+    /// Run DA1 through the BootROM or the preloader, upload DA2 and read the flash info
+    fn start(mut port: Port, da: &DaFile, entry: &DaEntry) -> Result<Self> {
+        run_da1(&mut port, da, entry)?;
+
+        let mut setup = DA1Setup::new();
+        setup.run(&mut port).context("DA1 setup failed")?;
+        let version = (setup.major(), setup.minor());
+        event!("DA1 v{}.{} is running", version.0, version.1);
+        if setup.emmc_ret() != 0 {
+            anyhow::bail!("DA1 found no eMMC: {:#x}", setup.emmc_ret());
+        }
+
+        let da2 = entry.da2()?;
+        let data = da.region_data(da2)?;
+        let len = data
+            .len()
+            .checked_sub(da2.sig_len as usize)
+            .context("DA2 signature is longer than the region")?;
+        let data = &data[..len];
+        event!("Sending DA2 to {:#x}", da2.addr);
+        DA2Start::new(da2.addr, data.len() as u32, DA2_PACKET_SIZE)
+            .run(&mut port)
+            .context("Error on starting DA2 upload")?;
+        for packet in data.chunks(DA2_PACKET_SIZE as usize) {
+            port.write_all(packet)?;
+            if port.read_u8()? != ACK {
+                anyhow::bail!("DA1 rejected a DA2 packet");
+            }
+        }
+        if port.read_u8()? != ACK {
+            anyhow::bail!("DA2 didn't start");
+        }
+
+        let emmc = Self::read_flash_info(&mut port)?;
+        if emmc.ret != 0 {
+            anyhow::bail!("DA2 failed to init the eMMC: {:#x}", emmc.ret);
+        }
+
+        Ok(Self {
+            port,
+            version,
+            emmc,
+        })
+    }
+
+    /// Go through the info of every flash type, the eMMC one is returned
+    fn read_flash_info(port: &mut Port) -> Result<EmmcInfo> {
+        let mut skip = |len: usize| -> Result<Vec<u8>> {
+            let mut buf = vec![0; len];
+            port.read_exact(&mut buf)
+                .context("Error on reading the flash info")?;
+            Ok(buf)
+        };
+
+        skip(NOR_INFO_SIZE)?;
+        let nand = skip(NAND_INFO_SIZE)?;
+        let id_count = u16::from_be_bytes([nand[15], nand[16]]) as usize;
+        skip(id_count * 2)?;
+        skip(NAND_INFO2_SIZE)?;
+        let emmc = EmmcInfo::parse(&skip(EMMC_INFO_SIZE)?);
+        skip(SDC_INFO_SIZE)?;
+        skip(CONFIG_INFO_SIZE)?;
+
+        let pass = skip(PASS_INFO_SIZE)?;
+        if pass[0] != ACK {
+            anyhow::bail!("DA2 reported a flash init error: {:#x}", pass[0]);
+        }
+        // download status of the previous session comes with an extra byte
+        if be32(&pass, 1) == ACK as u32 {
+            skip(1)?;
+        }
+
+        Ok(emmc)
+    }
+
+    fn info(&self) {
+        let emmc = &self.emmc;
+        println!("DA version: {}.{}", self.version.0, self.version.1);
+        println!(
+            "eMMC CID: {}",
+            emmc.cid
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        );
+        println!("boot1: {:#x}", emmc.boot1);
+        println!("boot2: {:#x}", emmc.boot2);
+        println!("rpmb: {:#x}", emmc.rpmb);
+        for (i, size) in emmc.gp.iter().enumerate().filter(|(_, size)| **size != 0) {
+            println!("gp{}: {size:#x}", i + 1);
+        }
+        println!("user: {:#x}", emmc.user);
+    }
+
+    fn part_size(&self, part: EmmcPart) -> u64 {
+        match part {
+            EmmcPart::Boot1 => self.emmc.boot1,
+            EmmcPart::Boot2 => self.emmc.boot2,
+            EmmcPart::User => self.emmc.user,
+        }
+    }
+
+    fn check_range(&self, part: EmmcPart, addr: u64, len: u64) -> Result<()> {
+        let size = self.part_size(part);
+        if len == 0 || addr.checked_add(len).is_none_or(|end| end > size) {
+            anyhow::bail!("{addr:#x}+{len:#x} is out of the {part:?} partition ({size:#x} bytes)");
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, part: EmmcPart, addr: u64, len: u64, path: &Path) -> Result<()> {
+        self.check_range(part, addr, len)?;
+        let port = &mut self.port;
+
+        SwitchPart::new(part as u8)
+            .run(port)
+            .context("Error on switching the partition")?;
+        let (addr_hi, addr_lo) = split(addr);
+        let (len_hi, len_lo) = split(len);
+        ReadBack::new(
+            STORAGE_EMMC,
+            HOST_LINUX,
+            addr_hi,
+            addr_lo,
+            len_hi,
+            len_lo,
+            READ_PACKET_SIZE,
+        )
+        .run(port)
+        .context("Error on starting the read back")?;
+
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let mut pb = tqdm!(
+            total = len as usize,
+            desc = format!("{addr:#x}"),
+            unit = "B"
+        );
+
+        let mut buf = vec![0; READ_PACKET_SIZE as usize];
+        let mut done = 0;
+        while done < len {
+            let packet = &mut buf[..(len - done).min(READ_PACKET_SIZE as u64) as usize];
+            port.read_exact(packet)?;
+            let checksum = port.read_u16_be()?;
+            let expected = packet
+                .iter()
+                .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+            if checksum != expected {
+                anyhow::bail!("Bad checksum of the packet at {:#x}", addr + done);
+            }
+            port.write_u8(ACK)?;
+
+            out.write_all(packet)?;
+            pb.update(packet.len())?;
+            done += packet.len() as u64;
+        }
+        println!();
+        out.flush()?;
+
+        event!("Read {len:#x} bytes to {}", path.display());
+        Ok(())
+    }
+
+    fn format(&mut self, addr: u64, len: u64) -> Result<()> {
+        self.check_range(EmmcPart::User, addr, len)?;
+        let port = &mut self.port;
+
+        let (addr_hi, addr_lo) = split(addr);
+        let (len_hi, len_lo) = split(len);
+        Format::new(STORAGE_EMMC, addr_hi, addr_lo, len_hi, len_lo)
+            .run(port)
+            .context("Error on starting the format")?;
+
+        let mut pb = tqdm!(total = 100, desc = format!("{addr:#x}"), unit = "%");
+        let mut last = 0;
+        loop {
+            if port.read_u8()? != ACK {
+                anyhow::bail!("DA aborted the format");
+            }
+            let status = port.read_u32_be()?;
+            let progress = port.read_u8()?;
+            port.write_u8(ACK)?;
+            if status != 0 {
+                anyhow::bail!("Format failed: {status:#x}");
+            }
+
+            pb.update(progress.saturating_sub(last) as usize)?;
+            last = last.max(progress);
+            if progress >= 100 {
+                break;
+            }
+        }
+        println!();
+
+        event!("Formatted {len:#x} bytes at {addr:#x}");
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<()> {
+        EnableWatchdog::new(REBOOT_TIMEOUT_MS)
+            .run(&mut self.port)
+            .context("Error on enabling the watchdog")?;
+        event!("Device is rebooting");
+        Ok(())
+    }
+}
+
+/// Load the DA file and connect to the BootROM or the preloader, returns the hwcode too
+///
+/// DA1 runs from the SRAM and sets up the DRAM for DA2 by itself, so either works.
+pub(crate) fn connect(da_path: &Path) -> Result<(DaFile, Port, u16)> {
+    let data = fs::read(da_path).with_context(|| format!("Can't read {}", da_path.display()))?;
+    let da = DaFile::parse(data).context("Error on parsing the DA file")?;
+
+    let (device_mode, mut port) = open_port()?;
+    if device_mode.is_preloader() {
+        invalidate_ready(&mut port)?;
+    }
+    handshake(&mut port)?;
+    let mut port = mt6572_preloader_workaround(port)?;
+    let hwcode = get_hwcode(&mut port).context("Error on getting hwcode")?;
+    event!("HW code: {hwcode:#x}");
+
+    Ok((da, port, hwcode))
+}
+
+/// DA for the SoC with `hwcode`
+pub(crate) fn find_entry(da: &DaFile, hwcode: u16) -> Result<&DaEntry> {
+    let entry = da
+        .find(hwcode)
+        .with_context(|| format!("DA file has no DA for hwcode {hwcode:#x}"))?;
+    event!(
+        "Using the DA for hw sub code {:#x}, hw version {:#x}, sw version {:#x}",
+        entry.hw_sub_code,
+        entry.hw_version,
+        entry.sw_version
+    );
+
+    Ok(entry)
+}
+
+/// Send DA1 and jump to it
+pub(crate) fn run_da1(port: &mut Port, da: &DaFile, entry: &DaEntry) -> Result<()> {
+    let da1 = entry.da1()?;
+    event!("Sending DA1 to {:#x}", da1.addr);
+    SendDA::new(da1.addr, da1.len, da1.sig_len, da.region_data(da1)?)
+        .run(port)
+        .context("Error on sending DA1")?;
+    JumpDA::new(da1.addr)
+        .run(port)
+        .context("Error on jumping to DA1")
+}
+
+/// Connect to the device, start the SP Flash Tool DA from `da_path` and run `op`
+pub fn run_legacy_da(da_path: &Path, op: &DaOp) -> Result<()> {
+    let (da, port, hwcode) = connect(da_path)?;
+    let entry = find_entry(&da, hwcode)?;
+    let mut client = LegacyDa::start(port, &da, entry)?;
+
+    match op {
+        DaOp::Info => {
+            client.info();
+            Ok(())
+        }
+        DaOp::Read {
+            addr,
+            len,
+            part,
+            out,
+        } => client.read((*part).into(), *addr, *len, out),
+        DaOp::Format { addr, len } => client.format(*addr, *len),
+        DaOp::Reboot => client.reboot(),
+    }
+}
@@ -1,45 +1,74 @@
 use std::{
     io::{Write, stdout},
     ops::Deref,
+    path::PathBuf,
     thread::sleep,
     time::Duration,
 };
 
-use acon::{MMIO, SoC};
+use acon::{MMIO, Memory, SoC};
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_num::maybe_hex;
-use da_params::PayloadParams;
+use da_params::{PayloadParams, WatchdogPolicy};
 use da_patcher::{Extract, preloader::lk_base::LKBase};
+use da_protocol::{Protocol, WritePolicy, reset::ResetTarget};
 use derive_ctor::ctor;
 use derive_more::IsVariant;
 use hacc::{Image, Preloader, TryRead};
 use kaiko::Analyzer;
 use serialport::{SerialPort, SerialPortInfo, SerialPortType, available_ports};
 use simpleport::{SimpleRead, SimpleWrite};
-use which::which;
 
 type Port = Box<dyn SerialPort>;
 
+/// Space reserved after the LK image for its BSS
+const LK_BSS_SIZE: u32 = 512 * 1024;
+
 use crate::{
     boot::{
         bootrom::run_brom,
         lk_arg::LkBootMode,
         preloader::{invalidate_ready, mt6572_preloader_workaround, run_preloader},
     },
+    brom_repl::{reset_to, run_brom_repl},
     commands::{
         generic::GetHwCode,
         preloader::{JumpDA, Read32, SendDA},
     },
+    dump::RegionSpec,
     err::Error,
-    file_ext::{FileContent, FileContentSpec, UploadFile, UploadFileSpec},
+    file_ext::{
+        FileContent, FileContentSpec, InputFile, RamPartFile, RamPartSpec, UploadFile,
+        UploadFileSpec,
+    },
+    formats::bootimg::{BOOT_MAGIC, BootImg, BootImgLayout},
+    identify::run_identify,
+    inspect::run_inspect,
+    legacy_da::{DaOp, run_legacy_da},
+    security::{CommandSigner, SlaSigner, check_security},
+    session::{FailAction, UartSpec, event},
+    storage::StoragePart,
+    watch::run_watch,
+    xflash::{XFlashOp, run_xflash},
 };
 
 mod boot;
+mod brom_repl;
 mod commands;
+mod dump;
 mod err;
 mod file_ext;
+mod formats;
+mod identify;
+mod inspect;
+mod legacy_da;
 mod repl;
+mod security;
+mod session;
+mod storage;
+mod watch;
+mod xflash;
 
 #[derive(Clone, Default, PartialEq, Eq, IsVariant, Subcommand)]
 enum BootMode {
@@ -60,6 +89,235 @@ enum BootMode {
     LK,
     /// Stay in the payload in the REPL mode
     REPL,
+    /// Resume the stock boot flow: BootROM -> Preloader -> payload -> Preloader -> LK
+    ///
+    /// Installed hooks stay active. If LK is provided, the preloader loads it instead of the eMMC one
+    Continue,
+    /// Boot Linux kernel right from the payload: BootROM -> Preloader -> payload -> kernel
+    ///
+    /// for mainline kernel bring-up. DTB or ATAGs get the DRAM layout, command line and initrd
+    Kernel,
+    /// Stay in the payload and stream memory regions to files: BootROM -> Preloader -> payload
+    ///
+    /// Regions are presets from the SoC memory map, `START..END` or `START+SIZE`
+    Dump {
+        /// Regions to dump
+        #[arg(required = true)]
+        regions: Vec<RegionSpec>,
+
+        /// Output directory, gets a file per region and the manifest
+        #[arg(short, long, default_value = "dump")]
+        out: PathBuf,
+
+        /// Continue the regions listed in the manifest, e.g. after a dropped connection
+        #[arg(long)]
+        resume: bool,
+
+        /// Leave out the chunks filled with 0x00 or 0xff, the manifest lists them
+        #[arg(long)]
+        sparse: bool,
+    },
+    /// Read eMMC blocks through the preloader storage driver: BootROM -> Preloader -> payload
+    ReadLba {
+        /// First block
+        #[arg(value_parser=maybe_hex::<u32>)]
+        lba: u32,
+
+        /// Number of blocks
+        #[arg(value_parser=maybe_hex::<u32>)]
+        count: u32,
+
+        /// eMMC hardware partition
+        #[arg(long, default_value = "user")]
+        part: StoragePart,
+
+        /// Output file, its SHA-1 is written next to it
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Read a partition from the partition table by its name: BootROM -> Preloader -> payload
+    ReadPartition {
+        /// Partition name
+        name: String,
+
+        /// Output file, `NAME.img` if not given
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Write an image to a partition through the preloader storage driver: BootROM -> Preloader -> payload
+    ///
+    /// The original data is backed up first and the written data is verified by reading it back.
+    WritePartition {
+        /// Partition name
+        name: String,
+
+        /// Image to write, a whole number of blocks not bigger than the partition
+        image: PathBuf,
+
+        /// Backup of the overwritten range, `NAME-backup-TIME.img` if not given
+        #[arg(long)]
+        backup: Option<PathBuf>,
+
+        /// Write the preloader and the boot partitions too
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the partitions from GPT, PMT or MBR: BootROM -> Preloader -> payload
+    ///
+    /// With LK the partition names are looked up in LK, the start and size aren't compared.
+    Partitions {
+        /// Export the partition table to a JSON file
+        #[arg(long)]
+        json: Option<PathBuf>,
+    },
+}
+
+/// Commands working without the payload, they never reach the boot flow
+#[derive(Subcommand)]
+enum Tool {
+    /// Print what's inside boot.img, MTK images, preloader or LK without connecting the device
+    ///
+    /// The preloader, LK and input files from the main arguments are used to check the load
+    /// addresses against them.
+    Inspect {
+        /// Files to inspect
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Unpack the parts to this directory
+        #[arg(long)]
+        unpack: Option<PathBuf>,
+    },
+    /// Print hwcode, versions, security flags, ME ID and SoC ID, the device is left untouched
+    Identify {
+        /// Print JSON instead of the report
+        #[arg(long)]
+        json: bool,
+    },
+    /// Talk to the BootROM or the preloader with the usbdl commands, nothing is uploaded
+    ///
+    /// for devices where the payload can't run yet
+    BromRepl,
+    /// Work with the storage through the SP Flash Tool DA: BootROM or Preloader -> DA1 -> DA2
+    ///
+    /// For devices the payload doesn't support yet, only the DA file is needed.
+    LegacyDa {
+        /// DA file of SP Flash Tool, e.g. `MTK_AllInOne_DA.bin`
+        #[arg(long)]
+        da: PathBuf,
+
+        #[command(subcommand)]
+        op: DaOp,
+    },
+    /// Work with the storage through the XFlash DA (DA v5) of newer SoCs: BootROM or Preloader -> DA1 -> DA2
+    ///
+    /// The storage is found by the DA, eMMC and UFS are supported. Only the DA file is needed.
+    #[command(name = "xflash")]
+    XFlash {
+        /// DA file of SP Flash Tool with the XFlash DAs
+        #[arg(long)]
+        da: PathBuf,
+
+        #[command(subcommand)]
+        op: XFlashOp,
+    },
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    #[command(flatten)]
+    Boot(BootMode),
+    #[command(flatten)]
+    Tool(Tool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LkWritePolicy {
+    /// Drop the writes, LK sees them as succeed
+    Discard,
+    /// Keep the writes in the RAM, later reads see them
+    Overlay,
+    /// Log the writes to UART and pass them to the eMMC
+    Log,
+}
+
+impl From<LkWritePolicy> for WritePolicy {
+    fn from(value: LkWritePolicy) -> Self {
+        match value {
+            LkWritePolicy::Discard => Self::Discard,
+            LkWritePolicy::Overlay => Self::Overlay,
+            LkWritePolicy::Log => Self::Log,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Watchdog {
+    /// Leave the watchdog as the preloader or the BootROM set it
+    Keep,
+    /// Restart the watchdog while waiting for the host
+    Kick,
+    /// Turn the watchdog off until the final jump
+    Disable,
+}
+
+impl From<Watchdog> for WatchdogPolicy {
+    fn from(value: Watchdog) -> Self {
+        match value {
+            Watchdog::Keep => Self::Keep,
+            Watchdog::Kick => Self::Kick,
+            Watchdog::Disable => Self::Disable,
+        }
+    }
+}
+
+impl BootMode {
+    /// Boot modes and the REPL only wait for the host, a driver call of the dump and storage
+    /// modes can outlast the watchdog timeout
+    fn default_watchdog(&self) -> WatchdogPolicy {
+        match self {
+            Self::Dump { .. }
+            | Self::ReadLba { .. }
+            | Self::ReadPartition { .. }
+            | Self::WritePartition { .. }
+            | Self::Partitions { .. } => WatchdogPolicy::Disable,
+            _ => WatchdogPolicy::Kick,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ResetMode {
+    /// Stock boot flow
+    Normal,
+    /// BootROM download mode
+    Brom,
+    /// Preloader download mode
+    Download,
+    /// Recovery
+    Recovery,
+    /// Fastboot
+    Fastboot,
+}
+
+impl From<ResetMode> for ResetTarget {
+    fn from(value: ResetMode) -> Self {
+        match value {
+            ResetMode::Normal => Self::Normal,
+            ResetMode::Brom => Self::Brom,
+            ResetMode::Download => Self::Download,
+            ResetMode::Recovery => Self::Recovery,
+            ResetMode::Fastboot => Self::Fastboot,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CrashMethod {
+    /// Read the address 0, the preloader faults and falls back to the BootROM
+    Fault,
+    /// Set the USBDL flag and reset, for the preloaders handling the fault
+    Reset,
 }
 
 #[derive(Parser)]
@@ -69,14 +327,56 @@ struct Cli {
     #[arg(short, long)]
     crash: bool,
 
-    /// Preloader path
+    /// How the preloader gets to the BootROM with `--crash`
+    #[arg(long, default_value = "fault", requires = "crash")]
+    crash_method: CrashMethod,
+
+    /// Watch the files after the boot and boot them again on change
+    ///
+    /// The device is reset through the payload if it still runs (REPL left with Ctrl-C, dump and
+    /// storage modes), the modes handing over to the next stage need a manual reset
+    #[arg(short, long)]
+    watch: bool,
+
+    /// What the payload does with the watchdog, `disable` for the dump and storage modes and
+    /// `kick` otherwise
+    ///
+    /// The state the payload started with is restored before the final jump
+    #[arg(long)]
+    watchdog: Option<Watchdog>,
+
+    /// Capture the UART log into the session log, e.g. `/dev/ttyUSB0:115200` (921600 if not given)
+    #[arg(long, value_name = "PATH[:BAUD]")]
+    uart: Option<UartSpec>,
+
+    /// Session log with the UART lines and the da-boot events, both timestamped
+    #[arg(long, default_value = "session.log", requires = "uart")]
+    session_log: PathBuf,
+
+    /// UART lines containing this mark a failure
+    #[arg(long, default_values = ["HALTED:", "Panic :("], requires = "uart")]
+    uart_fail_pattern: Vec<String>,
+
+    /// What to do on a failure seen on the UART
+    #[arg(long, default_value = "flag", requires = "uart")]
+    uart_on_fail: FailAction,
+
+    /// Preloader path (not needed for inspect, identify, brom-repl and the DA modes)
     #[arg(short, long)]
-    preloader: FileContentSpec,
+    preloader: Option<FileContentSpec>,
 
     /// Manually specify Preloader address (used only if header autodetection fails)
     #[arg(long)]
     preloader_addr: Option<u32>,
 
+    /// Program answering the SLA challenge of your device: gets it on stdin, prints the signature
+    #[arg(long)]
+    sla_signer: Option<PathBuf>,
+
+    /// Key file passed to the SLA signer as its argument
+    #[arg(long, requires = "sla_signer")]
+    sla_key: Option<PathBuf>,
+
     /// LK path
     #[arg(short, long)]
     lk: Option<FileContentSpec>,
@@ -85,10 +385,28 @@ struct Cli {
     #[arg(long)]
     lk_addr: Option<u32>,
 
+    /// Let the preloader load LK through its own boot flow instead of jumping to it (preloader mode)
+    ///
+    /// The preloader fills the boot argument and does the security setup by itself
+    #[arg(long, requires = "lk")]
+    lk_via_preloader: bool,
+
     /// LK boot mode (used only if mode is preloader or lk)
     #[arg(short, long)]
     lk_mode: Option<LkBootMode>,
 
+    /// What to do with the eMMC writes done by LK (LK mode). Writes are untouched if not set
+    #[arg(long)]
+    lk_write_policy: Option<LkWritePolicy>,
+
+    /// RAM overlay size for the overlay write policy
+    #[arg(long, default_value = "0x400000", value_parser=maybe_hex::<u32>)]
+    lk_overlay_size: u32,
+
+    /// Print the LK log on the screen too, for boards without UART (LK mode)
+    #[arg(long)]
+    lk_screen_log: bool,
+
     /// DRAM size per rank
     #[arg(long, value_parser=maybe_hex::<u32>)]
     dram_size_per_rank: Option<u32>,
@@ -105,16 +423,95 @@ struct Cli {
     #[arg(short, long, requires = "kernel")]
     ramdisk: Option<FileContentSpec>,
 
-    /// Binaries to upload
+    /// Second stage bootloader path for the generated boot.img
+    #[arg(long, requires = "kernel")]
+    second: Option<FileContentSpec>,
+
+    /// Device tree blob, placed according to the boot.img header version
+    ///
+    /// Header v2 stores it in the DTB field, older versions get it appended to the kernel
+    #[arg(long, requires = "kernel")]
+    dtb: Option<FileContentSpec>,
+
+    /// Patch /memory and /chosen (bootargs, initrd) of the DTB from the DRAM layout
+    #[arg(long, requires = "dtb")]
+    dtb_patch: bool,
+
+    /// Kernel command line for the generated boot.img or the kernel mode
+    ///
+    /// Replaces the command line of a prepared boot.img in the LK mode
+    #[arg(long)]
+    cmdline: Option<String>,
+
+    /// Arguments appended to the kernel command line, e.g. `androidboot.selinux=permissive`
+    #[arg(long)]
+    cmdline_append: Option<String>,
+
+    /// Pass the command line through the LK `boot_linux` hook (LK mode)
+    ///
+    /// For LK adding its own arguments, the boot.img header is left untouched then
+    #[arg(long)]
+    lk_cmdline_hook: bool,
+
+    /// Machine ID for the kernel mode, required with ATAGs (defaults to 0xffffffff with DTB)
+    #[arg(long, value_parser=maybe_hex::<u32>)]
+    machine_id: Option<u32>,
+
+    /// Product name for the generated boot.img
+    #[arg(long, default_value = "")]
+    board: String,
+
+    /// boot.img page size
+    #[arg(long, default_value = "2048", value_parser=maybe_hex::<u32>)]
+    pagesize: u32,
+
+    /// boot.img header version (0-2)
+    #[arg(long, default_value = "0")]
+    header_version: u32,
+
+    /// boot.img base address
+    #[arg(long, default_value = "0x40000000", value_parser=maybe_hex::<u32>)]
+    base: u32,
+
+    /// Kernel offset from the boot.img base
+    #[arg(long, default_value = "0x8000", value_parser=maybe_hex::<u32>)]
+    kernel_offset: u32,
+
+    /// Ramdisk offset from the boot.img base
+    #[arg(long, default_value = "0x4000000", value_parser=maybe_hex::<u32>)]
+    ramdisk_offset: u32,
+
+    /// Second stage bootloader offset from the boot.img base
+    #[arg(long, default_value = "0xf00000", value_parser=maybe_hex::<u32>)]
+    second_offset: u32,
+
+    /// Kernel tags offset from the boot.img base
+    #[arg(long, default_value = "0x100", value_parser=maybe_hex::<u32>)]
+    tags_offset: u32,
+
+    /// DTB offset from the boot.img base (header v2)
+    #[arg(long, default_value = "0x1f00000", value_parser=maybe_hex::<u32>)]
+    dtb_offset: u32,
+
+    /// Serve partition from the RAM in the LK mode, e.g. `recovery=recovery.img` or `logo=logo.bin`
+    ///
+    /// The name must match the one used by LK (`BOOTIMG`/`RECOVERY` on older SoCs, `boot`/`recovery` on newer)
+    #[arg(long, value_name = "NAME=PATH")]
+    ram_part: Vec<RamPartSpec>,
+
+    /// Binaries to upload, `PATH@ADDRESS` for raw binaries
+    ///
+    /// ELF, uImage, FIT, Intel HEX and S-record carry their own load addresses, just `PATH`.
+    /// FIT may also provide the kernel, ramdisk and DTB
     #[arg(short, long, num_args = 1..)]
     input: Vec<UploadFileSpec>,
 
-    /// Final jump address if booting binary from the `input`
+    /// Final jump address if booting binary from the `input`, defaults to the ELF entry
     #[arg(short, long, value_parser=maybe_hex::<u32>)]
     jump_address: Option<u32>,
 
     #[command(subcommand)]
-    mode: BootMode,
+    mode: Mode,
 }
 
 #[derive(Debug, Copy, Clone, IsVariant)]
@@ -129,6 +526,42 @@ struct FileAndAnalyzer {
     analyzer: Analyzer,
 }
 
+/// boot.img options from the CLI
+struct BootImgOptions {
+    layout: BootImgLayout,
+    cmdline: Option<String>,
+    cmdline_append: Option<String>,
+    cmdline_hook: bool,
+    board: String,
+    patch_dtb: bool,
+}
+
+impl BootImgOptions {
+    /// Command line built on top of `base`: replaced by `--cmdline`, extended by `--cmdline-append`
+    fn full_cmdline(&self, base: &str) -> String {
+        let mut cmdline = self.cmdline.as_deref().unwrap_or(base).to_string();
+        if let Some(ref append) = self.cmdline_append {
+            if !cmdline.is_empty() {
+                cmdline.push(' ');
+            }
+            cmdline.push_str(append);
+        }
+
+        cmdline
+    }
+
+    /// Command line for the boot.img header, `None` if the `base` stays
+    ///
+    /// With the LK hook the header is not touched, the hook passes our arguments instead.
+    fn header_cmdline(&self, base: &str) -> Option<String> {
+        if self.cmdline_hook || (self.cmdline.is_none() && self.cmdline_append.is_none()) {
+            None
+        } else {
+            Some(self.full_cmdline(base))
+        }
+    }
+}
+
 #[derive(ctor)]
 struct LKState {
     file_and_analyzer: FileAndAnalyzer,
@@ -147,6 +580,10 @@ struct State {
 
     mode: BootMode,
     lk_mode: LkBootMode,
+    lk_write_policy: Option<WritePolicy>,
+    lk_overlay_size: u32,
+    lk_screen_log: bool,
+    lk_via_preloader: bool,
 
     dram_size_per_rank: u32,
     dram_ranks: u32,
@@ -159,12 +596,20 @@ struct State {
     // XXX: this may be not true on newer SoCs
     kernel: Option<FileContent>,
     ramdisk: Option<FileContent>,
+    second: Option<FileContent>,
+    dtb: Option<FileContent>,
+    machine_id: Option<u32>,
+    bootimg: BootImgOptions,
+    ram_parts: Vec<RamPartFile>,
     // jump address provided by the CLI.
     //
     // unused if not booting image from `upload`
     jump_addr: u32,
 
     params: PayloadParams,
+    /// Payload session kept by the modes staying in the payload
+    session: Option<Protocol<Port>>,
+    sla_signer: Option<Box<dyn SlaSigner>>,
 }
 
 fn get_ports() -> Result<impl Iterator<Item = (DeviceMode, SerialPortInfo)>> {
@@ -203,7 +648,7 @@ fn open_port() -> Result<(DeviceMode, Port)> {
         sleep(Duration::from_millis(500));
     };
 
-    println!("Found device at {}", &port.port_name);
+    event!("Found device at {}", &port.port_name);
     Ok((
         mode,
         serialport::new(port.port_name, 921600)
@@ -238,16 +683,22 @@ fn get_hwcode(port: &mut Port) -> Result<u16, Error> {
 }
 
 fn run_payload(addr: u32, payload: &[u8], port: &mut Port) -> Result<()> {
-    println!("Sending to {addr:#x}");
+    event!("Sending payload to {addr:#x}");
     SendDA::new(addr, payload.len() as u32, 0, &payload)
         .run(port)
         .context("Error on sending payload")?;
-    println!("Jump to {addr:#x}");
+    event!("Jump to payload at {addr:#x}");
     JumpDA::new(addr)
         .run(port)
         .context("Error on jumping to the payload")
 }
 
+impl Cli {
+    fn crash_method(&self) -> Option<CrashMethod> {
+        self.crash.then_some(self.crash_method)
+    }
+}
+
 fn crash_to_brom(port: &mut Port) -> Result<()> {
     match Read32::new(0x0, 1).run(port) {
         Err(Error::Io(_)) => Ok(()),
@@ -255,7 +706,17 @@ fn crash_to_brom(port: &mut Port) -> Result<()> {
     }
 }
 
-fn run(mut state: State, crash: bool) -> Result<()> {
+/// Reboot the preloader into the BootROM through the USBDL flag, no fault needed
+fn reset_to_brom(port: &mut Port, soc: SoC) -> Result<()> {
+    if !ResetTarget::Brom.is_supported(soc) {
+        anyhow::bail!("USBDL flag address is unknown for this SoC, use the fault crash method");
+    }
+
+    reset_to(port, soc, ResetTarget::Brom)?;
+    Ok(())
+}
+
+fn run(state: &mut State, crash: Option<CrashMethod>) -> Result<()> {
     let (device_mode, mut port) = open_port()?;
 
     if device_mode.is_preloader() {
@@ -266,59 +727,72 @@ fn run(mut state: State, crash: bool) -> Result<()> {
 
     let mut port = mt6572_preloader_workaround(port)?;
     let hwcode = get_hwcode(&mut port).context("Error on getting hwcode")?;
-    println!("HW code: {hwcode:#x}");
+    event!("HW code: {hwcode:#x}");
 
     let soc = SoC::try_from_hwcode(hwcode).context("Sorry, your SoC is not supported yet")?;
     state.soc = soc;
     state.params.soc = soc;
 
+    check_security(state, &mut port, device_mode)?;
+
     match device_mode {
-        DeviceMode::Brom => run_brom(&mut state, port, device_mode).context("Error on BootROM run"),
+        DeviceMode::Brom => run_brom(state, port, device_mode).context("Error on BootROM run"),
         DeviceMode::Preloader => {
-            if crash {
-                crash_to_brom(&mut port).context("Error on crashing to BootROM")?;
+            if let Some(method) = crash {
+                match method {
+                    CrashMethod::Fault => {
+                        crash_to_brom(&mut port).context("Error on crashing to BootROM")?
+                    }
+                    CrashMethod::Reset => {
+                        reset_to_brom(&mut port, soc).context("Error on resetting to BootROM")?
+                    }
+                }
                 drop(port);
                 sleep(Duration::from_millis(100));
                 println!();
 
                 let (device_mode, mut port) = open_port()?;
                 handshake(&mut port)?;
-                run_brom(&mut state, port, device_mode)
+                check_security(state, &mut port, device_mode)?;
+                run_brom(state, port, device_mode)
             } else {
-                run_preloader(&mut state, port, device_mode).context("Error on Preloader run")
+                run_preloader(state, port, device_mode).context("Error on Preloader run")
             }
         }
     }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let mut params = PayloadParams::default();
+/// Size of the MTK headers before the preloader code
+fn preloader_header_size(data: &[u8]) -> Result<usize> {
+    // wow. mtk bullshit is everywhere.
+    if data.starts_with(b"EMMC_BOOT") {
+        Ok(0xb00)
+    } else if data.starts_with(b"MMM") {
+        Ok(0x300)
+    } else {
+        anyhow::bail!("Junk preloader");
+    }
+}
 
-    if !cli.preloader.is_file() {
+/// Load preloader, stripping the MTK headers if there are any
+fn load_preloader(path: FileContentSpec, addr: Option<u32>) -> Result<FileAndAnalyzer> {
+    if !path.is_file() {
         anyhow::bail!("Preloader file doesn't exist");
     }
 
-    let preloader_file = FileContent::try_from(cli.preloader).context("Can't read preloader")?;
+    let preloader_file = FileContent::try_from(path).context("Can't read preloader")?;
     let (pl, pl_base) = if let Ok(pl) = Preloader::try_read(&preloader_file) {
         let pl_jump = pl.gfh().file_info().load_addr() + pl.gfh().file_info().jump_offset();
 
-        // wow. mtk bullshit is everywhere.
         let max = pl.content().len();
+        let header_size = preloader_header_size(&preloader_file)?;
         let mut content = preloader_file.into_vec();
-        if content.starts_with(b"EMMC_BOOT") {
-            content.drain(0..0xb00);
-        } else if content.starts_with(b"MMM") {
-            content.drain(0..0x300);
-        } else {
-            anyhow::bail!("Junk preloader");
-        }
-
+        content.drain(0..header_size);
         content.resize(max, 0);
 
         (FileContent::from(content), pl_jump)
     } else {
-        if let Some(addr) = cli.preloader_addr {
+        if let Some(addr) = addr {
             println!("Failed to parse preloader, assuming raw file");
             (preloader_file, addr)
         } else {
@@ -336,70 +810,163 @@ fn main() -> Result<()> {
         kaiko::cpu_mode::CpuMode::Arm,
     )
     .context("Failed to analyze preloader")?;
-    let pl = FileAndAnalyzer::new(pl_data, pl_analyzer);
 
-    let lk = if let Some(lk) = cli.lk {
-        let content = FileContent::try_from(lk).context("Can't read LK")?;
-        let image = Image::new(&content);
+    Ok(FileAndAnalyzer::new(pl_data, pl_analyzer))
+}
+
+/// Load LK, the base address is extracted from the preloader
+fn load_lk(path: FileContentSpec, pl: &FileAndAnalyzer) -> Result<FileAndAnalyzer> {
+    let content = FileContent::try_from(path).context("Can't read LK")?;
+    let image = Image::new(&content);
 
-        let content = if let Some(part) = image.partitions().next() {
-            println!("Loaded {} partition", part.header.name());
-            FileContent::from(part.content.to_vec())
-        } else {
-            println!("Failed to parse LK, assuming raw file");
-            content
-        };
+    let content = if let Some(part) = image.partitions().next() {
+        println!("Loaded {} partition", part.header.name());
+        FileContent::from(part.content.to_vec())
+    } else {
+        println!("Failed to parse LK, assuming raw file");
+        content
+    };
+
+    let lk_base = LKBase::new(&pl.analyzer)
+        .extract()
+        .context("Failed to extract LK data")?;
 
-        let lk_base = LKBase::new(&pl.analyzer)
-            .extract()
-            .context("Failed to extract LK data")?;
+    println!("Loaded LK ({} bytes, base: {lk_base:#x})", content.len());
 
-        println!("Loaded LK ({} bytes, base: {lk_base:#x})", content.len());
+    let analyzer = Analyzer::try_new(
+        content.as_vec().clone().into_boxed_slice(),
+        lk_base,
+        0,
+        kaiko::cpu_mode::CpuMode::Arm,
+    )
+    .context("Failed to analyze LK")?;
+    let file = UploadFile::from_content(content, lk_base);
+
+    Ok(FileAndAnalyzer::new(file, analyzer))
+}
+
+/// Load preloader and LK, reserving the LK BSS in `params`
+///
+/// The analysis is costly, the watch mode does it again only when these files change.
+fn load_bootloaders(
+    cli: &Cli,
+    params: &mut PayloadParams,
+) -> Result<(FileAndAnalyzer, Option<LKState>)> {
+    let Some(ref preloader) = cli.preloader else {
+        anyhow::bail!("Preloader is required, please provide it with --preloader");
+    };
+
+    let pl = load_preloader(preloader.clone(), cli.preloader_addr)?;
+
+    let lk = if let Some(ref lk) = cli.lk {
+        let lk = load_lk(lk.clone(), &pl)?;
 
         // better safe than sorry
-        let bss = lk_base + content.len() as u32;
-        if params.blacklist_reloc(bss..bss + (512 * 1024)).is_err() {
+        let bss = lk.file.upload_address() + lk.file.len() as u32;
+        if params.blacklist_reloc(bss..bss + LK_BSS_SIZE).is_err() {
             anyhow::bail!("Failed to blacklist LK BSS range");
         }
 
-        let analyzer = Analyzer::try_new(
-            content.as_vec().clone().into_boxed_slice(),
-            lk_base,
-            0,
-            kaiko::cpu_mode::CpuMode::Arm,
-        )
-        .context("Failed to analyze LK")?;
-        let file = UploadFile::from_content(content, lk_base);
-
-        let f_and_a = FileAndAnalyzer::new(file, analyzer);
-        Some(LKState::new(f_and_a))
+        Some(LKState::new(lk))
+    } else {
+        None
+    };
+
+    Ok((pl, lk))
+}
+
+/// Files to boot, read again on every watch round
+struct Plan {
+    upload: Vec<UploadFile>,
+    kernel: Option<FileContent>,
+    ramdisk: Option<FileContent>,
+    second: Option<FileContent>,
+    dtb: Option<FileContent>,
+    ram_parts: Vec<RamPartFile>,
+    jump_addr: u32,
+}
+
+/// Read the files to boot and check them against the boot mode
+fn load_plan(cli: &Cli, mode: &BootMode, has_lk: bool, bootimg: &BootImgOptions) -> Result<Plan> {
+    let mut kernel = if let Some(ref kernel) = cli.kernel {
+        Some(FileContent::try_from(kernel.clone()).context("Can't read kernel")?)
     } else {
         None
     };
 
-    let kernel = if let Some(kernel) = cli.kernel {
-        Some(FileContent::try_from(kernel).context("Can't read kernel")?)
+    let mut ramdisk = if let Some(ref ramdisk) = cli.ramdisk {
+        Some(FileContent::try_from(ramdisk.clone()).context("Can't read ramdisk")?)
     } else {
         None
     };
 
-    let ramdisk = if let Some(ramdisk) = cli.ramdisk {
-        Some(FileContent::try_from(ramdisk).context("Can't read ramdisk")?)
+    let second = if let Some(ref second) = cli.second {
+        Some(FileContent::try_from(second.clone()).context("Can't read second stage bootloader")?)
     } else {
         None
     };
 
-    let input = cli
-        .input
-        .into_iter()
-        .map(|f| UploadFile::try_from(f))
+    let mut dtb = if let Some(ref dtb) = cli.dtb {
+        Some(FileContent::try_from(dtb.clone()).context("Can't read DTB")?)
+    } else {
+        None
+    };
+
+    let ram_parts = cli
+        .ram_part
+        .iter()
+        .map(|p| RamPartFile::try_from(p.clone()))
         .collect::<Result<Vec<_>, std::io::Error>>()
-        .context("Can't read file")?;
+        .context("Can't read RAM partition")?;
+
+    let inputs = cli
+        .input
+        .iter()
+        .map(|f| InputFile::try_from(f.clone()))
+        .collect::<Result<Vec<_>>>()?;
+
+    // the first entry is used unless the jump address is set explicitly
+    let entry = inputs.iter().find_map(|i| i.entry);
+    let jump_address = cli.jump_address.or(entry);
+
+    let mut input = Vec::new();
+    for file in inputs {
+        // FIT may carry the whole kernel set
+        for (part, from_fit, name) in [
+            (&mut kernel, file.kernel, "kernel"),
+            (&mut ramdisk, file.ramdisk, "ramdisk"),
+            (&mut dtb, file.dtb, "DTB"),
+        ] {
+            if let Some(content) = from_fit {
+                if part.is_some() {
+                    anyhow::bail!("FIT provides {name}, but it's already given");
+                }
+                println!("Using {name} from FIT ({} bytes)", content.len());
+                *part = Some(content);
+            }
+        }
+
+        input.extend(file.files);
+    }
 
     let has_at_least_one_file = !input.is_empty();
     let has_jump = cli.jump_address.is_some();
     let has_kernel = kernel.is_some();
-    match cli.mode {
+    if !ram_parts.is_empty() && !matches!(mode, BootMode::LK) {
+        anyhow::bail!("RAM partitions are served only in the LK mode");
+    } else if cli.lk_write_policy.is_some() && !matches!(mode, BootMode::LK) {
+        anyhow::bail!("LK write policy is applied only in the LK mode");
+    } else if cli.lk_screen_log && !matches!(mode, BootMode::LK) {
+        anyhow::bail!("LK screen log is available only in the LK mode");
+    } else if cli.lk_via_preloader && !matches!(mode, BootMode::Preloader) {
+        anyhow::bail!("LK can be loaded by the preloader only in the Preloader mode");
+    } else if cli.lk_cmdline_hook && !matches!(mode, BootMode::LK) {
+        anyhow::bail!("LK command line hook is available only in the LK mode");
+    } else if cli.lk_cmdline_hook && cli.cmdline.is_none() && cli.cmdline_append.is_none() {
+        anyhow::bail!("LK command line hook needs --cmdline or --cmdline-append");
+    }
+
+    match mode {
         // BootROM needs Preloader, the preloader path is already checked
         BootMode::BootROM => {
             if has_kernel {
@@ -408,7 +975,7 @@ fn main() -> Result<()> {
                 println!("BootROM mode will boot Preloader");
             }
 
-            if !has_jump {
+            if jump_address.is_none() {
                 anyhow::bail!(
                     "BootROM needs target jump address if the target image is not Preloader"
                 );
@@ -416,10 +983,11 @@ fn main() -> Result<()> {
         }
         // Preloader needs... preloader, and the path is already checked
         BootMode::Preloader => {
-            if !has_at_least_one_file && lk.is_none() {
-                // XXX: boot stock LK
-                anyhow::bail!("Preloader needs at least one file to boot");
-            } else if !has_jump && lk.is_none() {
+            if !has_at_least_one_file && !has_lk {
+                anyhow::bail!(
+                    "Preloader needs at least one file to boot, use the continue mode to boot stock LK"
+                );
+            } else if jump_address.is_none() && !has_lk {
                 anyhow::bail!("Preloader needs target jump address");
             } else if has_kernel {
                 anyhow::bail!("Booting kernel is not possible in the Preloader mode");
@@ -427,10 +995,10 @@ fn main() -> Result<()> {
         }
         // LK needs Preloader and the LK, as well as at least one file to boot
         BootMode::LK { .. } => {
-            if lk.is_none() {
+            if !has_lk {
                 anyhow::bail!("LK mode requires LK file");
-            } else if !has_at_least_one_file && kernel.is_none() {
-                anyhow::bail!("LK mode requires kernel or prepared image");
+            } else if !has_at_least_one_file && kernel.is_none() && ram_parts.is_empty() {
+                anyhow::bail!("LK mode requires kernel, prepared image or RAM partition");
             } else if has_jump {
                 // XXX: remove once we can check if LK hardcodes kernel addr or no
                 //
@@ -439,9 +1007,7 @@ fn main() -> Result<()> {
             }
 
             if has_at_least_one_file && kernel.is_none() {
-                println!("Using prepared file, mkbootimg won't be invoked");
-            } else if which("mkbootimg").is_err() {
-                anyhow::bail!("mkbootimg is not installed for the LK mode");
+                println!("Using prepared file, boot.img won't be generated");
             }
 
             if cli.dram_size_per_rank.is_none() || cli.dram_ranks.is_none() {
@@ -456,25 +1022,167 @@ fn main() -> Result<()> {
                 anyhow::bail!("Booting kernel is not possible in the REPL mode");
             }
         }
+        // Continue needs only the preloader, LK and files are optional
+        BootMode::Continue => {
+            if has_jump {
+                anyhow::bail!("Continue mode can't have jump address");
+            } else if has_kernel {
+                anyhow::bail!("Booting kernel is not possible in the Continue mode");
+            }
+        }
+        // Kernel needs the kernel itself and the DRAM layout for the memory description
+        BootMode::Kernel => {
+            if !has_kernel {
+                anyhow::bail!("Kernel mode requires kernel");
+            } else if has_lk {
+                anyhow::bail!("Kernel mode boots without LK, don't provide it");
+            } else if has_jump {
+                anyhow::bail!("Kernel mode can't have jump address");
+            } else if cli.dram_size_per_rank.is_none() || cli.dram_ranks.is_none() {
+                anyhow::bail!("Unknown DRAM size. Please provide DRAM rank size and rank count");
+            }
+        }
+        // Dump and storage modes only work with the device, files are uploaded before if given
+        BootMode::Dump { .. }
+        | BootMode::ReadLba { .. }
+        | BootMode::ReadPartition { .. }
+        | BootMode::WritePartition { .. }
+        | BootMode::Partitions { .. } => {
+            if has_jump {
+                anyhow::bail!("Dump and storage modes can't have jump address");
+            } else if has_kernel {
+                anyhow::bail!("Booting kernel is not possible in the dump and storage modes");
+            }
+        }
+    }
+
+    // prepared boot.img is uploaded as is, so the header is patched right here
+    if matches!(mode, BootMode::LK)
+        && kernel.is_none()
+        && let Some(image) = input.first_mut()
+    {
+        if !image.starts_with(BOOT_MAGIC) {
+            if bootimg.cmdline.is_some() || bootimg.cmdline_append.is_some() {
+                println!("Prepared image is not a boot.img, command line is not changed");
+            }
+        } else {
+            let base = BootImg::parse(image.content())?.header.full_cmdline();
+            if let Some(cmdline) = bootimg.header_cmdline(&base) {
+                BootImg::set_cmdline(image.content_mut(), &cmdline)
+                    .context("Failed to patch boot.img command line")?;
+                println!("Patched boot.img command line: {cmdline:?}");
+            }
+        }
     }
 
-    let state = State {
+    Ok(Plan {
+        upload: input,
+        kernel,
+        ramdisk,
+        second,
+        dtb,
+        ram_parts,
+        jump_addr: jump_address.unwrap_or_default(),
+    })
+}
+
+fn run_tool(cli: &Cli, tool: &Tool) -> Result<()> {
+    match tool {
+        Tool::Inspect { files, unpack } => run_inspect(cli, files, unpack.as_deref()),
+        Tool::Identify { json } => run_identify(*json),
+        Tool::BromRepl => run_brom_repl(),
+        Tool::LegacyDa { da, op } => run_legacy_da(da, op),
+        Tool::XFlash { da, op } => run_xflash(da, op),
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mode = match cli.mode {
+        Mode::Boot(ref mode) => mode.clone(),
+        Mode::Tool(ref tool) => return run_tool(&cli, tool),
+    };
+
+    let mut params = PayloadParams::default();
+    params.watchdog = cli
+        .watchdog
+        .map_or_else(|| mode.default_watchdog(), From::from);
+
+    let (pl, lk) = load_bootloaders(&cli, &mut params)?;
+
+    let bootimg = BootImgOptions {
+        layout: BootImgLayout {
+            base: cli.base,
+            kernel_offset: cli.kernel_offset,
+            ramdisk_offset: cli.ramdisk_offset,
+            second_offset: cli.second_offset,
+            tags_offset: cli.tags_offset,
+            dtb_offset: cli.dtb_offset,
+            page_size: cli.pagesize,
+            header_version: cli.header_version,
+        },
+        cmdline: cli.cmdline.clone(),
+        cmdline_append: cli.cmdline_append.clone(),
+        cmdline_hook: cli.lk_cmdline_hook,
+        board: cli.board.clone(),
+        patch_dtb: cli.dtb_patch,
+    };
+
+    let plan = load_plan(&cli, &mode, lk.is_some(), &bootimg)?;
+
+    let mut state = State {
         soc: SoC::MT6572,
-        mode: cli.mode,
+        mode,
         lk_mode: cli.lk_mode.unwrap_or_default(),
+        lk_write_policy: cli.lk_write_policy.map(WritePolicy::from),
+        lk_overlay_size: cli.lk_overlay_size,
+        lk_screen_log: cli.lk_screen_log,
+        lk_via_preloader: cli.lk_via_preloader,
         dram_size_per_rank: cli.dram_size_per_rank.unwrap_or_default(),
         dram_ranks: cli.dram_ranks.unwrap_or_default(),
-        upload: input,
+        upload: plan.upload,
         preloader: pl,
         lk,
-        kernel,
-        ramdisk,
-        jump_addr: cli.jump_address.unwrap_or_default(),
+        kernel: plan.kernel,
+        ramdisk: plan.ramdisk,
+        second: plan.second,
+        dtb: plan.dtb,
+        machine_id: cli.machine_id,
+        ram_parts: plan.ram_parts,
+        bootimg,
+        jump_addr: plan.jump_addr,
         params,
+        session: None,
+        sla_signer: cli.sla_signer.clone().map(|program| -> Box<dyn SlaSigner> {
+            Box::new(CommandSigner::new(program, cli.sla_key.clone()))
+        }),
     };
 
+    if let Some(ref uart) = cli.uart {
+        session::start(
+            uart,
+            &cli.session_log,
+            cli.uart_fail_pattern.clone(),
+            cli.uart_on_fail,
+        )?;
+    }
+
     println!("For BROM mode short KCOL0 to GND or add the crash option and connect the device");
     println!("For preloader mode simply connect the device");
     println!();
-    run(state, cli.crash)
+    if cli.watch {
+        return run_watch(&cli, state);
+    }
+
+    let result = run(&mut state, cli.crash_method());
+    // the interesting part of the UART log usually comes after the boot
+    if cli.uart.is_some() {
+        match result {
+            Ok(()) => event!("Boot finished"),
+            Err(ref e) => event!("Boot failed: {e:?}"),
+        }
+        session::wait();
+    }
+
+    result
 }
@@ -1,11 +1,16 @@
-use std::iter::once;
+use std::{iter::once, path::PathBuf};
 
 use clap::{Parser, Subcommand};
 use clap_num::maybe_hex;
 use da_protocol::{Message, Protocol};
 use rustyline::{DefaultEditor, error::ReadlineError};
 
-use crate::{Port, Result, boot::rpc::ext::HostExtensions};
+use crate::{
+    Port, ResetMode, Result,
+    boot::rpc::ext::HostExtensions,
+    dump::{MemoryMap, RegionSpec, run_dump},
+    storage::{StoragePart, list_partitions, read_partition, read_to_file, write_partition},
+};
 
 #[derive(Parser)]
 struct REPL {
@@ -48,8 +53,63 @@ enum Command {
         #[arg(value_parser=maybe_hex::<u32>)]
         r1: Option<u32>,
     },
-    /// Reset the device using watchdog.
-    Reset,
+    /// Dump `region` (preset, `START..END` or `START+SIZE`) to a file in `out`.
+    Dump {
+        region: RegionSpec,
+        #[arg(short, long, default_value = "dump")]
+        out: PathBuf,
+        #[arg(long)]
+        resume: bool,
+        #[arg(long)]
+        sparse: bool,
+    },
+    /// Read `count` eMMC blocks at `lba` to the `out` file.
+    ReadLba {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        lba: u32,
+        #[arg(value_parser=maybe_hex::<u32>)]
+        count: u32,
+        #[arg(long, default_value = "user")]
+        part: StoragePart,
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Read partition `name` from the partition table to the `out` file.
+    ReadPartition {
+        name: String,
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Write `image` to partition `name`, backing up the original data first.
+    WritePartition {
+        name: String,
+        image: PathBuf,
+        #[arg(long)]
+        backup: Option<PathBuf>,
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the partitions, `json` gets the partition table if given.
+    Partitions {
+        #[arg(long)]
+        json: Option<PathBuf>,
+    },
+    /// Reset the device using watchdog, booting into `target`.
+    Reset {
+        #[arg(default_value = "normal")]
+        target: ResetMode,
+    },
+    /// Turn the watchdog off.
+    WdtDisable,
+    /// Turn the watchdog on, the device resets if it isn't kicked for `timeout_ms`.
+    WdtEnable {
+        #[arg(value_parser=maybe_hex::<u32>)]
+        timeout_ms: u32,
+    },
+    /// Restart the watchdog countdown.
+    WdtKick,
+    /// Resume the preloader boot flow, installed hooks stay active.
+    Continue,
 }
 
 fn do_send(protocol: &mut Protocol<Port>, message: Message) -> Result<()> {
@@ -60,7 +120,8 @@ fn do_send(protocol: &mut Protocol<Port>, message: Message) -> Result<()> {
         .map_err(Into::into)
 }
 
-pub fn run_repl(mut protocol: Protocol<Port>) -> Result<()> {
+/// Returns the session if it is still held by the payload, `None` if the boot flow was resumed
+pub fn run_repl(mut protocol: Protocol<Port>, map: MemoryMap) -> Result<Option<Protocol<Port>>> {
     println!("Enter --help for help, Ctrl-C to exit");
 
     let mut rl = DefaultEditor::new()?;
@@ -89,10 +150,33 @@ pub fn run_repl(mut protocol: Protocol<Port>) -> Result<()> {
                             do_send(&mut protocol, Message::Jump { addr, r0, r1 })?;
                             print_response(&mut protocol)?;
                         }
-                        Command::Reset => {
-                            do_send(&mut protocol, Message::Reset)?;
+                        Command::Reset { target } => {
+                            let target = target.into();
+                            do_send(&mut protocol, Message::Reset { target })?;
+                            print_response(&mut protocol)?;
+                        }
+                        Command::WdtDisable => {
+                            do_send(&mut protocol, Message::WatchdogDisable)?;
+                            print_response(&mut protocol)?;
+                        }
+                        Command::WdtEnable { timeout_ms } => {
+                            do_send(&mut protocol, Message::WatchdogEnable { timeout_ms })?;
                             print_response(&mut protocol)?;
                         }
+                        Command::WdtKick => {
+                            do_send(&mut protocol, Message::WatchdogKick)?;
+                            print_response(&mut protocol)?;
+                        }
+                        Command::Continue => {
+                            do_send(&mut protocol, Message::Continue)?;
+                            if protocol.read_response().is_ok_and(|r| r.is_ack()) {
+                                println!("<= Boot flow resumed, leaving REPL");
+                                return Ok(None);
+                            }
+                            eprintln!(
+                                "Failed to resume, the preloader boot flow is unknown or LK failed to load"
+                            );
+                        }
                         Command::Read { addr, size } => {
                             println!("Reading {size} bytes from {addr:#010x}...");
                             match protocol.download(addr, size) {
@@ -103,6 +187,56 @@ pub fn run_repl(mut protocol: Protocol<Port>) -> Result<()> {
                                 Err(e) => eprintln!("Download failed: {e}"),
                             }
                         }
+                        Command::Dump {
+                            region,
+                            out,
+                            resume,
+                            sparse,
+                        } => {
+                            if let Err(e) =
+                                run_dump(&mut protocol, &map, &[region], &out, resume, sparse)
+                            {
+                                eprintln!("Dump failed: {e:?}");
+                            }
+                        }
+                        Command::ReadLba {
+                            lba,
+                            count,
+                            part,
+                            out,
+                        } => {
+                            if let Err(e) =
+                                read_to_file(&mut protocol, lba, count, part.into(), &out)
+                            {
+                                eprintln!("Read failed: {e:?}");
+                            }
+                        }
+                        Command::ReadPartition { name, out } => {
+                            if let Err(e) = read_partition(&mut protocol, &name, out.as_deref()) {
+                                eprintln!("Read failed: {e:?}");
+                            }
+                        }
+                        Command::WritePartition {
+                            name,
+                            image,
+                            backup,
+                            force,
+                        } => {
+                            if let Err(e) = write_partition(
+                                &mut protocol,
+                                &name,
+                                &image,
+                                backup.as_deref(),
+                                force,
+                            ) {
+                                eprintln!("Write failed: {e:?}");
+                            }
+                        }
+                        Command::Partitions { json } => {
+                            if let Err(e) = list_partitions(&mut protocol, None, json.as_deref()) {
+                                eprintln!("Listing failed: {e:?}");
+                            }
+                        }
                         Command::Write { addr, data } => {
                             println!("Writing {} bytes to {addr:#010x}...", data.len());
                             match protocol.upload(addr, &data) {
@@ -121,7 +255,7 @@ pub fn run_repl(mut protocol: Protocol<Port>) -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(Some(protocol))
 }
 
 fn print_response(protocol: &mut Protocol<Port>) -> Result<()> {
@@ -0,0 +1,177 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+use simpleport::{SimpleRead, SimpleWrite};
+
+use crate::{
+    BootMode, DeviceMode, Port, State,
+    commands::generic::{GetTargetConfig, TargetConfig},
+    session::event,
+};
+
+/// Start of the SLA authentication
+const CMD_SLA: u8 = 0xe3;
+/// Status after `CMD_SLA` when SLA is already passed in this session
+const SLA_PASSED: u16 = 0x7017;
+/// Longest challenge accepted from the device
+const MAX_CHALLENGE_LEN: u32 = 0x1000;
+
+/// Answers the SLA challenge of the device
+pub trait SlaSigner {
+    fn sign(&self, challenge: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// External program signing the challenge, e.g. a script around the key of the device owner
+///
+/// The challenge goes to its stdin, the signature is read from its stdout. The key file,
+/// if given, is passed as the only argument.
+pub struct CommandSigner {
+    program: PathBuf,
+    key: Option<PathBuf>,
+}
+
+impl CommandSigner {
+    pub fn new(program: PathBuf, key: Option<PathBuf>) -> Self {
+        Self { program, key }
+    }
+}
+
+impl SlaSigner for CommandSigner {
+    fn sign(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.key)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Can't run SLA signer {}", self.program.display()))?;
+
+        child
+            .stdin
+            .take()
+            .context("SLA signer has no stdin")?
+            .write_all(challenge)?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            anyhow::bail!("SLA signer failed: {}", output.status);
+        } else if output.stdout.is_empty() {
+            anyhow::bail!("SLA signer returned an empty signature");
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// Get the challenge, sign it and send the signature back
+fn authenticate(port: &mut Port, signer: &dyn SlaSigner) -> Result<()> {
+    port.write_u8(CMD_SLA)?;
+    if port.read_u8()? != CMD_SLA {
+        anyhow::bail!("Device didn't echo the SLA command");
+    }
+
+    match port.read_u16_be()? {
+        0 => (),
+        SLA_PASSED => {
+            event!("SLA is already passed");
+            return Ok(());
+        }
+        status => anyhow::bail!("Device refused to start SLA: {status:#x}"),
+    }
+
+    let len = port.read_u32_be()?;
+    if len == 0 || len > MAX_CHALLENGE_LEN {
+        anyhow::bail!("Bad SLA challenge length: {len:#x}");
+    }
+    let mut challenge = vec![0u8; len as usize];
+    port.read_exact(&mut challenge)?;
+
+    let signature = signer.sign(&challenge)?;
+    port.simple_write_be(signature.len() as u32)?;
+    let echo = port.read_u32_be()?;
+    if echo != signature.len() as u32 {
+        anyhow::bail!("Device didn't echo the signature length");
+    }
+    let status = port.read_u16_be()?;
+    if status != 0 {
+        anyhow::bail!("Device refused the signature length: {status:#x}");
+    }
+    port.write_all(&signature)?;
+
+    let status = port.read_u16_be()?;
+    if status != 0 {
+        anyhow::bail!("Signature is rejected: {status:#x}");
+    }
+
+    Ok(())
+}
+
+fn device_name(device_mode: DeviceMode) -> &'static str {
+    match device_mode {
+        DeviceMode::Brom => "BootROM",
+        DeviceMode::Preloader => "preloader",
+    }
+}
+
+fn on_off(flag: bool) -> &'static str {
+    if flag { "on" } else { "off" }
+}
+
+/// What secure boot means for the selected mode
+fn sbc_impact(state: &State) -> &'static str {
+    match state.mode {
+        BootMode::Preloader if state.lk_via_preloader => {
+            "the preloader verifies the LK it loads, unsigned LK is rejected"
+        }
+        BootMode::Continue => {
+            "the stock boot flow verifies LK and boot.img, unsigned ones are rejected"
+        }
+        BootMode::LK => "LK may verify boot.img, an unsigned one can be rejected",
+        _ => "not affected, the payload loads everything by itself",
+    }
+}
+
+/// Query the security flags, report what they mean for the mode and pass SLA if possible
+///
+/// DAA and SLA without a signer are fatal: the payload upload would fail anyway.
+pub fn check_security(state: &State, port: &mut Port, device_mode: DeviceMode) -> Result<()> {
+    let device = device_name(device_mode);
+    let mut cmd = GetTargetConfig::new();
+    if let Err(e) = cmd.run(port) {
+        println!("Target config is not available ({e}), assuming no security");
+        return Ok(());
+    }
+
+    let TargetConfig { sbc, sla, daa } = cmd.parse();
+    event!(
+        "Target config ({device}): SBC {}, SLA {}, DAA {}",
+        on_off(sbc),
+        on_off(sla),
+        on_off(daa)
+    );
+
+    if sbc {
+        println!("Secure boot: {}", sbc_impact(state));
+    }
+
+    if daa {
+        anyhow::bail!(
+            "DAA is enabled: the {device} runs only signed download agents, the da-boot payload would be rejected"
+        );
+    }
+
+    if sla {
+        let Some(ref signer) = state.sla_signer else {
+            anyhow::bail!(
+                "SLA is enabled: the {device} refuses the payload until its challenge is signed. Use --sla-signer with the key of your device"
+            );
+        };
+
+        authenticate(port, signer.as_ref()).context("SLA authentication failed")?;
+        event!("SLA passed");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs::File,
+    io::{self, LineWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::Port;
+
+/// Baud rate of the MediaTek UART log
+const DEFAULT_BAUD: u32 = 921600;
+/// Read timeout of the UART, the capture just tries again
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// UART from the command line: `PATH[:BAUD]`
+#[derive(Debug, Clone)]
+pub struct UartSpec {
+    path: String,
+    baud: u32,
+}
+
+impl FromStr for UartSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some((path, baud)) = s.rsplit_once(':')
+            && let Ok(baud) = baud.parse()
+        {
+            return Ok(Self {
+                path: path.to_string(),
+                baud,
+            });
+        }
+
+        Ok(Self {
+            path: s.to_string(),
+            baud: DEFAULT_BAUD,
+        })
+    }
+}
+
+/// What to do when a failure pattern shows up on the UART
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FailAction {
+    /// Mark the line in the session log and go on
+    Flag,
+    /// Stop da-boot with an error
+    Stop,
+}
+
+/// Session log shared by the host and the UART capture
+struct SessionLog {
+    start: Instant,
+    file: LineWriter<File>,
+}
+
+static LOG: Mutex<Option<SessionLog>> = Mutex::new(None);
+static CAPTURE: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// `println!` going to the session log too
+macro_rules! event {
+    ($($arg:tt)*) => {
+        $crate::session::event(&format!($($arg)*))
+    };
+}
+pub(crate) use event;
+
+fn log(source: &str, line: &str) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(log) = log.as_mut() {
+        let time = log.start.elapsed().as_secs_f64();
+        // the log is best effort, the boot goes on without it
+        let _ = writeln!(log.file, "[{time:>10.3}] {source:<4} | {line}");
+    }
+}
+
+/// Print the host event and put it into the session log
+pub fn event(line: &str) {
+    println!("{line}");
+    log("host", line);
+}
+
+fn capture_line(line: &[u8], patterns: &[String], action: FailAction) {
+    let line = String::from_utf8_lossy(line);
+    println!("[uart] {line}");
+    log("uart", &line);
+
+    let Some(pattern) = patterns.iter().find(|p| line.contains(p.as_str())) else {
+        return;
+    };
+    match action {
+        FailAction::Flag => {
+            eprintln!("Failure on the UART: {pattern:?}");
+            log("host", &format!("FAILURE: UART matched {pattern:?}"));
+        }
+        FailAction::Stop => {
+            eprintln!("Failure on the UART: {pattern:?}, stopping");
+            log(
+                "host",
+                &format!("FAILURE: UART matched {pattern:?}, stopping"),
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn capture(mut port: Port, patterns: Vec<String>, action: FailAction) {
+    let mut buf = [0u8; 256];
+    let mut line = Vec::new();
+
+    loop {
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                event!("UART read failed: {e}, capture stopped");
+                return;
+            }
+        };
+
+        for &byte in &buf[..n] {
+            match byte {
+                b'\n' => {
+                    capture_line(&line, &patterns, action);
+                    line.clear();
+                }
+                b'\r' => (),
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+/// Start the UART capture, its lines and the host events go to `log_path`
+///
+/// Lines containing any of `patterns` are handled according to `action`.
+pub fn start(
+    uart: &UartSpec,
+    log_path: &Path,
+    patterns: Vec<String>,
+    action: FailAction,
+) -> Result<()> {
+    let port = serialport::new(&uart.path, uart.baud)
+        .timeout(READ_TIMEOUT)
+        .open()
+        .with_context(|| format!("Can't open UART {}", uart.path))?;
+
+    let mut file = LineWriter::new(
+        File::create(log_path).with_context(|| format!("Can't create {}", log_path.display()))?,
+    );
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    writeln!(
+        file,
+        "# da-boot session, started at {time} (unix time), UART {} at {}",
+        uart.path, uart.baud
+    )?;
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(SessionLog {
+        start: Instant::now(),
+        file,
+    });
+
+    event!(
+        "Capturing UART {} at {} to {}",
+        uart.path,
+        uart.baud,
+        log_path.display()
+    );
+    let handle = thread::spawn(move || capture(port, patterns, action));
+    *CAPTURE.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle);
+
+    Ok(())
+}
+
+/// Keep capturing until the UART goes away or the process is interrupted
+pub fn wait() {
+    let handle = CAPTURE.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(handle) = handle {
+        event!("Capturing UART, Ctrl-C to exit");
+        let _ = handle.join();
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use da_protocol::{BLOCK_SIZE, EmmcPart, Protocol};
+use kaiko::Analyzer;
+use kdam::{BarExt, tqdm};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    Port,
+    boot::rpc::ext::HostExtensions,
+    formats::{
+        Partition,
+        gpt::{GPT_HEADER_LBA, GptHeader},
+        mbr::{MbrEntry, parse_mbr},
+        pmt::{PMT_BLOCKS, PMT_END_OFFSET, parse_pmt},
+    },
+    session::event,
+};
+
+/// Device memory reserved for the block transfers
+pub const SCRATCH_SIZE: u32 = 256 * 1024;
+/// Bound of the EBR chain walk, a looped chain would never end otherwise
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+/// Partitions that can brick the device, written only when forced
+const PROTECTED: [&str; 2] = ["preloader", "boot0"];
+
+/// eMMC hardware partition from the CLI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StoragePart {
+    /// First boot partition with the preloader (mmcblk0boot0 in Linux)
+    Boot1,
+    /// Second boot partition (mmcblk0boot1 in Linux)
+    Boot2,
+    /// User data area
+    User,
+}
+
+impl From<EmmcPart> for StoragePart {
+    fn from(value: EmmcPart) -> Self {
+        match value {
+            EmmcPart::Boot1 => Self::Boot1,
+            EmmcPart::Boot2 => Self::Boot2,
+            EmmcPart::User => Self::User,
+        }
+    }
+}
+
+impl From<StoragePart> for EmmcPart {
+    fn from(value: StoragePart) -> Self {
+        match value {
+            StoragePart::Boot1 => Self::Boot1,
+            StoragePart::Boot2 => Self::Boot2,
+            StoragePart::User => Self::User,
+        }
+    }
+}
+
+pub(crate) fn hex_digest(sha: Sha1) -> String {
+    sha.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Read `count` blocks at `lba` to `out`, returns the SHA-1 of the data in hex
+fn read_to(
+    protocol: &mut Protocol<Port>,
+    lba: u32,
+    count: u32,
+    part: EmmcPart,
+    out: &mut dyn Write,
+) -> Result<String> {
+    let max_blocks = SCRATCH_SIZE / BLOCK_SIZE;
+    let mut sha = Sha1::new();
+    let mut pb = tqdm!(
+        total = count as usize * BLOCK_SIZE as usize,
+        desc = format!("LBA {lba:#x}"),
+        unit = "B"
+    );
+
+    let mut done = 0;
+    while done < count {
+        let blocks = max_blocks.min(count - done);
+        let data = protocol
+            .read_blocks(lba + done, blocks, part)
+            .with_context(|| format!("Failed to read LBA {:#x}", lba + done))?;
+
+        out.write_all(&data)?;
+        sha.update(&data);
+        pb.update(data.len())?;
+        done += blocks;
+    }
+    println!();
+
+    Ok(hex_digest(sha))
+}
+
+/// Read blocks to the file, the SHA-1 goes next to it in the `sha1sum` format
+pub fn read_to_file(
+    protocol: &mut Protocol<Port>,
+    lba: u32,
+    count: u32,
+    part: EmmcPart,
+    path: &Path,
+) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    let sha = read_to(protocol, lba, count, part, &mut out)?;
+    out.flush()?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut sha_path = path.as_os_str().to_owned();
+    sha_path.push(".sha1");
+    fs::write(PathBuf::from(sha_path), format!("{sha}  {name}\n"))?;
+
+    println!(
+        "Read {count} blocks at LBA {lba:#x} of {part:?} to {}",
+        path.display()
+    );
+    println!("SHA-1: {sha}");
+    Ok(())
+}
+
+/// Partitions of the user area from the GPT
+fn read_gpt(protocol: &mut Protocol<Port>, header: &GptHeader) -> Result<Vec<Partition>> {
+    let lba = u32::try_from(header.entries_lba).context("GPT entries are beyond 2TiB")?;
+    let blocks = header.entries_size().div_ceil(BLOCK_SIZE as usize) as u32;
+    let mut entries = Vec::new();
+    for i in (0..blocks).step_by((SCRATCH_SIZE / BLOCK_SIZE) as usize) {
+        let count = (SCRATCH_SIZE / BLOCK_SIZE).min(blocks - i);
+        entries.extend(protocol.read_blocks(lba + i, count, EmmcPart::User)?);
+    }
+
+    header.entries(&entries)
+}
+
+/// Partitions from the MBR and the EBR chain, named the way Linux numbers them
+fn read_mbr(protocol: &mut Protocol<Port>) -> Result<Vec<Partition>> {
+    let partition = |name: String, entry: &MbrEntry, base: u32| Partition {
+        name,
+        part: EmmcPart::User,
+        start: base as u64 + entry.start as u64,
+        size: entry.size as u64,
+        attributes: entry.kind as u64,
+    };
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for entry in parse_mbr(&protocol.read_blocks(0, 1, EmmcPart::User)?)? {
+        if entry.is_extended() {
+            extended = Some(entry.start);
+        } else {
+            partitions.push(partition(format!("p{}", entry.slot + 1), &entry, 0));
+        }
+    }
+
+    // logical partitions are numbered from 5, each EBR points to the next one
+    if let Some(base) = extended {
+        let mut ebr = base;
+        for n in 5..5 + MAX_LOGICAL_PARTITIONS {
+            let entries = parse_mbr(&protocol.read_blocks(ebr, 1, EmmcPart::User)?)
+                .with_context(|| format!("Bad EBR at LBA {ebr:#x}"))?;
+            let mut entries = entries.iter();
+            let Some(logical) = entries.next().filter(|e| !e.is_extended()) else {
+                break;
+            };
+            partitions.push(partition(format!("p{n}"), logical, ebr));
+
+            let Some(next) = entries.next().filter(|e| e.is_extended()) else {
+                break;
+            };
+            ebr = base + next.start;
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Partition table of the boot device and where it came from
+pub struct PartitionTable {
+    pub source: &'static str,
+    pub partitions: Vec<Partition>,
+}
+
+/// Find the partition table: GPT, MediaTek PMT, then the plain MBR
+pub fn read_partition_table(protocol: &mut Protocol<Port>) -> Result<PartitionTable> {
+    let block = protocol.read_blocks(GPT_HEADER_LBA, 1, EmmcPart::User)?;
+    match GptHeader::parse(&block) {
+        Ok(header) => {
+            return Ok(PartitionTable {
+                source: "GPT",
+                partitions: read_gpt(protocol, &header)?,
+            });
+        }
+        Err(e) => println!("GPT not found: {e}"),
+    }
+
+    let blocks = protocol.block_count()?;
+    let lba = blocks
+        .checked_sub(PMT_END_OFFSET)
+        .context("User area is too small")?;
+    let data = protocol.read_blocks(lba, PMT_BLOCKS, EmmcPart::User)?;
+    match parse_pmt(&data, blocks) {
+        Ok(partitions) => {
+            return Ok(PartitionTable {
+                source: "PMT",
+                partitions,
+            });
+        }
+        Err(e) => println!("PMT not found: {e}"),
+    }
+
+    Ok(PartitionTable {
+        source: "MBR",
+        partitions: read_mbr(protocol).context("No partition table found")?,
+    })
+}
+
+/// Find the partition by its name
+pub fn find_partition(protocol: &mut Protocol<Port>, name: &str) -> Result<Partition> {
+    let table = read_partition_table(protocol).context("Failed to read the partition table")?;
+    let names: Vec<&str> = table.partitions.iter().map(|p| p.name.as_str()).collect();
+    let names = names.join(", ");
+
+    table
+        .partitions
+        .into_iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .with_context(|| format!("No partition {name}, known partitions: {names}"))
+}
+
+/// Read the whole partition `name` to `path`, `NAME.img` if not given
+pub fn read_partition(
+    protocol: &mut Protocol<Port>,
+    name: &str,
+    path: Option<&Path>,
+) -> Result<()> {
+    let part = find_partition(protocol, name)?;
+    let (Ok(start), Ok(size)) = (u32::try_from(part.start), u32::try_from(part.size)) else {
+        anyhow::bail!("Partition {} is beyond 2TiB", part.name);
+    };
+    println!(
+        "Partition {}: LBA {start:#x} of {:?}, {size} blocks",
+        part.name, part.part
+    );
+
+    let default = PathBuf::from(format!("{}.img", part.name));
+    read_to_file(protocol, start, size, part.part, path.unwrap_or(&default))
+}
+
+/// Storage the partition images are written to, addresses are in blocks
+pub(crate) trait BlockDevice {
+    fn block_size(&self) -> u64;
+
+    /// Read `count` blocks at `lba` of `part` to `out`, returns the SHA-1 of the data in hex
+    fn read_to(
+        &mut self,
+        part: EmmcPart,
+        lba: u64,
+        count: u64,
+        out: &mut dyn Write,
+    ) -> Result<String>;
+
+    /// Write `data`, a whole number of blocks, at `lba` of `part`
+    fn write(&mut self, part: EmmcPart, lba: u64, data: &[u8], desc: String) -> Result<()>;
+}
+
+impl BlockDevice for Protocol<Port> {
+    fn block_size(&self) -> u64 {
+        BLOCK_SIZE as u64
+    }
+
+    fn read_to(
+        &mut self,
+        part: EmmcPart,
+        lba: u64,
+        count: u64,
+        out: &mut dyn Write,
+    ) -> Result<String> {
+        let (Ok(lba), Ok(count)) = (u32::try_from(lba), u32::try_from(count)) else {
+            anyhow::bail!("LBA {lba:#x}+{count:#x} is beyond 2TiB");
+        };
+        read_to(self, lba, count, part, out)
+    }
+
+    fn write(&mut self, part: EmmcPart, lba: u64, data: &[u8], desc: String) -> Result<()> {
+        let lba = u32::try_from(lba).context("LBA is beyond 2TiB")?;
+        let mut pb = tqdm!(total = data.len(), desc = desc, unit = "B");
+        let max_blocks = SCRATCH_SIZE / BLOCK_SIZE;
+        for (i, chunk) in data.chunks(SCRATCH_SIZE as usize).enumerate() {
+            let lba = lba + i as u32 * max_blocks;
+            self.write_blocks(lba, part, chunk)
+                .with_context(|| format!("Failed to write LBA {lba:#x}"))?;
+            pb.update(chunk.len())?;
+        }
+        println!();
+
+        Ok(())
+    }
+}
+
+/// Write `image` to `part`, the original data is backed up first
+///
+/// The backup goes to `backup` or `NAME-backup-TIME.img` and covers the written range only.
+/// The preloader and the boot partitions are refused unless `force` is set. The written data
+/// is read back and its SHA-1 is compared with the image.
+pub(crate) fn write_image(
+    dev: &mut impl BlockDevice,
+    part: &Partition,
+    image: &Path,
+    backup: Option<&Path>,
+    force: bool,
+) -> Result<()> {
+    let protected =
+        part.part != EmmcPart::User || PROTECTED.iter().any(|p| part.name.eq_ignore_ascii_case(p));
+    if protected && !force {
+        anyhow::bail!(
+            "Partition {} is in the preloader area, a bad write bricks the device. Use --force to write it anyway",
+            part.name
+        );
+    }
+
+    let block = dev.block_size();
+    let data = fs::read(image).with_context(|| format!("Can't read {}", image.display()))?;
+    if data.is_empty() || data.len() as u64 % block != 0 {
+        anyhow::bail!(
+            "{} is not a whole number of {block} byte blocks",
+            image.display()
+        );
+    }
+    let count = data.len() as u64 / block;
+    if count > part.size {
+        anyhow::bail!(
+            "{} is {count} blocks, partition {} has only {}",
+            image.display(),
+            part.name,
+            part.size
+        );
+    }
+
+    // there's no way back without the backup, so nothing is written if it fails
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let default = PathBuf::from(format!("{}-backup-{time}.img", part.name));
+    let backup = backup.unwrap_or(&default);
+    if backup.exists() {
+        anyhow::bail!("Backup {} already exists", backup.display());
+    }
+    println!("Backing up partition {}", part.name);
+    let file =
+        File::create(backup).with_context(|| format!("Can't create {}", backup.display()))?;
+    let mut out = BufWriter::new(file);
+    dev.read_to(part.part, part.start, count, &mut out)
+        .context("Backup failed, nothing was written")?;
+    out.flush()?;
+
+    dev.write(
+        part.part,
+        part.start,
+        &data,
+        format!("Writing {}", part.name),
+    )
+    .with_context(|| format!("Write failed, the original data is in {}", backup.display()))?;
+
+    let mut sha = Sha1::new();
+    sha.update(&data);
+    let expected = hex_digest(sha);
+    let written = dev.read_to(part.part, part.start, count, &mut io::sink())?;
+    if written != expected {
+        anyhow::bail!(
+            "Verification failed: image SHA-1 is {expected}, read back {written}. The original data is in {}",
+            backup.display()
+        );
+    }
+
+    event!(
+        "Wrote {count} blocks of {} to partition {}, SHA-1 verified: {expected}",
+        image.display(),
+        part.name
+    );
+    println!("Backup: {}", backup.display());
+    Ok(())
+}
+
+/// Write `image` to the partition `name` through the payload, see [`write_image`]
+pub fn write_partition(
+    protocol: &mut Protocol<Port>,
+    name: &str,
+    image: &Path,
+    backup: Option<&Path>,
+    force: bool,
+) -> Result<()> {
+    let part = find_partition(protocol, name)?;
+    write_image(protocol, &part, image, backup, force)
+}
+
+/// Partition with the LK name check for the JSON export
+#[derive(Serialize)]
+struct ListedPartition<'a> {
+    #[serde(flatten)]
+    partition: &'a Partition,
+    /// The name is a string in the LK image, `None` without LK. Start and size aren't compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name_known_to_lk: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Listing<'a> {
+    source: &'a str,
+    block_size: u32,
+    partitions: Vec<ListedPartition<'a>>,
+}
+
+/// Print the partition table, optionally exporting it to JSON
+///
+/// With LK, every partition name is looked up as a string in the LK image, where the names
+/// passed to `get_part` and `mt_part_get_partition` are. Only the name is checked, not the
+/// start or the size LK resolves it to.
+pub fn list_partitions(
+    protocol: &mut Protocol<Port>,
+    lk: Option<&Analyzer>,
+    json: Option<&Path>,
+) -> Result<()> {
+    let table = read_partition_table(protocol).context("Failed to read the partition table")?;
+    let listing = Listing {
+        source: table.source,
+        block_size: BLOCK_SIZE,
+        partitions: table
+            .partitions
+            .iter()
+            .map(|p| ListedPartition {
+                partition: p,
+                name_known_to_lk: lk.map(|lk| lk.blocks_by_str(&p.name).next().is_some()),
+            })
+            .collect(),
+    };
+
+    println!("Partition table: {}", listing.source);
+    println!(
+        "{:<20} {:<6} {:>12} {:>12} {:>10} {:>18}{}",
+        "Name",
+        "Part",
+        "Start LBA",
+        "Blocks",
+        "Size",
+        "Attributes",
+        if lk.is_some() { "  LK name" } else { "" }
+    );
+    for ListedPartition {
+        partition: p,
+        name_known_to_lk,
+    } in &listing.partitions
+    {
+        let known = match name_known_to_lk {
+            Some(true) => "  yes",
+            Some(false) => "  no",
+            None => "",
+        };
+        println!(
+            "{:<20} {:<6} {:>#12x} {:>12} {:>9}M {:>#18x}{known}",
+            p.name,
+            format!("{:?}", p.part),
+            p.start,
+            p.size,
+            p.size * BLOCK_SIZE as u64 / (1024 * 1024),
+            p.attributes,
+        );
+    }
+
+    if lk.is_some() {
+        let unknown: Vec<&str> = listing
+            .partitions
+            .iter()
+            .filter(|p| p.name_known_to_lk == Some(false))
+            .map(|p| p.partition.name.as_str())
+            .collect();
+        if !unknown.is_empty() {
+            println!("Names unknown to LK: {}", unknown.join(", "));
+        }
+    }
+
+    if let Some(path) = json {
+        let text = serde_json::to_string_pretty(&listing)?;
+        fs::write(path, text).with_context(|| format!("Can't write {}", path.display()))?;
+        println!("Partition table exported to {}", path.display());
+    }
+
+    Ok(())
+}
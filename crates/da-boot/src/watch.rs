@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use da_params::PayloadParams;
+use da_protocol::{Message, Protocol, Response, reset::ResetTarget};
+
+use crate::{Cli, Port, State, load_bootloaders, load_plan, run};
+
+/// Interval between the modification time checks
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Time for the device to leave the USB after the watchdog reset
+const RESET_DELAY: Duration = Duration::from_secs(1);
+
+/// Watched file and its last modification time
+struct Watched {
+    path: PathBuf,
+    /// Preloader and LK, their analysis is cached until they change
+    bootloader: bool,
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn watched_files(cli: &Cli) -> Vec<Watched> {
+    let bootloaders = cli.preloader.iter().chain(&cli.lk).map(|f| (&**f, true));
+    let files = [&cli.kernel, &cli.ramdisk, &cli.second, &cli.dtb]
+        .into_iter()
+        .flatten()
+        .map(|f| &**f)
+        .chain(cli.ram_part.iter().map(|p| &**p))
+        .chain(cli.input.iter().map(|f| &**f))
+        .map(|f| (f, false));
+
+    bootloaders
+        .chain(files)
+        .map(|(path, bootloader)| Watched {
+            path: path.to_path_buf(),
+            bootloader,
+            modified: modified(path),
+        })
+        .collect()
+}
+
+/// Block until the files change, returns whether the preloader or LK did
+///
+/// Editors and build systems write in several steps, so the change is reported once
+/// the modification times stay the same for a whole poll interval.
+fn wait_for_change(files: &mut [Watched]) -> bool {
+    let mut bootloader_changed = false;
+    let mut changed = false;
+
+    loop {
+        sleep(POLL_INTERVAL);
+
+        let mut settled = true;
+        for file in files.iter_mut() {
+            let modified = modified(&file.path);
+            if modified != file.modified {
+                println!("Changed: {}", file.path.display());
+                file.modified = modified;
+                bootloader_changed |= file.bootloader;
+                changed = true;
+                settled = false;
+            }
+        }
+
+        if changed && settled {
+            return bootloader_changed;
+        }
+    }
+}
+
+/// Reset the device through the payload session kept by the last round
+///
+/// The payload answers only if it can't reset, otherwise the device just goes away.
+fn reset(mut protocol: Protocol<Port>) {
+    println!("Resetting the device");
+    if let Err(e) = protocol.send_message(Message::Reset {
+        target: ResetTarget::Normal,
+    }) {
+        eprintln!("Failed to reset the device: {e}, reset it manually");
+        return;
+    }
+
+    match protocol.read_response() {
+        Ok(Response::Nack(e)) => {
+            eprintln!("Payload can't reset the device: {e}, reset it manually")
+        }
+        Ok(response) => {
+            eprintln!("Unexpected response to the reset: {response}, reset it manually")
+        }
+        Err(_) => {
+            drop(protocol);
+            sleep(RESET_DELAY);
+        }
+    }
+}
+
+/// Boot, then boot again every time the files change
+///
+/// Errors of a single round are printed, the watch goes on until interrupted.
+pub fn run_watch(cli: &Cli, mut state: State) -> Result<()> {
+    let mut files = watched_files(cli);
+    // params with the LK BSS reserved, the run fills the rest
+    let mut params = state.params;
+    let mut reload_bootloaders = false;
+
+    loop {
+        state.params = params;
+        if let Err(e) = run(&mut state, cli.crash_method()) {
+            eprintln!("Boot failed: {e:?}");
+        }
+
+        println!();
+        println!("Watching {} files for changes, Ctrl-C to exit", files.len());
+
+        loop {
+            reload_bootloaders |= wait_for_change(&mut files);
+
+            if reload_bootloaders {
+                println!("Bootloader changed, analyzing again");
+                let mut fresh = PayloadParams::default();
+                fresh.watchdog = params.watchdog;
+                match load_bootloaders(cli, &mut fresh) {
+                    Ok((pl, lk)) => {
+                        state.preloader = pl;
+                        state.lk = lk;
+                        params = fresh;
+                        reload_bootloaders = false;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to load bootloaders: {e:?}");
+                        continue;
+                    }
+                }
+            }
+
+            match load_plan(cli, &state.mode, state.lk.is_some(), &state.bootimg) {
+                Ok(plan) => {
+                    state.upload = plan.upload;
+                    state.kernel = plan.kernel;
+                    state.ramdisk = plan.ramdisk;
+                    state.second = plan.second;
+                    state.dtb = plan.dtb;
+                    state.ram_parts = plan.ram_parts;
+                    state.jump_addr = plan.jump_addr;
+                    break;
+                }
+                Err(e) => eprintln!("Failed to load files: {e:?}"),
+            }
+        }
+
+        if let Some(protocol) = state.session.take() {
+            reset(protocol);
+        } else {
+            println!("The payload handed over the device, reset it to boot the new files");
+        }
+        println!();
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use acon::SoC;
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use da_protocol::EmmcPart;
+use kdam::{BarExt, tqdm};
+use sha1::{Digest, Sha1};
+use simpleport::{SimpleRead, SimpleWrite};
+
+use crate::{
+    Port,
+    commands::xflash::{
+        BootTo, DeviceCtrl, ReadData, SetupEnvironment, SetupHwInitParams, Shutdown, WriteData,
+        XFlashCommand, ctrl, storage,
+    },
+    formats::{
+        Partition,
+        da::{DaEntry, DaFile},
+        gpt::{GPT_HEADER_LBA, GptHeader},
+    },
+    legacy_da::{connect, find_entry, run_da1},
+    session::event,
+    storage::{BlockDevice, StoragePart, hex_digest, write_image},
+};
+
+/// Start of every XFlash packet
+const MAGIC: u32 = 0xfeeeeeef;
+/// Packet type of the commands, statuses and data
+const DT_PROTOCOL_FLOW: u32 = 1;
+/// "SYNC", sent by the host once DA1 runs and by DA1 once it's set up
+const SYNC_SIGNAL: u32 = 0x434e5953;
+/// Sync byte sent by DA1 right after the jump
+const DA_SYNC: u8 = 0xc0;
+/// Longest packet accepted from the DA
+const MAX_PACKET_SIZE: usize = 0x1000000;
+/// Packet size used for DA2 before the DA tells its own
+const DEFAULT_PACKET_SIZE: usize = 0x100000;
+
+/// Operation done through the XFlash DA
+#[derive(Clone, PartialEq, Eq, Subcommand)]
+pub enum XFlashOp {
+    /// Print the storage and its partitions
+    Info,
+    /// Read a partition from the GPT by its name
+    ReadPartition {
+        /// Partition name
+        name: String,
+        /// Output file, `NAME.img` if not given, its SHA-1 is written next to it
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Write an image to a partition from the GPT, the original data is backed up first
+    WritePartition {
+        /// Partition name
+        name: String,
+        /// Image to write, not bigger than the partition
+        image: PathBuf,
+        /// Backup of the overwritten range, `NAME-backup-TIME.img` if not given
+        #[arg(long)]
+        backup: Option<PathBuf>,
+        /// Write the preloader too
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reset the device
+    Reboot,
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Storage found by DA2, sizes are in bytes
+enum Storage {
+    Emmc {
+        block_size: u32,
+        boot1: u64,
+        boot2: u64,
+        user: u64,
+        cid: [u8; 16],
+    },
+    Ufs {
+        block_size: u32,
+        lu0: u64,
+        lu1: u64,
+        lu2: u64,
+    },
+}
+
+impl Storage {
+    /// Storage type of the read and write commands
+    fn kind(&self) -> u32 {
+        match self {
+            Self::Emmc { .. } => storage::EMMC,
+            Self::Ufs { .. } => storage::UFS,
+        }
+    }
+
+    fn block_size(&self) -> u32 {
+        match *self {
+            Self::Emmc { block_size, .. } | Self::Ufs { block_size, .. } => block_size,
+        }
+    }
+
+    /// Hardware partition code and its size, UFS has the boot partitions in LU0 and LU1
+    fn part(&self, part: StoragePart) -> (u32, u64) {
+        match (self, part) {
+            (Self::Emmc { boot1, .. }, StoragePart::Boot1) => (1, *boot1),
+            (Self::Emmc { boot2, .. }, StoragePart::Boot2) => (2, *boot2),
+            (Self::Emmc { user, .. }, StoragePart::User) => (8, *user),
+            (Self::Ufs { lu0, .. }, StoragePart::Boot1) => (0, *lu0),
+            (Self::Ufs { lu1, .. }, StoragePart::Boot2) => (1, *lu1),
+            (Self::Ufs { lu2, .. }, StoragePart::User) => (2, *lu2),
+        }
+    }
+}
+
+/// Device running DA2 of the XFlash DA
+struct XFlash {
+    port: Port,
+    write_packet_len: usize,
+    read_packet_len: usize,
+    storage: Option<Storage>,
+}
+
+impl XFlash {
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&DT_PROTOCOL_FLOW.to_le_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.port.write_all(&header)?;
+        self.port.write_all(data)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0u8; 12];
+        self.port.read_exact(&mut header)?;
+        if le32(&header, 0) != MAGIC {
+            anyhow::bail!("Bad XFlash packet magic: {:#x}", le32(&header, 0));
+        }
+
+        let len = le32(&header, 8) as usize;
+        if len > MAX_PACKET_SIZE {
+            anyhow::bail!("XFlash packet is too big: {len:#x}");
+        }
+        let mut data = vec![0u8; len];
+        self.port.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Status packet of the last step, anything but 0 is an error
+    fn status(&mut self) -> Result<()> {
+        let data = self.recv()?;
+        let status = match data.len() {
+            2 => u16::from_le_bytes([data[0], data[1]]) as u32,
+            4 => le32(&data, 0),
+            len => anyhow::bail!("Bad status packet length: {len}"),
+        };
+
+        if status != 0 {
+            anyhow::bail!("DA returned status {status:#x}");
+        }
+        Ok(())
+    }
+
+    fn command<C: XFlashCommand>(&mut self, cmd: &C) -> Result<()> {
+        self.send(&C::COMMAND.to_le_bytes())?;
+        if C::ACKED {
+            self.status()
+                .with_context(|| format!("DA refused command {:#x}", C::COMMAND))?;
+        }
+
+        let param = cmd.param();
+        if !param.is_empty() {
+            self.send(&param)?;
+            self.status()
+                .with_context(|| format!("DA refused parameters of {:#x}", C::COMMAND))?;
+        }
+
+        Ok(())
+    }
+
+    /// Data of the device control request
+    fn device_ctrl(&mut self, ctrl: u32) -> Result<Vec<u8>> {
+        self.command(&DeviceCtrl::new(ctrl))?;
+        let data = self.recv()?;
+        self.status()?;
+        Ok(data)
+    }
+
+    /// Send the data in packets, each with its checksum (0 - not checked)
+    fn send_data(&mut self, data: &[u8], desc: String) -> Result<()> {
+        let mut pb = tqdm!(total = data.len(), desc = desc, unit = "B");
+        for chunk in data.chunks(self.write_packet_len) {
+            self.send(&0u32.to_le_bytes())?;
+            self.send(chunk)?;
+            self.status()?;
+            pb.update(chunk.len())?;
+        }
+        println!();
+
+        self.status()
+    }
+
+    /// Run DA1, let it load DA2 and find the storage
+    fn start(mut port: Port, da: &DaFile, entry: &DaEntry) -> Result<Self> {
+        run_da1(&mut port, da, entry)?;
+        if port.read_u8()? != DA_SYNC {
+            anyhow::bail!("DA1 didn't sync");
+        }
+
+        let mut xflash = Self {
+            port,
+            write_packet_len: DEFAULT_PACKET_SIZE,
+            read_packet_len: DEFAULT_PACKET_SIZE,
+            storage: None,
+        };
+        xflash.send(&SYNC_SIGNAL.to_le_bytes())?;
+        xflash.command(&SetupEnvironment::new())?;
+        xflash.command(&SetupHwInitParams::new())?;
+        if xflash.recv()? != SYNC_SIGNAL.to_le_bytes() {
+            anyhow::bail!("DA1 didn't finish its setup");
+        }
+        event!("DA1 is running");
+
+        let da2 = entry.da2()?;
+        let data = da.region_data(da2)?;
+        let len = data
+            .len()
+            .checked_sub(da2.sig_len as usize)
+            .context("DA2 signature is longer than the region")?;
+        let data = &data[..len];
+        event!("Sending DA2 to {:#x}", da2.addr);
+        xflash.command(&BootTo::new(da2.addr as u64, data.len() as u64))?;
+        xflash.send_data(data, "DA2".to_string())?;
+
+        let lengths = xflash.device_ctrl(ctrl::GET_PACKET_LENGTH)?;
+        if lengths.len() >= 8 && le32(&lengths, 0) != 0 && le32(&lengths, 4) != 0 {
+            xflash.write_packet_len = le32(&lengths, 0) as usize;
+            xflash.read_packet_len = le32(&lengths, 4) as usize;
+        }
+        if let Ok(version) = xflash.device_ctrl(ctrl::GET_DA_VERSION) {
+            event!(
+                "DA2 {} is running",
+                String::from_utf8_lossy(&version).trim_end_matches('\0')
+            );
+        }
+
+        xflash.storage = Some(xflash.identify()?);
+        Ok(xflash)
+    }
+
+    /// Ask for the eMMC first, then UFS
+    fn identify(&mut self) -> Result<Storage> {
+        let emmc = self.device_ctrl(ctrl::GET_EMMC_INFO)?;
+        if emmc.len() >= 88 && le32(&emmc, 0) != 0 {
+            return Ok(Storage::Emmc {
+                block_size: le32(&emmc, 4),
+                boot1: le64(&emmc, 8),
+                boot2: le64(&emmc, 16),
+                user: le64(&emmc, 64),
+                cid: emmc[72..88].try_into().unwrap(),
+            });
+        }
+
+        let ufs = self.device_ctrl(ctrl::GET_UFS_INFO)?;
+        if ufs.len() >= 0x20 && le32(&ufs, 0) != 0 {
+            return Ok(Storage::Ufs {
+                block_size: le32(&ufs, 4),
+                lu0: le64(&ufs, 8),
+                lu1: le64(&ufs, 16),
+                lu2: le64(&ufs, 24),
+            });
+        }
+
+        anyhow::bail!("DA found neither eMMC nor UFS")
+    }
+
+    fn storage(&self) -> &Storage {
+        self.storage
+            .as_ref()
+            .expect("storage is identified on start")
+    }
+
+    /// Read `len` bytes at `addr` of the hardware partition to `out`, returns the SHA-1 in hex
+    fn read_to(
+        &mut self,
+        part: StoragePart,
+        addr: u64,
+        len: u64,
+        out: &mut dyn Write,
+    ) -> Result<String> {
+        let kind = self.storage().kind();
+        let (code, size) = self.storage().part(part);
+        if addr.checked_add(len).is_none_or(|end| end > size) {
+            anyhow::bail!("{addr:#x}+{len:#x} is out of the {part:?} partition ({size:#x} bytes)");
+        }
+
+        self.command(&ReadData::new(kind, code, addr, len))?;
+        let mut sha = Sha1::new();
+        let mut pb = tqdm!(
+            total = len as usize,
+            desc = format!("{addr:#x}"),
+            unit = "B"
+        );
+        let mut done = 0;
+        while done < len {
+            let data = self.recv()?;
+            if data.is_empty() || data.len() > self.read_packet_len {
+                anyhow::bail!("Bad data packet length: {:#x}", data.len());
+            }
+            self.status()?;
+            self.send(&0u32.to_le_bytes())?;
+
+            out.write_all(&data)?;
+            sha.update(&data);
+            pb.update(data.len())?;
+            done += data.len() as u64;
+        }
+        println!();
+        self.status()?;
+
+        Ok(hex_digest(sha))
+    }
+
+    fn read_exact_range(&mut self, part: StoragePart, addr: u64, len: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        self.read_to(part, addr, len, &mut data)?;
+        Ok(data)
+    }
+
+    /// Partitions of the user area, addresses are in blocks
+    fn partitions(&mut self) -> Result<Vec<Partition>> {
+        let block = self.storage().block_size() as u64;
+        let header =
+            self.read_exact_range(StoragePart::User, GPT_HEADER_LBA as u64 * block, block)?;
+        let header = GptHeader::parse(&header).context("No GPT in the user area")?;
+        let entries = self.read_exact_range(
+            StoragePart::User,
+            header.entries_lba * block,
+            header.entries_size() as u64,
+        )?;
+        header.entries(&entries)
+    }
+
+    fn find_partition(&mut self, name: &str) -> Result<Partition> {
+        self.partitions()?
+            .into_iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+            .with_context(|| format!("Partition {name} is not found"))
+    }
+
+    fn info(&mut self) -> Result<()> {
+        match *self.storage() {
+            Storage::Emmc {
+                block_size,
+                boot1,
+                boot2,
+                user,
+                cid,
+            } => {
+                println!("eMMC, {block_size} byte blocks");
+                println!(
+                    "CID: {}",
+                    cid.iter().map(|b| format!("{b:02x}")).collect::<String>()
+                );
+                println!("boot1: {boot1:#x}");
+                println!("boot2: {boot2:#x}");
+                println!("user: {user:#x}");
+            }
+            Storage::Ufs {
+                block_size,
+                lu0,
+                lu1,
+                lu2,
+            } => {
+                println!("UFS, {block_size} byte blocks");
+                println!("LU0: {lu0:#x}");
+                println!("LU1: {lu1:#x}");
+                println!("LU2: {lu2:#x}");
+            }
+        }
+
+        println!();
+        println!("{:<24} {:>12} {:>12}", "Name", "Start", "Size");
+        for p in self.partitions()? {
+            println!("{:<24} {:>#12x} {:>#12x}", p.name, p.start, p.size);
+        }
+
+        Ok(())
+    }
+
+    fn read_partition(&mut self, name: &str, out: Option<&Path>) -> Result<()> {
+        let part = self.find_partition(name)?;
+        let block = self.storage().block_size() as u64;
+        let default = PathBuf::from(format!("{}.img", part.name));
+        let path = out.unwrap_or(&default);
+
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let sha = self.read_to(
+            StoragePart::User,
+            part.start * block,
+            part.size * block,
+            &mut out,
+        )?;
+        out.flush()?;
+
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut sha_path = path.as_os_str().to_owned();
+        sha_path.push(".sha1");
+        fs::write(PathBuf::from(sha_path), format!("{sha}  {file_name}\n"))?;
+
+        event!(
+            "Read partition {} to {} (SHA-1 {sha})",
+            part.name,
+            path.display()
+        );
+        Ok(())
+    }
+
+    fn write_partition(
+        &mut self,
+        name: &str,
+        image: &Path,
+        backup: Option<&Path>,
+        force: bool,
+    ) -> Result<()> {
+        let part = self.find_partition(name)?;
+        write_image(self, &part, image, backup, force)
+    }
+
+    fn reboot(&mut self) -> Result<()> {
+        self.command(&Shutdown::new())?;
+        event!("Device is rebooting");
+        Ok(())
+    }
+}
+
+impl BlockDevice for XFlash {
+    fn block_size(&self) -> u64 {
+        self.storage().block_size() as u64
+    }
+
+    fn read_to(
+        &mut self,
+        part: EmmcPart,
+        lba: u64,
+        count: u64,
+        out: &mut dyn Write,
+    ) -> Result<String> {
+        let block = self.block_size();
+        XFlash::read_to(self, part.into(), lba * block, count * block, out)
+    }
+
+    fn write(&mut self, part: EmmcPart, lba: u64, data: &[u8], desc: String) -> Result<()> {
+        let kind = self.storage().kind();
+        let (code, _) = self.storage().part(part.into());
+        let addr = lba * self.block_size();
+        self.command(&WriteData::new(kind, code, addr, data.len() as u64))?;
+        self.send_data(data, desc)
+    }
+}
+
+/// Connect to the device, start the XFlash DA from `da_path` and run `op`
+pub fn run_xflash(da_path: &Path, op: &XFlashOp) -> Result<()> {
+    let (da, port, hwcode) = connect(da_path)?;
+    let soc = SoC::try_from_hwcode(hwcode).context("Sorry, your SoC is not supported yet")?;
+    event!("SoC: {soc:?}");
+
+    let entry = find_entry(&da, hwcode)?;
+    let mut client = XFlash::start(port, &da, entry)?;
+
+    match op {
+        XFlashOp::Info => client.info(),
+        XFlashOp::ReadPartition { name, out } => client.read_partition(name, out.as_deref()),
+        XFlashOp::WritePartition {
+            name,
+            image,
+            backup,
+            force,
+        } => client.write_partition(name, image, backup.as_deref(), *force),
+        XFlashOp::Reboot => client.reboot(),
+    }
+}
@@ -9,7 +9,7 @@ use crate::err::Error;
 pub mod err;
 
 pub const MAGIC: u32 = 0xDAB001;
-pub const CURRENT_VERSION: u32 = 1;
+pub const CURRENT_VERSION: u32 = 2;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -36,7 +36,7 @@ impl MemoryRange {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct PayloadParams {
     /// Payload magic. Should be `MAGIC`
@@ -53,6 +53,8 @@ pub struct PayloadParams {
     pub ptr_ul: u32,
     /// Current SoC
     pub soc: SoC,
+    /// What the payload does with the watchdog while it waits for commands
+    pub watchdog: WatchdogPolicy,
 }
 
 const impl Default for PayloadParams {
@@ -65,6 +67,7 @@ const impl Default for PayloadParams {
             ptr_dl: 0,
             ptr_ul: 0,
             soc: SoC::MT6572,
+            watchdog: WatchdogPolicy::Keep,
         }
     }
 }
@@ -79,6 +82,7 @@ impl PayloadParams {
             ptr_dl,
             ptr_ul,
             soc,
+            watchdog: WatchdogPolicy::Keep,
         }
     }
 
@@ -177,3 +181,21 @@ const impl Default for BlacklistMode {
         Self::None
     }
 }
+
+/// Watchdog handling of the payload, the final jump restores the state the payload started with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum WatchdogPolicy {
+    /// Leave the watchdog as the previous stage set it
+    Keep,
+    /// Restart the watchdog while waiting for the host
+    Kick,
+    /// Turn the watchdog off until the final jump
+    Disable,
+}
+
+const impl Default for WatchdogPolicy {
+    fn default() -> Self {
+        Self::Keep
+    }
+}
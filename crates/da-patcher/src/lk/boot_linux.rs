@@ -0,0 +1,19 @@
+use anyhow::{Context, Result};
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor};
+
+extractor!(BootLinux);
+impl Extract for BootLinux<'_> {
+    type Value = u32;
+
+    fn extract(&self) -> Result<Self::Value> {
+        // boot_linux(kernel, tags, cmdline, machtype, ramdisk, ramdisk_size)
+        let f = self
+            .analyzer
+            .fn_by_str("booting linux @ %p, ramdisk @ %p (%d)\n")
+            .context("string not found")?;
+
+        Ok(f.start_va())
+    }
+}
@@ -1,3 +1,82 @@
+use anyhow::{Context, Result};
+use kaiko::{
+    Analyzer,
+    yaxpeax_arm::armv7::{Instruction, Opcode, Operand},
+};
+
+pub mod boot_linux;
 pub mod get_part;
 pub mod mt_part_generic_read;
+pub mod mt_part_generic_write;
 pub mod mt_part_get_partition;
+pub mod printf;
+pub mod video_printf;
+
+fn is_field_store(instruction: &Instruction, field_offset: u32) -> bool {
+    if let Operand::RegDerefPreindexOffset(_, offset, _, _) = instruction.operands[1]
+        && instruction.opcode == Opcode::STR
+    {
+        offset as u32 == field_offset
+    } else {
+        false
+    }
+}
+
+/// Find the function stored to the `part_dev_t` field at `field_offset` in `mt_part_register_device`
+fn part_dev_fn(analyzer: &Analyzer, field_offset: u32) -> Result<u32> {
+    let f = analyzer
+        .fn_by_str("[mt_part_register_device]\n")
+        .context("string not found")?;
+
+    let block = f
+        .blocks()
+        // block must NOT have POP
+        .filter(|b| !b.code().any(|c| c.instruction().opcode.is_pop()))
+        .find(|b| {
+            b.code().count() < 10
+                && b.code()
+                    .any(|c| is_field_store(&c.instruction(), field_offset))
+        })
+        .with_context(|| format!("no block with STR to {field_offset:#x}"))?;
+
+    let str = block
+        .code()
+        .find(|c| is_field_store(&c.instruction(), field_offset))
+        .context("STR must exist")?;
+    let Operand::Reg(r) = str.instruction().operands[0] else {
+        unreachable!("operand must be a reg");
+    };
+
+    let va = block
+        .regs()
+        .try_get_imm(str.va(), r.number())
+        .context("Failed to get imm")?;
+
+    Ok(va)
+}
+
+/// Find the function called with the string `s`, the closest call after its reference
+fn fn_called_with_str(analyzer: &Analyzer, s: &str) -> Result<u32> {
+    let f = analyzer.fn_by_str(s).context("string not found")?;
+    let ref_va = f
+        .blocks()
+        .find_map(|b| {
+            b.data_refs().find_map(|(code, ref_va)| {
+                (analyzer.read_cstr(ref_va).is_some_and(|r| r == s)).then_some(code.va())
+            })
+        })
+        .context("no block with the string")?;
+
+    let (_, va) = f
+        .blocks()
+        .flat_map(|b| {
+            b.fn_calls()
+                .map(|(code, va)| (code.va(), va))
+                .collect::<Vec<_>>()
+        })
+        .filter(|(code_va, _)| *code_va > ref_va)
+        .min_by_key(|(code_va, _)| *code_va)
+        .context("no call after the string")?;
+
+    Ok(va)
+}
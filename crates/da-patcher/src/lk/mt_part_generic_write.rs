@@ -0,0 +1,14 @@
+use anyhow::Result;
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor, lk::part_dev_fn};
+
+extractor!(MtPartGenericWrite);
+impl Extract for MtPartGenericWrite<'_> {
+    type Value = u32;
+
+    fn extract(&self) -> Result<Self::Value> {
+        // write = 0x14, right after the read
+        part_dev_fn(self.analyzer, 0x14)
+    }
+}
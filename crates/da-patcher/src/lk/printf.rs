@@ -0,0 +1,15 @@
+use anyhow::Result;
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor, lk::fn_called_with_str};
+
+extractor!(LkPrintf);
+impl Extract for LkPrintf<'_> {
+    type Value = u32;
+
+    /// `_dprintf(fmt, ...)` behind `dprintf`, LK logs to the UART through it
+    fn extract(&self) -> Result<Self::Value> {
+        // dprintf(INFO, "booting linux @ %p, ramdisk @ %p (%d)\n", ...) in boot_linux
+        fn_called_with_str(self.analyzer, "booting linux @ %p, ramdisk @ %p (%d)\n")
+    }
+}
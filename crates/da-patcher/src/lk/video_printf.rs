@@ -0,0 +1,15 @@
+use anyhow::Result;
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor, lk::fn_called_with_str};
+
+extractor!(VideoPrintf);
+impl Extract for VideoPrintf<'_> {
+    type Value = u32;
+
+    /// `video_printf(fmt, ...)` printing on the framebuffer console
+    fn extract(&self) -> Result<Self::Value> {
+        // video_printf(" => FASTBOOT mode...\n") when entering fastboot
+        fn_called_with_str(self.analyzer, " => FASTBOOT mode...\n")
+    }
+}
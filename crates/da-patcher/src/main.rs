@@ -7,10 +7,14 @@ use da_cli_ext::{maybe_image, maybe_preloader};
 use da_patcher::{
     Extract,
     lk::{
-        get_part::GetPart, mt_part_generic_read::MtPartGenericRead,
-        mt_part_get_partition::MtPartGetPartition,
+        boot_linux::BootLinux, get_part::GetPart, mt_part_generic_read::MtPartGenericRead,
+        mt_part_generic_write::MtPartGenericWrite, mt_part_get_partition::MtPartGetPartition,
+        printf::LkPrintf, video_printf::VideoPrintf,
+    },
+    preloader::{
+        bldr_jump::BldrJump, bldr_load_part::BldrLoadPart, bldr_resume::BldrResume,
+        blkdev_get::BlkdevGet, lk_base::LKBase, usb_ptr::PreloaderDLULPtr,
     },
-    preloader::{bldr_jump::BldrJump, lk_base::LKBase, usb_ptr::PreloaderDLULPtr},
 };
 use kaiko::Analyzer;
 
@@ -63,6 +67,24 @@ fn main() -> Result<()> {
                 .extract()
                 .context("error on extracting bldr_jump ptr")?;
             println!("bldr_jump: {bldr_jump:#x}, DA DRAM addr: {da_addr:#x}");
+
+            match BldrLoadPart::new(&analyzer).extract() {
+                Ok(v) => println!("bldr_load_part: {v:#x}"),
+                Err(e) => eprintln!("failed to find bldr_load_part: {e:?}"),
+            }
+
+            match BldrResume::new(&analyzer).extract() {
+                Ok(v) => println!(
+                    "bldr_load_images: {:#x}, bldr_post_process: {:#x}, boot argument: {:#x} ({:#x} bytes)",
+                    v.load_images, v.post_process, v.bootarg_addr, v.bootarg_size
+                ),
+                Err(e) => eprintln!("failed to find the boot flow after the handshake: {e:?}"),
+            }
+
+            match BlkdevGet::new(&analyzer).extract() {
+                Ok((f, dev)) => println!("blkdev_get: {f:#x}, boot device: {dev}"),
+                Err(e) => eprintln!("failed to find blkdev_get: {e:?}"),
+            }
         }
         Mode::LK => {
             let content = maybe_image(&data)
@@ -87,6 +109,26 @@ fn main() -> Result<()> {
                 Ok(v) => println!("mt_part_generic_read: {v:#x}"),
                 Err(e) => eprintln!("failed to find mt_part_generic_read: {e:?}"),
             }
+
+            match MtPartGenericWrite::new(&analyzer).extract() {
+                Ok(v) => println!("mt_part_generic_write: {v:#x}"),
+                Err(e) => eprintln!("failed to find mt_part_generic_write: {e:?}"),
+            }
+
+            match BootLinux::new(&analyzer).extract() {
+                Ok(v) => println!("boot_linux: {v:#x}"),
+                Err(e) => eprintln!("failed to find boot_linux: {e:?}"),
+            }
+
+            match LkPrintf::new(&analyzer).extract() {
+                Ok(v) => println!("LK printf: {v:#x}"),
+                Err(e) => eprintln!("failed to find LK printf: {e:?}"),
+            }
+
+            match VideoPrintf::new(&analyzer).extract() {
+                Ok(v) => println!("video_printf: {v:#x}"),
+                Err(e) => eprintln!("failed to find video_printf: {e:?}"),
+            }
         }
     }
 
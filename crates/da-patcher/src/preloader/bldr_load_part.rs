@@ -0,0 +1,16 @@
+use anyhow::{Context, Result};
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor, preloader::lk_load};
+
+extractor!(BldrLoadPart);
+impl Extract for BldrLoadPart<'_> {
+    type Value = u32;
+
+    /// The first call after the partition name is the loader, it takes LK base in r3
+    fn extract(&self) -> Result<Self::Value> {
+        lk_load(self.analyzer)?
+            .load_part
+            .context("no call after the partition name")
+    }
+}
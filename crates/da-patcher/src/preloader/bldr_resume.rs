@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use kaiko::Analyzer;
+
+use crate::{
+    Extract, extractor,
+    preloader::{LOAD_FAILED, bldr_jump::BldrJump, lk_load},
+};
+
+/// The rest of the preloader boot flow after the handshake, as done by its `main`
+pub struct BootFlow {
+    /// `bldr_load_images(u32 *jump_addr)`, returns 0 once LK is loaded
+    pub load_images: u32,
+    /// `bldr_post_process()`, the platform post init filling the boot argument
+    pub post_process: u32,
+    /// Boot argument passed to `bldr_jump` along with LK
+    pub bootarg_addr: u32,
+    /// Size of the boot argument
+    pub bootarg_size: u32,
+}
+
+extractor!(BldrResume);
+impl Extract for BldrResume<'_> {
+    type Value = BootFlow;
+
+    /// `main` does `bldr_load_images(&jump_addr)`, `bldr_post_process()` and `bldr_jump(jump_addr,
+    /// bootarg, size)`, each of them is called from the payload in the same order
+    fn extract(&self) -> Result<Self::Value> {
+        let load_images = lk_load(self.analyzer)?.fn_va;
+        let (bldr_jump, _) = BldrJump::new(self.analyzer).extract()?;
+
+        let main = self
+            .analyzer
+            .fn_by_str(LOAD_FAILED)
+            .context("preloader main fn not found")?;
+        if main.start_va() == load_images {
+            anyhow::bail!("LK is loaded right in main, there's no function to call");
+        }
+
+        // the failure message is printed in its own block, its calls are not on the way
+        let calls: Vec<_> = main
+            .blocks()
+            .filter(|b| {
+                !b.data_refs().any(|(_, ref_va)| {
+                    self.analyzer
+                        .read_cstr(ref_va)
+                        .is_some_and(|s| s == LOAD_FAILED)
+                })
+            })
+            .flat_map(|b| {
+                b.fn_calls()
+                    .map(|(code, va)| {
+                        (
+                            code.va(),
+                            va,
+                            b.regs().try_get_imm(code.va(), 1),
+                            b.regs().try_get_imm(code.va(), 2),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let load_va = calls
+            .iter()
+            .find(|(_, va, ..)| *va == load_images)
+            .map(|(code_va, ..)| *code_va)
+            .context("no bldr_load_images call in main")?;
+        let post_process = calls
+            .iter()
+            .filter(|(code_va, ..)| *code_va > load_va)
+            .min_by_key(|(code_va, ..)| *code_va)
+            .map(|(_, va, ..)| *va)
+            .context("no call after bldr_load_images")?;
+        let (bootarg_addr, bootarg_size) = calls
+            .iter()
+            .find_map(|&(code_va, va, r1, r2)| {
+                (code_va > load_va && va == bldr_jump).then_some((r1?, r2?))
+            })
+            .context("boot argument of bldr_jump is unknown")?;
+
+        if post_process == bldr_jump {
+            anyhow::bail!("no post process call before bldr_jump");
+        }
+
+        Ok(BootFlow {
+            load_images,
+            post_process,
+            bootarg_addr,
+            bootarg_size,
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use kaiko::Analyzer;
+
+use crate::{Extract, extractor};
+
+const NO_BOOT_DEVICE: &str = "%s can't find boot device(%d)\n";
+
+extractor!(BlkdevGet);
+impl Extract for BlkdevGet<'_> {
+    type Value = (u32, u32);
+
+    /// `blkdev_get` and the boot device type the preloader passes to it
+    fn extract(&self) -> Result<Self::Value> {
+        let f = self
+            .analyzer
+            .fn_by_str(NO_BOOT_DEVICE)
+            .context("string not found")?;
+        let msg_va = f
+            .blocks()
+            .find_map(|b| {
+                b.data_refs().find_map(|(code, ref_va)| {
+                    (self
+                        .analyzer
+                        .read_cstr(ref_va)
+                        .is_some_and(|s| s == NO_BOOT_DEVICE))
+                    .then_some(code.va())
+                })
+            })
+            .context("no block with the boot device string")?;
+
+        // if (!(bootdev = blkdev_get(CFG_BOOT_DEV))) print(...), the closest call before
+        let (call_va, blkdev_get) = f
+            .blocks()
+            .flat_map(|b| {
+                b.fn_calls()
+                    .map(|(code, va)| (code.va(), va))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|(code_va, _)| *code_va < msg_va)
+            .max_by_key(|(code_va, _)| *code_va)
+            .context("no call before the boot device string")?;
+
+        let boot_dev = f
+            .blocks()
+            .find(|b| b.code().any(|c| c.va() == call_va))
+            .and_then(|b| b.regs().try_get_imm(call_va, 0))
+            .context("boot device type is unknown")?;
+
+        Ok((blkdev_get, boot_dev))
+    }
+}
@@ -1,3 +1,51 @@
 pub mod bldr_jump;
+pub mod bldr_load_part;
+pub mod bldr_resume;
+pub mod blkdev_get;
 pub mod lk_base;
 pub mod usb_ptr;
+
+use anyhow::{Context, Result};
+use kaiko::Analyzer;
+
+/// Printed by `main` if LK can't be loaded
+pub(crate) const LOAD_FAILED: &str = "%s Second Bootloader Load Failed";
+
+/// Where the preloader loads LK
+pub(crate) struct LkLoad {
+    /// Start of the function loading LK
+    pub fn_va: u32,
+    /// LK base, the load method takes it in r3
+    pub base: Option<u32>,
+    /// The loader, the first call after the partition name
+    pub load_part: Option<u32>,
+}
+
+/// Find the block loading LK in the preloader main
+pub(crate) fn lk_load(analyzer: &Analyzer) -> Result<LkLoad> {
+    let f = analyzer
+        .fn_by_str("UBOOT")
+        .or_else(|| analyzer.fn_by_str(LOAD_FAILED))
+        .context("preloader main fn not found")?;
+
+    f.blocks()
+        .find_map(|b| {
+            let code_va = b.data_refs().find_map(|(code, ref_va)| {
+                (analyzer
+                    .read_cstr(ref_va)
+                    .is_some_and(|s| s == "UBOOT" || s == "lk"))
+                .then_some(code.va())
+            })?;
+
+            Some(LkLoad {
+                fn_va: f.start_va(),
+                // use code_va before fn call
+                base: b.regs().try_get_imm(code_va, 3),
+                load_part: b
+                    .fn_calls()
+                    .find(|(code, _)| code.va() > code_va)
+                    .map(|(_, va)| va),
+            })
+        })
+        .context("no block with parition string")
+}
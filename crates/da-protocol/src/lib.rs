@@ -6,35 +6,123 @@ use core::{borrow::Borrow, fmt::Display};
 
 use derive_ctor::ctor;
 use derive_more::IsVariant;
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 use simpleport::{SimpleRead, SimpleWrite};
 
-use crate::err::Error;
+use crate::{err::Error, reset::ResetTarget};
 
 pub mod err;
+pub mod reset;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, MaxSize)]
 pub enum HookId {
-    /// Allow booting boot.img or recovery.img from the RAM
+    /// Serve partitions from the RAM, e.g. for booting boot.img or recovery.img
     MtPartGenericRead,
+    /// Apply the write policy to the storage writes
+    MtPartGenericWrite,
+    /// Load LK from the RAM through the preloader boot flow
+    BldrLoadPart,
+    /// Override the kernel command line composed by LK
+    BootLinux,
+    /// Mirror the LK log to the screen through `video_printf`
+    LkScreenLog,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
 #[repr(C)]
 pub struct PreloaderRunnerParams {
     /// `bldr_jump` function pointer (for call)
     pub ptr_bldr_jump: u32,
+    /// `bldr_load_part` function pointer (for hook)
+    pub ptr_bldr_load_part: u32,
+    /// Address the preloader loads LK to
+    pub lk_load_addr: u32,
+    /// Address of the uploaded LK image
+    pub lk_image_addr: u32,
+    /// Size of the uploaded LK image
+    pub lk_image_size: u32,
+    /// `bldr_load_images` function pointer (for call)
+    pub ptr_bldr_load_images: u32,
+    /// `bldr_post_process` function pointer (for call)
+    pub ptr_bldr_post_process: u32,
+    /// Boot argument passed to LK through `bldr_jump`
+    pub bootarg_addr: u32,
+    /// Size of the boot argument
+    pub bootarg_size: u32,
+    /// `blkdev_get` function pointer (for call)
+    pub ptr_blkdev_get: u32,
+    /// Boot device type passed to `blkdev_get`
+    pub boot_dev: u32,
+    /// Buffer for the block transfers
+    pub scratch_addr: u32,
+    /// Size of the block transfer buffer
+    pub scratch_size: u32,
 }
 
 const impl Default for PreloaderRunnerParams {
     fn default() -> Self {
-        Self { ptr_bldr_jump: 0 }
+        Self {
+            ptr_bldr_jump: 0,
+            ptr_bldr_load_part: 0,
+            lk_load_addr: 0,
+            lk_image_addr: 0,
+            lk_image_size: 0,
+            ptr_bldr_load_images: 0,
+            ptr_bldr_post_process: 0,
+            bootarg_addr: 0,
+            bootarg_size: 0,
+            ptr_blkdev_get: 0,
+            boot_dev: 0,
+            scratch_addr: 0,
+            scratch_size: 0,
+        }
     }
 }
 
 impl PreloaderRunnerParams {
     pub fn new(ptr_bldr_jump: u32) -> Self {
-        Self { ptr_bldr_jump }
+        Self {
+            ptr_bldr_jump,
+            ..Default::default()
+        }
+    }
+
+    /// Let the preloader load LK from the RAM instead of the storage
+    pub fn set_lk_image(&mut self, ptr_bldr_load_part: u32, load_addr: u32, image: Range<u32>) {
+        self.ptr_bldr_load_part = ptr_bldr_load_part;
+        self.lk_load_addr = load_addr;
+        self.lk_image_addr = image.start;
+        self.lk_image_size = image.end - image.start;
+    }
+
+    /// Finish the boot flow the way the preloader `main` does after the handshake
+    pub fn set_boot_flow(
+        &mut self,
+        ptr_bldr_load_images: u32,
+        ptr_bldr_post_process: u32,
+        bootarg: Range<u32>,
+    ) {
+        self.ptr_bldr_load_images = ptr_bldr_load_images;
+        self.ptr_bldr_post_process = ptr_bldr_post_process;
+        self.bootarg_addr = bootarg.start;
+        self.bootarg_size = bootarg.end - bootarg.start;
+    }
+
+    pub fn has_boot_flow(&self) -> bool {
+        self.ptr_bldr_load_images != 0 && self.ptr_bldr_post_process != 0 && self.bootarg_addr != 0
+    }
+
+    /// Access the boot device through the preloader storage driver, using `scratch` for transfers
+    pub fn set_blkdev(&mut self, ptr_blkdev_get: u32, boot_dev: u32, scratch: Range<u32>) {
+        self.ptr_blkdev_get = ptr_blkdev_get;
+        self.boot_dev = boot_dev;
+        self.scratch_addr = scratch.start;
+        self.scratch_size = scratch.end - scratch.start;
+    }
+
+    pub fn has_blkdev(&self) -> bool {
+        self.ptr_blkdev_get != 0 && self.scratch_size >= BLOCK_SIZE
     }
 
     pub fn is_valid(&self) -> bool {
@@ -42,15 +130,112 @@ impl PreloaderRunnerParams {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Block size of the boot device, the only one supported for the block transfers
+pub const BLOCK_SIZE: u32 = 512;
+
+/// eMMC hardware partition, as numbered by the MediaTek storage driver
+///
+/// Linux calls the boot partitions `mmcblk0boot0` and `mmcblk0boot1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+#[repr(u32)]
+pub enum EmmcPart {
+    /// First boot partition, holds the preloader
+    Boot1 = 1,
+    /// Second boot partition
+    Boot2 = 2,
+    /// User data area with the partition table
+    User = 8,
+}
+
+/// Max number of partitions served from the RAM
+pub const MAX_RAM_PARTITIONS: usize = 8;
+/// Max partition name length, including NUL
+pub const RAM_PARTITION_NAME_LEN: usize = 16;
+
+/// Partition which content is served from the RAM instead of the storage
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
+#[repr(C)]
+pub struct RamPartition {
+    /// NUL-padded partition name, as known by LK
+    pub name: [u8; RAM_PARTITION_NAME_LEN],
+    /// Address of the content
+    pub addr: u32,
+    /// Size of the content, zero if the slot is unused
+    pub size: u32,
+}
+
+const impl Default for RamPartition {
+    fn default() -> Self {
+        Self {
+            name: [0; RAM_PARTITION_NAME_LEN],
+            addr: 0,
+            size: 0,
+        }
+    }
+}
+
+impl RamPartition {
+    /// Returns `None` if the name doesn't fit
+    pub fn new(name: &str, addr: u32, size: u32) -> Option<Self> {
+        if name.is_empty() || name.len() >= RAM_PARTITION_NAME_LEN {
+            return None;
+        }
+
+        let mut part = Self {
+            addr,
+            size,
+            ..Default::default()
+        };
+        part.name[..name.len()].copy_from_slice(name.as_bytes());
+        Some(part)
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.size != 0
+    }
+}
+
+/// What to do with the storage writes done by LK
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+#[repr(u32)]
+pub enum WritePolicy {
+    /// Pretend the write succeed
+    Discard,
+    /// Keep the written data in the RAM overlay, later reads see it
+    Overlay,
+    /// Log the write and pass it to the storage
+    Log,
+}
+
+#[derive(Debug, Serialize, Deserialize, MaxSize)]
 #[repr(C)]
 pub struct LKRunnerParams {
     /// `mt_part_generic_read` function pointer (for hook)
     pub ptr_mt_part_generic_read: u32,
     /// `mt_part_get_partition` function pointer (for call)
     pub ptr_mt_part_get_partition: u32,
-    /// Address for the boot.img
-    pub bootimg_scratch_addr: u32,
+    /// Offset of the start sector in the struct returned by `mt_part_get_partition`
+    pub part_start_offset: u32,
+    /// Partitions served from the RAM
+    pub ram_partitions: [RamPartition; MAX_RAM_PARTITIONS],
+    /// `mt_part_generic_write` function pointer (for hook)
+    pub ptr_mt_part_generic_write: u32,
+    /// Storage write policy, writes are untouched if not set
+    pub write_policy: Option<WritePolicy>,
+    /// RAM overlay address for the `WritePolicy::Overlay`
+    pub overlay_addr: u32,
+    /// RAM overlay size
+    pub overlay_size: u32,
+    /// `boot_linux` function pointer (for hook)
+    pub ptr_boot_linux: u32,
+    /// Address of the NUL-terminated command line passed to the kernel
+    pub cmdline_addr: u32,
+    /// Replace the LK command line instead of appending to it
+    pub cmdline_replace: bool,
+    /// LK print function pointer (for hook)
+    pub ptr_lk_printf: u32,
+    /// `video_printf` function pointer (for call)
+    pub ptr_video_printf: u32,
 }
 
 const impl Default for LKRunnerParams {
@@ -58,7 +243,17 @@ const impl Default for LKRunnerParams {
         Self {
             ptr_mt_part_generic_read: 0,
             ptr_mt_part_get_partition: 0,
-            bootimg_scratch_addr: 0,
+            part_start_offset: 0,
+            ram_partitions: [Default::default(); MAX_RAM_PARTITIONS],
+            ptr_mt_part_generic_write: 0,
+            write_policy: None,
+            overlay_addr: 0,
+            overlay_size: 0,
+            ptr_boot_linux: 0,
+            cmdline_addr: 0,
+            cmdline_replace: false,
+            ptr_lk_printf: 0,
+            ptr_video_printf: 0,
         }
     }
 }
@@ -67,23 +262,77 @@ impl LKRunnerParams {
     pub fn new(
         ptr_mt_part_generic_read: u32,
         ptr_mt_part_get_partition: u32,
-        bootimg_scratch_addr: u32,
+        part_start_offset: u32,
     ) -> Self {
         Self {
             ptr_mt_part_generic_read,
             ptr_mt_part_get_partition,
-            bootimg_scratch_addr,
+            part_start_offset,
+            ..Default::default()
         }
     }
 
+    /// Serve `part` from the RAM. Returns `false` if the table is full
+    pub fn map_partition(&mut self, part: RamPartition) -> bool {
+        if let Some(slot) = self.ram_partitions.iter_mut().find(|p| !p.is_mapped()) {
+            *slot = part;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Apply `policy` to the writes done through `mt_part_generic_write`
+    pub fn set_write_policy(&mut self, ptr_mt_part_generic_write: u32, policy: WritePolicy) {
+        self.ptr_mt_part_generic_write = ptr_mt_part_generic_write;
+        self.write_policy = Some(policy);
+    }
+
+    /// Set RAM overlay used with the `WritePolicy::Overlay`
+    pub fn set_overlay(&mut self, range: Range<u32>) {
+        self.overlay_addr = range.start;
+        self.overlay_size = range.end - range.start;
+    }
+
+    /// Pass the command line at `cmdline_addr` to the kernel through the `boot_linux` hook
+    pub fn set_cmdline(&mut self, ptr_boot_linux: u32, cmdline_addr: u32, replace: bool) {
+        self.ptr_boot_linux = ptr_boot_linux;
+        self.cmdline_addr = cmdline_addr;
+        self.cmdline_replace = replace;
+    }
+
+    /// Print everything LK logs on the screen too
+    pub fn set_screen_log(&mut self, ptr_lk_printf: u32, ptr_video_printf: u32) {
+        self.ptr_lk_printf = ptr_lk_printf;
+        self.ptr_video_printf = ptr_video_printf;
+    }
+
+    /// Iterate over the mapped partitions
+    pub fn mapped_partitions(&self) -> impl Iterator<Item = &RamPartition> {
+        self.ram_partitions.iter().filter(|p| p.is_mapped())
+    }
+
     pub fn is_valid(&self) -> bool {
+        let write_policy_valid = match self.write_policy {
+            None => true,
+            Some(WritePolicy::Overlay) => {
+                self.ptr_mt_part_generic_write != 0 && self.overlay_size != 0
+            }
+            Some(_) => self.ptr_mt_part_generic_write != 0,
+        };
+        let cmdline_valid = self.ptr_boot_linux == 0 || self.cmdline_addr != 0;
+        let screen_log_valid = (self.ptr_lk_printf == 0) == (self.ptr_video_printf == 0);
+
         self.ptr_mt_part_generic_read != 0
             && self.ptr_mt_part_get_partition != 0
-            && self.bootimg_scratch_addr != 0
+            && self.mapped_partitions().next().is_some()
+            && write_policy_valid
+            && cmdline_valid
+            && screen_log_valid
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, MaxSize)]
 pub enum ParamsType {
     /// Preloader params
     Preloader(PreloaderRunnerParams),
@@ -92,7 +341,7 @@ pub enum ParamsType {
 }
 
 /// Protocol messages
-#[derive(ctor, Serialize, Deserialize, IsVariant)]
+#[derive(ctor, Serialize, Deserialize, IsVariant, MaxSize)]
 #[repr(u8)]
 pub enum Message {
     /// Heartbeat.
@@ -109,8 +358,8 @@ pub enum Message {
         r0: Option<u32>,
         r1: Option<u32>,
     },
-    /// Reset the device using watchdog.
-    Reset,
+    /// Reset the device using watchdog, booting into `target`.
+    Reset { target: ResetTarget },
     /// Setup hook
     Hook(HookId),
     /// Get free memory range with `size`
@@ -119,9 +368,41 @@ pub enum Message {
     BlacklistRange(Range<u32>),
     /// Set params with a given type
     SetParams(ParamsType),
+    /// Load the images and finish the preloader boot flow, installed hooks stay active.
+    /// Nack if the boot flow is unknown or loading fails, nothing is answered after the Ack.
+    Continue,
+    /// Boot Linux kernel at `addr`: disable MMU and caches, then jump in **ARM** mode
+    /// with r0 = 0, r1 = `machine_id` and r2 = `tags` (DTB or ATAG list)
+    BootKernel {
+        addr: u32,
+        machine_id: u32,
+        tags: u32,
+    },
+    /// Read `count` blocks at `lba` of the eMMC `part` through the preloader storage driver.
+    /// The response comes first, the data follows it on success, then the final response.
+    BlockRead {
+        lba: u32,
+        count: u32,
+        part: EmmcPart,
+    },
+    /// Get the number of blocks in the eMMC user area
+    BlockCount,
+    /// Write `count` blocks at `lba` of the eMMC `part` through the preloader storage driver.
+    /// The data is sent after the first response, then the final response comes.
+    BlockWrite {
+        lba: u32,
+        count: u32,
+        part: EmmcPart,
+    },
+    /// Turn the watchdog off
+    WatchdogDisable,
+    /// Turn the watchdog on, the device resets if nothing kicks it for `timeout_ms`
+    WatchdogEnable { timeout_ms: u32 },
+    /// Restart the watchdog countdown
+    WatchdogKick,
 }
 
-#[derive(Debug, Serialize, Deserialize, IsVariant)]
+#[derive(Debug, Serialize, Deserialize, IsVariant, MaxSize)]
 pub enum ProtocolError {
     /// Command is not supported
     NotSupported,
@@ -131,10 +412,12 @@ pub enum ProtocolError {
     DownloadForbidden,
     /// Parameters are not valid.
     InvalidParams,
+    /// Storage driver returned an error.
+    Storage,
 }
 
 /// Protocol responses
-#[derive(Debug, ctor, Serialize, Deserialize, IsVariant)]
+#[derive(Debug, ctor, Serialize, Deserialize, IsVariant, MaxSize)]
 #[repr(u8)]
 pub enum Response {
     /// Operation succeed.
@@ -143,9 +426,12 @@ pub enum Response {
     Nack(ProtocolError),
     /// Range address.
     Range(Option<u32>),
+    /// Number of blocks.
+    BlockCount(u32),
 }
 
-const BUF_SIZE: usize = size_of::<Message>().max(size_of::<Response>());
+/// Largest postcard encoding of a message or a response
+const BUF_SIZE: usize = Message::POSTCARD_MAX_SIZE.max(Response::POSTCARD_MAX_SIZE);
 
 /// `da-boot` protocol to communicate between host and device
 ///
@@ -232,6 +518,10 @@ impl Display for HookId {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::MtPartGenericRead => write!(f, "mt_part_generic_read"),
+            Self::MtPartGenericWrite => write!(f, "mt_part_generic_write"),
+            Self::BldrLoadPart => write!(f, "bldr_load_part"),
+            Self::BootLinux => write!(f, "boot_linux"),
+            Self::LkScreenLog => write!(f, "lk_screen_log"),
         }
     }
 }
@@ -266,13 +556,34 @@ impl Display for Message {
                 }
                 Ok(())
             }
-            Self::Reset => write!(f, "Reset"),
+            Self::Reset { target } => write!(f, "Reset to {target:?}"),
             Self::Hook(hook) => write!(f, "Hook: {hook}"),
             Self::GetFreeRange { size } => write!(f, "Get free range with {size:#x} bytes"),
             Self::BlacklistRange(range) => {
                 write!(f, "Blacklist range {:#x}..{:#x}", range.start, range.end)
             }
             Self::SetParams(params) => write!(f, "Set params for the {params}"),
+            Self::Continue => write!(f, "Continue boot"),
+            Self::BootKernel {
+                addr,
+                machine_id,
+                tags,
+            } => write!(
+                f,
+                "Boot kernel at {addr:#10x}, machine ID {machine_id:#x}, tags at {tags:#10x}"
+            ),
+            Self::BlockRead { lba, count, part } => {
+                write!(f, "Read {count} blocks at LBA {lba:#x} of {part:?}")
+            }
+            Self::BlockCount => write!(f, "Get block count"),
+            Self::BlockWrite { lba, count, part } => {
+                write!(f, "Write {count} blocks at LBA {lba:#x} of {part:?}")
+            }
+            Self::WatchdogDisable => write!(f, "Disable watchdog"),
+            Self::WatchdogEnable { timeout_ms } => {
+                write!(f, "Enable watchdog with {timeout_ms} ms timeout")
+            }
+            Self::WatchdogKick => write!(f, "Kick watchdog"),
         }
     }
 }
@@ -284,6 +595,7 @@ impl Display for ProtocolError {
             Self::Unreachable => write!(f, "Unreachable"),
             Self::DownloadForbidden => write!(f, "Download forbidden"),
             Self::InvalidParams => write!(f, "Invalid parameters"),
+            Self::Storage => write!(f, "Storage error"),
         }
     }
 }
@@ -300,6 +612,7 @@ impl Display for Response {
                     write!(f, "Free range list is exhaustd")
                 }
             }
+            Self::BlockCount(count) => write!(f, "{count} blocks"),
         }
     }
 }
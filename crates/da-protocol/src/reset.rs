@@ -0,0 +1,371 @@
+//! Boot mode selection for the next watchdog reset, shared by the payload and the host
+
+use acon::SoC;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// Key unlocking the USBDL flag and the reset control of security AO
+const MISC_LOCK_KEY: u32 = 0xad98;
+/// Reset control register, after the lock register
+const RST_CON: u32 = 0x8;
+/// Magic of the USBDL flag, the BootROM ignores the flag without it
+const USBDL_MAGIC: u32 = 0x444c0000;
+const USBDL_EN: u32 = 1 << 0;
+/// Download by the preloader, the BootROM jumps to it instead of waiting
+const USBDL_BOOTLOADER: u32 = 1 << 1;
+/// Timeout field from bit 2, all ones waits forever
+const USBDL_NO_TIMEOUT: u32 = 0x3fff << 2;
+
+/// PMIC wrapper channel 2 command, `write << 31 | (addr >> 1) << 16 | data`
+const WACS2_CMD: u32 = 0x9c;
+const WACS2_WRITE: u32 = 1 << 31;
+/// Read data in the low half, the channel state from bit 16
+const WACS2_RDATA: u32 = 0xa0;
+const WACS2_VLDCLR: u32 = 0xa4;
+const WACS_FSM_SHIFT: u32 = 16;
+const WACS_FSM_MASK: u32 = 0x7;
+const WACS_FSM_IDLE: u32 = 0;
+/// Read data is valid until `WACS2_VLDCLR` is written
+const WACS_FSM_WFVLDCLR: u32 = 6;
+/// Polls of the channel state before giving up, the wrapper isn't set up by the BootROM
+const WACS_POLLS: u32 = 10000;
+
+/// RTC registers of the PMIC
+const RTC_BBPU: u32 = 0x0;
+const RTC_BBPU_CBUSY: u32 = 1 << 6;
+const RTC_PDN1: u32 = 0x2c;
+const RTC_PROT: u32 = 0x36;
+const RTC_PROT_UNLOCK: [u32; 2] = [0x586a, 0x9136];
+const RTC_WRTGR: u32 = 0x3c;
+/// Spare bits of `RTC_PDN1` checked by LK, `rtc_mark_recovery` and `rtc_mark_fast`
+const RTC_PDN1_RECOVERY_MASK: u32 = 0x3 << 4;
+const RTC_PDN1_RECOVERY: u32 = 1 << 4;
+const RTC_PDN1_FAST_BOOT: u32 = 1 << 7;
+
+/// Where the device ends up after the reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum ResetTarget {
+    /// Stock boot flow
+    Normal,
+    /// BootROM download mode, like shorting KCOL0
+    Brom,
+    /// Preloader download mode, the preloader waits for the tool without a timeout
+    Download,
+    /// Recovery through LK
+    Recovery,
+    /// Fastboot through LK
+    Fastboot,
+}
+
+/// USBDL flag (`BOOT_MISC0`) and `MISC_LOCK_KEY` of security AO, from the preloader sources
+fn usbdl(soc: SoC) -> Option<(u32, u32)> {
+    match soc {
+        SoC::MT6572 | SoC::MT6582 => Some((0x10001680, 0x1000141c)),
+        SoC::MT6595 => Some((0x1001a080, 0x1001a100)),
+        _ => None,
+    }
+}
+
+/// PMIC wrapper and the RTC base in the PMIC, where LK reads the recovery and fastboot marks
+fn rtc(soc: SoC) -> Option<(u32, u32)> {
+    match soc {
+        // MT6323 PMIC
+        SoC::MT6572 | SoC::MT6582 => Some((0x1000f000, 0x8000)),
+        _ => None,
+    }
+}
+
+/// Register access of the side doing the reset
+pub trait Registers {
+    type Error;
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Self::Error>;
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Self::Error>;
+}
+
+/// PMIC registers through the wrapper channel 2, `None` if the channel doesn't answer
+struct Pmic<'a, R> {
+    regs: &'a mut R,
+    pwrap: u32,
+}
+
+impl<R: Registers> Pmic<'_, R> {
+    fn wait_fsm(&mut self, state: u32) -> Result<Option<u32>, R::Error> {
+        for _ in 0..WACS_POLLS {
+            let value = self.regs.read32(self.pwrap + WACS2_RDATA)?;
+            if (value >> WACS_FSM_SHIFT) & WACS_FSM_MASK == state {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn read16(&mut self, addr: u32) -> Result<Option<u32>, R::Error> {
+        if self.wait_fsm(WACS_FSM_IDLE)?.is_none() {
+            return Ok(None);
+        }
+
+        self.regs
+            .write32(self.pwrap + WACS2_CMD, (addr >> 1) << 16)?;
+        let Some(value) = self.wait_fsm(WACS_FSM_WFVLDCLR)? else {
+            return Ok(None);
+        };
+        self.regs.write32(self.pwrap + WACS2_VLDCLR, 1)?;
+        Ok(Some(value & 0xffff))
+    }
+
+    fn write16(&mut self, addr: u32, value: u32) -> Result<Option<()>, R::Error> {
+        if self.wait_fsm(WACS_FSM_IDLE)?.is_none() {
+            return Ok(None);
+        }
+
+        self.regs.write32(
+            self.pwrap + WACS2_CMD,
+            WACS2_WRITE | ((addr >> 1) << 16) | (value & 0xffff),
+        )?;
+        Ok(Some(()))
+    }
+
+    /// Write an RTC register and commit it to the RTC domain
+    fn rtc_write(&mut self, rtc: u32, reg: u32, value: u32) -> Result<Option<()>, R::Error> {
+        if self.write16(rtc + reg, value)?.is_none() || self.write16(rtc + RTC_WRTGR, 1)?.is_none()
+        {
+            return Ok(None);
+        }
+
+        for _ in 0..WACS_POLLS {
+            match self.read16(rtc + RTC_BBPU)? {
+                Some(bbpu) if bbpu & RTC_BBPU_CBUSY == 0 => return Ok(Some(())),
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Set `bits` of `RTC_PDN1` after clearing `mask`, the way LK marks the boot mode
+    fn mark(&mut self, rtc: u32, mask: u32, bits: u32) -> Result<Option<()>, R::Error> {
+        for key in RTC_PROT_UNLOCK {
+            if self.rtc_write(rtc, RTC_PROT, key)?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let Some(pdn1) = self.read16(rtc + RTC_PDN1)? else {
+            return Ok(None);
+        };
+        self.rtc_write(rtc, RTC_PDN1, (pdn1 & !mask) | bits)
+    }
+}
+
+impl ResetTarget {
+    /// Download targets need the USBDL flag, recovery and fastboot the RTC of the PMIC,
+    /// both known only for some SoCs
+    pub fn is_supported(self, soc: SoC) -> bool {
+        match self {
+            Self::Normal => true,
+            Self::Brom | Self::Download => usbdl(soc).is_some(),
+            Self::Recovery | Self::Fastboot => rtc(soc).is_some(),
+        }
+    }
+
+    /// Set the flags for the next watchdog reset, the caller resets the device after it.
+    ///
+    /// Returns `false` if the flags aren't set: the target isn't supported on `soc` or the
+    /// PMIC wrapper doesn't answer. Don't reset then, the device would just boot normally.
+    pub fn prepare<R: Registers>(self, regs: &mut R, soc: SoC) -> Result<bool, R::Error> {
+        match self {
+            Self::Normal => Ok(true),
+            Self::Brom | Self::Download => {
+                let Some((flag, lock)) = usbdl(soc) else {
+                    return Ok(false);
+                };
+
+                let mut value = USBDL_MAGIC | USBDL_NO_TIMEOUT | USBDL_EN;
+                if self == Self::Download {
+                    value |= USBDL_BOOTLOADER;
+                }
+
+                regs.write32(lock, MISC_LOCK_KEY)?;
+                regs.write32(lock + RST_CON, 1)?;
+                regs.write32(flag, value)?;
+                regs.write32(lock, 0)?;
+                Ok(true)
+            }
+            Self::Recovery | Self::Fastboot => {
+                let Some((pwrap, rtc)) = rtc(soc) else {
+                    return Ok(false);
+                };
+
+                let (mask, bits) = match self {
+                    Self::Recovery => (RTC_PDN1_RECOVERY_MASK, RTC_PDN1_RECOVERY),
+                    _ => (RTC_PDN1_FAST_BOOT, RTC_PDN1_FAST_BOOT),
+                };
+                Ok(Pmic { regs, pwrap }.mark(rtc, mask, bits)?.is_some())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    const PWRAP: u32 = 0x1000f000;
+    const RTC: u32 = 0x8000;
+
+    /// Security AO writes and a PMIC behind an always ready wrapper
+    struct Mock {
+        writes: [(u32, u32); 8],
+        count: usize,
+        pmic: [u32; 0x20],
+        /// Read data latched by a read command
+        rdata: Option<u32>,
+        /// Wrapper isn't set up, the channel never gets idle
+        dead: bool,
+    }
+
+    impl Mock {
+        fn new() -> Self {
+            Self {
+                writes: [(0, 0); 8],
+                count: 0,
+                pmic: [0; 0x20],
+                rdata: None,
+                dead: false,
+            }
+        }
+
+        fn rtc(&self, reg: u32) -> u32 {
+            self.pmic[(reg / 2) as usize]
+        }
+    }
+
+    impl Registers for Mock {
+        type Error = Infallible;
+
+        fn read32(&mut self, addr: u32) -> Result<u32, Infallible> {
+            assert_eq!(addr, PWRAP + WACS2_RDATA);
+            Ok(match (self.dead, self.rdata) {
+                (true, _) => 1 << WACS_FSM_SHIFT,
+                (false, Some(data)) => (WACS_FSM_WFVLDCLR << WACS_FSM_SHIFT) | data,
+                (false, None) => WACS_FSM_IDLE,
+            })
+        }
+
+        fn write32(&mut self, addr: u32, value: u32) -> Result<(), Infallible> {
+            match addr {
+                a if a == PWRAP + WACS2_CMD => {
+                    let reg = ((value >> 16) & 0x7fff) << 1;
+                    assert!(
+                        (RTC..RTC + 0x40).contains(&reg),
+                        "{reg:#x} is out of the RTC"
+                    );
+                    let index = ((reg - RTC) / 2) as usize;
+                    if value & WACS2_WRITE != 0 {
+                        self.pmic[index] = value & 0xffff;
+                    } else {
+                        self.rdata = Some(self.pmic[index]);
+                    }
+                }
+                a if a == PWRAP + WACS2_VLDCLR => self.rdata = None,
+                _ => {
+                    self.writes[self.count] = (addr, value);
+                    self.count += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn brom_sets_usbdl_flag() {
+        let mut mock = Mock::new();
+        assert_eq!(ResetTarget::Brom.prepare(&mut mock, SoC::MT6572), Ok(true));
+        assert_eq!(
+            mock.writes[..mock.count],
+            [
+                (0x1000141c, MISC_LOCK_KEY),
+                (0x10001424, 1),
+                (0x10001680, 0x444cfffd),
+                (0x1000141c, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn download_sets_bootloader_bit() {
+        let mut mock = Mock::new();
+        assert_eq!(
+            ResetTarget::Download.prepare(&mut mock, SoC::MT6582),
+            Ok(true)
+        );
+        assert_eq!(mock.writes[2], (0x10001680, 0x444cffff));
+    }
+
+    #[test]
+    fn recovery_marks_rtc() {
+        let mut mock = Mock::new();
+        mock.pmic[(RTC_PDN1 / 2) as usize] = 0x2a;
+        assert_eq!(
+            ResetTarget::Recovery.prepare(&mut mock, SoC::MT6572),
+            Ok(true)
+        );
+        assert_eq!(mock.rtc(RTC_PDN1), 0x1a);
+        assert_eq!(mock.rtc(RTC_PROT), 0x9136);
+        assert_eq!(mock.count, 0);
+    }
+
+    #[test]
+    fn fastboot_marks_rtc() {
+        let mut mock = Mock::new();
+        mock.pmic[(RTC_PDN1 / 2) as usize] = 0x1;
+        assert_eq!(
+            ResetTarget::Fastboot.prepare(&mut mock, SoC::MT6582),
+            Ok(true)
+        );
+        assert_eq!(mock.rtc(RTC_PDN1), 0x81);
+    }
+
+    #[test]
+    fn dead_wrapper_fails() {
+        let mut mock = Mock {
+            dead: true,
+            ..Mock::new()
+        };
+        assert_eq!(
+            ResetTarget::Recovery.prepare(&mut mock, SoC::MT6572),
+            Ok(false)
+        );
+        assert_eq!(mock.rtc(RTC_PDN1), 0);
+    }
+
+    #[test]
+    fn normal_touches_nothing() {
+        let mut mock = Mock::new();
+        assert_eq!(
+            ResetTarget::Normal.prepare(&mut mock, SoC::MT6572),
+            Ok(true)
+        );
+        assert_eq!(mock.count, 0);
+        assert_eq!(mock.pmic, [0; 0x20]);
+    }
+
+    #[test]
+    fn unknown_soc_is_unsupported() {
+        for target in [
+            ResetTarget::Brom,
+            ResetTarget::Download,
+            ResetTarget::Recovery,
+            ResetTarget::Fastboot,
+        ] {
+            assert!(!target.is_supported(SoC::MT6589));
+            assert_eq!(target.prepare(&mut Mock::new(), SoC::MT6589), Ok(false));
+        }
+        assert!(ResetTarget::Normal.is_supported(SoC::MT6589));
+    }
+}
@@ -16,6 +16,7 @@ mod macros;
 mod setup;
 mod uart;
 mod usb;
+mod watchdog;
 
 static mut PRELOADER_PARAMS: Option<PreloaderRunnerParams> = None;
 static mut LK_PARAMS: Option<LKRunnerParams> = None;
@@ -1,45 +1,279 @@
-use crate::{LK_PARAMS, c_function, uart_println};
-use core::ptr;
+use crate::{LK_PARAMS, PRELOADER_PARAMS, c_function, uart_printfln, uart_println};
+use core::{ffi::CStr, ptr};
+use da_protocol::{LKRunnerParams, WritePolicy};
 use interceptor::hook;
+use shared::flush_cache;
+
+/// Journal of the writes kept in the RAM
+///
+/// Every record is a header followed by the data aligned to 8, later records win.
+mod overlay {
+    use super::*;
+
+    #[repr(C)]
+    struct Record {
+        dst: u64,
+        size: u32,
+        _pad: u32,
+    }
+
+    static mut USED: u32 = 0;
+
+    fn record_size(size: u32) -> u32 {
+        size_of::<Record>() as u32 + ((size + 7) & !7)
+    }
+
+    /// Returns `false` if there's no space left
+    pub unsafe fn store(params: &LKRunnerParams, src: *const u8, dst: u64, size: u32) -> bool {
+        let used = unsafe { USED };
+        if params.overlay_size - used < record_size(size) {
+            return false;
+        }
+
+        unsafe {
+            let record = (params.overlay_addr + used) as *mut Record;
+            record.write(Record { dst, size, _pad: 0 });
+            ptr::copy_nonoverlapping(src, record.add(1).cast::<u8>(), size as usize);
+            USED = used + record_size(size);
+        }
+
+        true
+    }
+
+    /// Replay the writes overlapping the read of `size` bytes at `src`
+    pub unsafe fn apply(params: &LKRunnerParams, src: u64, dst: *mut u8, size: u32) {
+        let mut offset = 0;
+        while offset < unsafe { USED } {
+            let record = (params.overlay_addr + offset) as *const Record;
+            let (start, len) = unsafe { ((*record).dst, (*record).size) };
+            offset += record_size(len);
+
+            let from = start.max(src);
+            let to = (start + len as u64).min(src + size as u64);
+            if from >= to {
+                continue;
+            }
+
+            unsafe {
+                let data = record.add(1).cast::<u8>().add((from - start) as usize);
+                ptr::copy_nonoverlapping(data, dst.add((from - src) as usize), (to - from) as usize);
+            }
+        }
+    }
+}
+
+/// Command line composed for the kernel in the append mode
+static mut CMDLINE: [u8; 2048] = [0; 2048];
+
+/// Put `ours` after `lk` into the `CMDLINE`, truncating if it doesn't fit
+unsafe fn append_cmdline(lk: &[u8], ours: &[u8]) -> *const u8 {
+    let buf = unsafe { &mut *ptr::addr_of_mut!(CMDLINE) };
+    let max = buf.len() - 1;
+
+    let mut len = 0;
+    for part in [lk, b" ", ours] {
+        let n = part.len().min(max - len);
+        buf[len..len + n].copy_from_slice(&part[..n]);
+        len += n;
+    }
+    buf[len] = 0;
+
+    buf.as_ptr()
+}
+
+/// Parts of the display driver logs, drawing them while the framebuffer is set up hangs LK
+const DISPLAY_LOG_MARKERS: [&[u8]; 4] = [b"DISP", b"fb", b"DDP", b"pitch"];
+
+/// Set while `video_printf` runs, it may log through the hooked print function itself
+static mut IN_VIDEO_PRINTF: bool = false;
+
+fn is_display_log(format: &[u8]) -> bool {
+    DISPLAY_LOG_MARKERS.iter().any(|m| format.windows(m.len()).any(|w| w == *m))
+}
 
 pub mod hooks {
     use core::ffi::c_void;
 
     use super::*;
 
+    type ReadFn = unsafe extern "C" fn(*mut c_void, u64, *mut u8, u32) -> u32;
+
+    /// Serve the read from the mapped partition or pass it to the storage
+    unsafe fn read(params: &LKRunnerParams, original: ReadFn, dev: *mut c_void, src: u64, dst: *mut u8, size: u32) -> u32 {
+        let mt_part_get_partition = params.ptr_mt_part_get_partition;
+        let f = unsafe { c_function!(fn(*const u8) -> *const u32, mt_part_get_partition as usize | 1) };
+
+        for part in params.mapped_partitions() {
+            let info = unsafe { f(part.name.as_ptr()) };
+            if info.is_null() {
+                continue;
+            }
+
+            let start = unsafe { (*info.cast::<u8>().add(params.part_start_offset as usize).cast::<u32>() as u64) << 9 };
+            if src < start || src >= start + part.size as u64 {
+                continue;
+            }
+
+            let offset = (src - start) as u32;
+            let len = size.min(part.size - offset);
+            uart_printfln!("serving {:#x} bytes at {:#x} from the RAM", len, offset);
+
+            unsafe {
+                ptr::copy_nonoverlapping((part.addr + offset) as *const u8, dst, len as usize);
+            }
+
+            // the content is shorter than the partition, let the storage handle the rest
+            if len < size {
+                return len + unsafe { original(dev, src + len as u64, dst.add(len as usize), size - len) };
+            }
+
+            return size;
+        }
+
+        unsafe { original(dev, src, dst, size) }
+    }
+
     hook! {
         fn mt_part_generic_read(dev: *mut c_void, src: u64, dst: *mut u8, size: u32) -> u32 {
             let Some(ref params) = LK_PARAMS else {
                 panic!("LK parameters are not valid");
             };
 
-            let mt_part_get_partition = params.ptr_mt_part_get_partition;
-            let f = unsafe { c_function!(fn(*const u8) -> *const u32, mt_part_get_partition as usize | 1) };
-            let mut part = unsafe { f(b"BOOTIMG\0".as_ptr()) };
-            let mut offset = 12;
+            let original = unsafe { c_function!(fn(*mut c_void, u64, *mut u8, u32) -> u32, mt_part_generic_read::original() as usize | 1) };
+            let read = unsafe { read(params, original, dev, src, dst, size) };
 
-            // new devices have boot instead of BOOTIMG
-            if part.is_null() {
-                part = unsafe { f(b"boot\0".as_ptr()) };
-                offset = 0;
+            if params.write_policy == Some(WritePolicy::Overlay) {
+                unsafe { overlay::apply(params, src, dst, read) };
             }
 
-            if !part.is_null() {
-                let addr = unsafe { (*part.cast::<u8>().add(offset).cast::<u32>() as u64) << 9 };
-                let delta = (src - addr) as u32;
+            return read;
+        }
+    }
 
-                if delta <= 0x1000 {
-                    uart_println!("replacing boot.img");
+    hook! {
+        fn mt_part_generic_write(dev: *mut c_void, src: *const u8, dst: u64, size: u32) -> u32 {
+            let Some(ref params) = LK_PARAMS else {
+                panic!("LK parameters are not valid");
+            };
 
-                    unsafe {
-                        ptr::copy_nonoverlapping((params.bootimg_scratch_addr + delta) as _, dst, size as usize);
+            let lba = (dst >> 9) as u32;
+            match params.write_policy {
+                Some(WritePolicy::Discard) => {
+                    uart_printfln!("discarding write of {:#x} bytes at LBA {:#x}", size, lba);
+                    size
+                }
+                Some(WritePolicy::Overlay) => {
+                    if unsafe { overlay::store(params, src, dst, size) } {
+                        uart_printfln!("keeping write of {:#x} bytes at LBA {:#x} in the RAM", size, lba);
+                    } else {
+                        uart_printfln!("overlay is full, discarding write of {:#x} bytes at LBA {:#x}", size, lba);
                     }
-
-                    return size;
+                    size
                 }
+                Some(WritePolicy::Log) | None => {
+                    uart_printfln!("writing {:#x} bytes at LBA {:#x}", size, lba);
+                    unsafe { c_function!(fn(*mut c_void, *const u8, u64, u32) -> u32, mt_part_generic_write::original() as usize | 1)(dev, src, dst, size) }
+                }
+            }
+        }
+    }
+
+    hook! {
+        fn boot_linux(kernel: u32, tags: u32, cmdline: *const u8, machtype: u32, ramdisk: u32, ramdisk_size: u32) {
+            let Some(ref params) = LK_PARAMS else {
+                panic!("LK parameters are not valid");
+            };
+
+            let ours = params.cmdline_addr as *const u8;
+            let cmdline = if params.cmdline_replace || cmdline.is_null() {
+                uart_println!("replacing kernel command line");
+                ours
+            } else {
+                uart_println!("appending to kernel command line");
+                unsafe { append_cmdline(CStr::from_ptr(cmdline.cast()).to_bytes(), CStr::from_ptr(ours.cast()).to_bytes()) }
+            };
+
+            unsafe {
+                c_function!(fn(u32, u32, *const u8, u32, u32, u32), boot_linux::original() as usize | 1)(kernel, tags, cmdline, machtype, ramdisk, ramdisk_size)
+            }
+        }
+    }
+
+    /// LK `printf`, mirrored to the screen with `video_printf`
+    ///
+    /// The thunk doesn't touch the registers and the stack of the caller, so the original gets every
+    /// argument. Only the mirror is limited to the format and the three arguments in registers.
+    pub mod lk_printf {
+        use interceptor::Interceptor;
+
+        use super::*;
+
+        #[allow(static_mut_refs)]
+        static mut ADDR: usize = 0;
+
+        /// Mirror, then tail call the original with the caller's registers, stack and return address
+        #[unsafe(naked)]
+        unsafe extern "C" fn thunk() {
+            core::arch::naked_asm!(
+                // r4 keeps the stack aligned to 8
+                "push {{r0-r3, r4, lr}}",
+                "bl {mirror}",
+                "bl {original}",
+                "mov r12, r0",
+                "pop {{r0-r3, r4, lr}}",
+                "bx r12",
+                mirror = sym mirror,
+                original = sym original,
+            );
+        }
+
+        unsafe extern "C" fn mirror(format: *const u8, r1: u32, r2: u32, r3: u32) {
+            let Some(ref params) = LK_PARAMS else {
+                panic!("LK parameters are not valid");
+            };
+
+            if format.is_null() || unsafe { IN_VIDEO_PRINTF } || is_display_log(unsafe { CStr::from_ptr(format.cast()).to_bytes() }) {
+                return;
+            }
+
+            unsafe {
+                IN_VIDEO_PRINTF = true;
+                c_function!(fn(*const u8, u32, u32, u32), params.ptr_video_printf as usize | 1)(format, r1, r2, r3);
+                IN_VIDEO_PRINTF = false;
+            }
+        }
+
+        /// Trampoline of the original, Thumb
+        extern "C" fn original() -> usize {
+            unsafe { Interceptor::original(ADDR) }.unwrap_or(ADDR) | 1
+        }
+
+        pub unsafe fn replace(target: usize) -> interceptor::Result<()> {
+            unsafe {
+                Interceptor::replace(target, thunk)?;
+                ADDR = target;
+            }
+            Ok(())
+        }
+    }
+
+    hook! {
+        fn bldr_load_part(r0: u32, r1: u32, r2: u32, addr: u32) -> i32 {
+            let Some(ref params) = PRELOADER_PARAMS else {
+                panic!("Preloader parameters are not valid");
+            };
+
+            if addr != params.lk_load_addr {
+                return unsafe { c_function!(fn(u32, u32, u32, u32) -> i32, bldr_load_part::original() as usize | 1)(r0, r1, r2, addr) };
+            }
+
+            uart_printfln!("loading LK from {:#x} ({:#x} bytes)", params.lk_image_addr, params.lk_image_size);
+            unsafe {
+                ptr::copy(params.lk_image_addr as *const u8, addr as *mut u8, params.lk_image_size as usize);
+                flush_cache(addr as usize, params.lk_image_size as usize);
             }
 
-            return unsafe { c_function!(fn(*mut c_void, u64, *mut u8, u32) -> u32, mt_part_generic_read::original() as usize | 1)(dev, src, dst, size) };
+            0
         }
     }
 }
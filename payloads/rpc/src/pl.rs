@@ -16,8 +16,10 @@ mod err;
 mod hooks;
 mod macros;
 mod setup;
+mod storage;
 mod uart;
 mod usb;
+mod watchdog;
 
 const HEAP_SIZE: usize = 1 * 1024 * 1024;
 
@@ -5,6 +5,8 @@ use core::{
 
 use acon::SoC;
 use da_params::{BlacklistMode, CURRENT_VERSION, MAGIC, PayloadParams};
+#[cfg(feature = "pl")]
+use da_protocol::PreloaderRunnerParams;
 use shared::flush_icache;
 
 use crate::{c_function, err::ParamsError, uart_print, uart_printfln, uart_println};
@@ -95,6 +97,27 @@ pub unsafe extern "C" fn is_bootrom() -> bool {
     unsafe { where_am_i() < 0x40000000 }
 }
 
+/// Load the images with `bldr_load_images`, the installed hooks are active. Returns the LK entry
+#[cfg(feature = "pl")]
+pub unsafe fn load_images(params: &PreloaderRunnerParams) -> Option<u32> {
+    let mut jump_addr = 0;
+    let ret = unsafe { c_function!(fn(*mut u32) -> i32, params.ptr_bldr_load_images as usize | 1)(&mut jump_addr) };
+
+    (ret == 0).then_some(jump_addr)
+}
+
+/// Finish the boot flow like the preloader `main`: post process, then jump to LK with the boot argument
+#[cfg(feature = "pl")]
+pub unsafe fn finish_boot(params: &PreloaderRunnerParams, jump_addr: u32) -> ! {
+    uart_printfln!("jumping to LK at {:#x}", jump_addr);
+    unsafe {
+        c_function!(fn(), params.ptr_bldr_post_process as usize | 1)();
+        c_function!(fn(u32, u32, u32), params.ptr_bldr_jump as usize | 1)(jump_addr, params.bootarg_addr, params.bootarg_size);
+    }
+
+    die("bldr_jump returned");
+}
+
 pub fn die(why: &str) -> ! {
     uart_print!("HALTED: ");
     uart_println!(why);
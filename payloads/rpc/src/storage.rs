@@ -0,0 +1,105 @@
+use core::{ffi::c_void, slice};
+
+use da_protocol::{BLOCK_SIZE, EmmcPart, PreloaderRunnerParams, ProtocolError};
+
+use crate::c_function;
+
+/// `blkdev_t` of the preloader storage driver
+#[repr(C)]
+struct BlkDev {
+    _type: u32,
+    blksz: u32,
+    _erasesz: u32,
+    blks: u32,
+    _offset: u32,
+    _blkbuf: *mut u8,
+    _priv: *mut c_void,
+    _next: *mut BlkDev,
+    /// `bread(bdev, blknr, blks, buf, part_id)`, older drivers don't take `part_id`
+    bread: unsafe extern "C" fn(*mut BlkDev, u32, u32, *mut u8, u32) -> i32,
+    /// `bwrite(bdev, blknr, blks, buf, part_id)`, same as `bread`, missing in some drivers
+    bwrite: Option<unsafe extern "C" fn(*mut BlkDev, u32, u32, *const u8, u32) -> i32>,
+}
+
+/// Boot device registered by the preloader
+unsafe fn boot_device(params: &PreloaderRunnerParams) -> Result<&'static mut BlkDev, ProtocolError> {
+    let bdev = unsafe { c_function!(fn(u32) -> *mut BlkDev, params.ptr_blkdev_get as usize | 1)(params.boot_dev) };
+    match unsafe { bdev.as_mut() } {
+        Some(bdev) if bdev.blksz == BLOCK_SIZE => Ok(bdev),
+        Some(_) => Err(ProtocolError::NotSupported),
+        None => Err(ProtocolError::Storage),
+    }
+}
+
+/// Scratch buffer for `count` blocks
+fn scratch(params: &PreloaderRunnerParams, count: u32) -> Result<*mut u8, ProtocolError> {
+    match count.checked_mul(BLOCK_SIZE) {
+        Some(size) if count != 0 && size <= params.scratch_size => Ok(params.scratch_addr as *mut u8),
+        _ => Err(ProtocolError::InvalidParams),
+    }
+}
+
+/// Whether `bread` of the boot device selects the partition by `part_id`, probed once
+static mut HONORS_PART_ID: Option<bool> = None;
+
+/// Older drivers read the current partition whatever `part_id` is. The last block of the user
+/// area is past the end of the boot partitions, so only a driver ignoring `part_id` reads it.
+unsafe fn honors_part_id(bdev: &mut BlkDev, part: EmmcPart) -> bool {
+    if let Some(honors) = unsafe { HONORS_PART_ID } {
+        return honors;
+    }
+
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    let honors = unsafe { (bdev.bread)(bdev, bdev.blks - 1, 1, block.as_mut_ptr(), part as u32) } != 0;
+    unsafe { HONORS_PART_ID = Some(honors) };
+    honors
+}
+
+/// Read `count` blocks at `lba` into the scratch buffer
+///
+/// Fails with `NotSupported` for the boot partitions if the driver can't select them.
+pub unsafe fn read(params: &PreloaderRunnerParams, lba: u32, count: u32, part: EmmcPart) -> Result<&'static [u8], ProtocolError> {
+    let buf = scratch(params, count)?;
+    let bdev = unsafe { boot_device(params)? };
+
+    if part != EmmcPart::User && !unsafe { honors_part_id(bdev, part) } {
+        return Err(ProtocolError::NotSupported);
+    }
+
+    if unsafe { (bdev.bread)(bdev, lba, count, buf, part as u32) } != 0 {
+        return Err(ProtocolError::Storage);
+    }
+
+    Ok(unsafe { slice::from_raw_parts(buf, (count * BLOCK_SIZE) as usize) })
+}
+
+/// Scratch buffer for the data of `count` blocks to write
+pub unsafe fn write_buffer(params: &PreloaderRunnerParams, count: u32) -> Result<&'static mut [u8], ProtocolError> {
+    let buf = scratch(params, count)?;
+    Ok(unsafe { slice::from_raw_parts_mut(buf, (count * BLOCK_SIZE) as usize) })
+}
+
+/// Write `count` blocks from the scratch buffer at `lba` through the driver `bwrite`
+///
+/// The driver selects `part` by itself. A driver ignoring `part_id` never leaves the user area,
+/// so only the user area is written with it, the boot partitions fail with `NotSupported`.
+pub unsafe fn write(params: &PreloaderRunnerParams, lba: u32, count: u32, part: EmmcPart) -> Result<(), ProtocolError> {
+    let buf = scratch(params, count)?;
+    let bdev = unsafe { boot_device(params)? };
+    let bwrite = bdev.bwrite.ok_or(ProtocolError::NotSupported)?;
+
+    if part != EmmcPart::User && !unsafe { honors_part_id(bdev, part) } {
+        return Err(ProtocolError::NotSupported);
+    }
+
+    if unsafe { bwrite(bdev, lba, count, buf, part as u32) } != 0 {
+        return Err(ProtocolError::Storage);
+    }
+
+    Ok(())
+}
+
+/// Size of the user area in blocks
+pub unsafe fn block_count(params: &PreloaderRunnerParams) -> Result<u32, ProtocolError> {
+    Ok(unsafe { boot_device(params)? }.blks)
+}
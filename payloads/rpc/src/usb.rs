@@ -1,19 +1,21 @@
 use core::{arch::asm, convert::Infallible, mem::transmute};
 
-use acon::MMIO;
 use da_protocol::{HookId, Message, ParamsType, Protocol, ProtocolError, Response};
 use derive_ctor::ctor;
-use shared::flush_cache;
+use shared::{disable_mmu_and_caches, flush_cache};
 use simpleport::{SimpleRead, SimpleWrite};
 
 use crate::{
     LK_PARAMS, PRELOADER_PARAMS, c_function, die,
     setup::{get_params, get_params_mut},
-    uart_printfln, uart_println,
+    uart_printfln, uart_println, watchdog,
 };
 
 #[cfg(feature = "pl")]
-use crate::hooks::hooks;
+use crate::{hooks::hooks, storage};
+
+/// Timeout of the first byte of a read with the `Kick` policy, far below the watchdog timeout
+const KICK_INTERVAL_MS: u32 = 500;
 
 #[derive(ctor)]
 pub struct USB {
@@ -24,7 +26,20 @@ impl SimpleRead for USB {
     type Error = Infallible;
 
     fn read(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
-        unsafe { (self.recv)(buf.as_mut_ptr(), buf.len() as u32, 0) };
+        let mut buf = buf;
+        if watchdog::keeps_alive()
+            && let [first, rest @ ..] = buf
+        {
+            // the preloader `recv` fails on timeout and the bytes read so far are lost, only a single byte is safe to wait for
+            while unsafe { (self.recv)(first, 1, KICK_INTERVAL_MS) } != 0 {
+                watchdog::kick();
+            }
+            buf = rest;
+        }
+
+        if !buf.is_empty() {
+            unsafe { (self.recv)(buf.as_mut_ptr(), buf.len() as u32, 0) };
+        }
         Ok(())
     }
 }
@@ -42,6 +57,7 @@ pub unsafe fn handler() -> ! {
     let params = get_params();
     let usb = unsafe { USB::new(transmute(params.ptr_dl as usize), transmute(params.ptr_ul as usize)) };
     let mut protocol = Protocol::new(usb);
+    watchdog::init();
 
     uart_println!("send");
     if protocol.send_message(Message::Ack).is_err() {
@@ -79,6 +95,7 @@ pub unsafe fn handler() -> ! {
                 Message::Jump { addr, r0, r1 } => unsafe {
                     #[cfg(not(feature = "pl"))]
                     {
+                        watchdog::restore();
                         asm!("dsb; isb");
                         c_function!(fn(u32, u32), addr as usize)(r0.unwrap_or_default(), r1.unwrap_or_default());
                         Response::Nack(ProtocolError::Unreachable)
@@ -87,6 +104,7 @@ pub unsafe fn handler() -> ! {
                     #[cfg(feature = "pl")]
                     {
                         if let Some(ref params) = PRELOADER_PARAMS {
+                            watchdog::restore();
                             asm!("dsb; isb");
                             c_function!(fn(u32, u32, u32), params.ptr_bldr_jump as usize | 1)(addr, r0.unwrap_or_default(), r1.unwrap_or_default());
                             Response::Nack(ProtocolError::Unreachable)
@@ -95,10 +113,7 @@ pub unsafe fn handler() -> ! {
                         }
                     }
                 },
-                Message::Reset => unsafe {
-                    ((get_params().soc.toprgu() + 0x14) as *mut u32).write_volatile(0x1209);
-                    Response::ack()
-                },
+                Message::Reset { target } => Response::Nack(watchdog::reset_to(target)),
                 Message::Hook(id) => {
                     #[cfg(feature = "pl")]
                     match id {
@@ -111,6 +126,50 @@ pub unsafe fn handler() -> ! {
                                 Response::Nack(ProtocolError::InvalidParams)
                             }
                         },
+                        HookId::BldrLoadPart => unsafe {
+                            if let Some(ref params) = PRELOADER_PARAMS
+                                && params.ptr_bldr_load_part != 0
+                            {
+                                hooks::bldr_load_part::replace(params.ptr_bldr_load_part as usize | 1);
+                                uart_println!("replaced bldr_load_part");
+                                Response::Ack
+                            } else {
+                                Response::Nack(ProtocolError::InvalidParams)
+                            }
+                        },
+                        HookId::MtPartGenericWrite => unsafe {
+                            if let Some(ref params) = LK_PARAMS
+                                && params.write_policy.is_some()
+                            {
+                                hooks::mt_part_generic_write::replace(params.ptr_mt_part_generic_write as usize | 1);
+                                uart_println!("replaced mt_part_generic_write");
+                                Response::Ack
+                            } else {
+                                Response::Nack(ProtocolError::InvalidParams)
+                            }
+                        },
+                        HookId::BootLinux => unsafe {
+                            if let Some(ref params) = LK_PARAMS
+                                && params.ptr_boot_linux != 0
+                            {
+                                hooks::boot_linux::replace(params.ptr_boot_linux as usize | 1);
+                                uart_println!("replaced boot_linux");
+                                Response::Ack
+                            } else {
+                                Response::Nack(ProtocolError::InvalidParams)
+                            }
+                        },
+                        HookId::LkScreenLog => unsafe {
+                            if let Some(ref params) = LK_PARAMS
+                                && params.ptr_lk_printf != 0
+                            {
+                                hooks::lk_printf::replace(params.ptr_lk_printf as usize | 1);
+                                uart_println!("replaced LK printf");
+                                Response::Ack
+                            } else {
+                                Response::Nack(ProtocolError::InvalidParams)
+                            }
+                        },
                     }
                     #[cfg(not(feature = "pl"))]
                     Response::Nack(ProtocolError::NotSupported)
@@ -157,6 +216,132 @@ pub unsafe fn handler() -> ! {
                         }
                     }
                 },
+                Message::Continue => unsafe {
+                    #[cfg(not(feature = "pl"))]
+                    {
+                        Response::Nack(ProtocolError::NotSupported)
+                    }
+
+                    #[cfg(feature = "pl")]
+                    {
+                        if let Some(ref params) = PRELOADER_PARAMS
+                            && params.has_boot_flow()
+                        {
+                            match crate::setup::load_images(params) {
+                                Some(jump_addr) => {
+                                    let _ = protocol.send_response(Response::Ack);
+                                    watchdog::restore();
+                                    crate::setup::finish_boot(params, jump_addr)
+                                }
+                                None => {
+                                    uart_println!("bldr_load_images failed");
+                                    Response::Nack(ProtocolError::Storage)
+                                }
+                            }
+                        } else {
+                            Response::Nack(ProtocolError::InvalidParams)
+                        }
+                    }
+                },
+                Message::BootKernel { addr, machine_id, tags } => unsafe {
+                    let _ = protocol.send_response(Response::Ack);
+                    uart_printfln!("booting kernel at {:#x}, tags at {:#x}", addr, tags);
+
+                    watchdog::restore();
+                    asm!("cpsid if");
+                    disable_mmu_and_caches();
+                    c_function!(fn(u32, u32, u32), addr as usize)(0, machine_id, tags);
+                    Response::Nack(ProtocolError::Unreachable)
+                },
+                Message::BlockRead { lba, count, part } => unsafe {
+                    #[cfg(not(feature = "pl"))]
+                    {
+                        Response::Nack(ProtocolError::NotSupported)
+                    }
+
+                    #[cfg(feature = "pl")]
+                    {
+                        if let Some(ref params) = PRELOADER_PARAMS
+                            && params.has_blkdev()
+                        {
+                            match storage::read(params, lba, count, part) {
+                                Ok(data) => {
+                                    let _ = protocol.send_response(Response::Ack);
+                                    protocol.io.write(data);
+                                    Response::Ack
+                                }
+                                Err(e) => {
+                                    uart_printfln!("block read at {:#x} failed", lba);
+                                    Response::Nack(e)
+                                }
+                            }
+                        } else {
+                            Response::Nack(ProtocolError::InvalidParams)
+                        }
+                    }
+                },
+                Message::BlockCount => unsafe {
+                    #[cfg(not(feature = "pl"))]
+                    {
+                        Response::Nack(ProtocolError::NotSupported)
+                    }
+
+                    #[cfg(feature = "pl")]
+                    {
+                        if let Some(ref params) = PRELOADER_PARAMS
+                            && params.has_blkdev()
+                        {
+                            match storage::block_count(params) {
+                                Ok(count) => Response::BlockCount(count),
+                                Err(e) => Response::Nack(e),
+                            }
+                        } else {
+                            Response::Nack(ProtocolError::InvalidParams)
+                        }
+                    }
+                },
+                Message::BlockWrite { lba, count, part } => unsafe {
+                    #[cfg(not(feature = "pl"))]
+                    {
+                        Response::Nack(ProtocolError::NotSupported)
+                    }
+
+                    #[cfg(feature = "pl")]
+                    {
+                        if let Some(ref params) = PRELOADER_PARAMS
+                            && params.has_blkdev()
+                        {
+                            match storage::write_buffer(params, count) {
+                                Ok(buf) => {
+                                    let _ = protocol.send_response(Response::Ack);
+                                    protocol.io.read(buf);
+                                    match storage::write(params, lba, count, part) {
+                                        Ok(()) => Response::Ack,
+                                        Err(e) => {
+                                            uart_printfln!("block write at {:#x} failed", lba);
+                                            Response::Nack(e)
+                                        }
+                                    }
+                                }
+                                Err(e) => Response::Nack(e),
+                            }
+                        } else {
+                            Response::Nack(ProtocolError::InvalidParams)
+                        }
+                    }
+                },
+                Message::WatchdogDisable => {
+                    watchdog::disable();
+                    Response::Ack
+                }
+                Message::WatchdogEnable { timeout_ms } => {
+                    watchdog::enable(timeout_ms);
+                    Response::Ack
+                }
+                Message::WatchdogKick => {
+                    watchdog::kick();
+                    Response::Ack
+                }
             },
             Err(e) => {
                 uart_println!("Error reading message");
@@ -0,0 +1,125 @@
+use acon::MMIO;
+use core::convert::Infallible;
+
+use da_params::WatchdogPolicy;
+use da_protocol::{
+    ProtocolError,
+    reset::{Registers, ResetTarget},
+};
+
+use crate::{setup::get_params, uart_printfln, uart_println};
+
+/// Mode register, writes need the key in the top byte
+const WDT_MODE: u32 = 0x0;
+const WDT_MODE_KEY: u32 = 0x22000000;
+const WDT_MODE_EN: u32 = 1 << 0;
+/// Timeout in 1/64 s ticks from bit 5, writes need the key in the low bits
+const WDT_LENGTH: u32 = 0x4;
+const WDT_LENGTH_KEY: u32 = 0x8;
+const WDT_LENGTH_MAX_TICKS: u32 = 0x7ff;
+const WDT_RESTART: u32 = 0x8;
+const WDT_RESTART_KEY: u32 = 0x1971;
+const WDT_SWRST: u32 = 0x14;
+const WDT_SWRST_KEY: u32 = 0x1209;
+
+/// Watchdog state the payload was started with
+struct Saved {
+    mode: u32,
+    length: u32,
+}
+
+static mut SAVED: Option<Saved> = None;
+
+fn read(offset: u32) -> u32 {
+    unsafe { ((get_params().soc.toprgu() + offset) as *const u32).read_volatile() }
+}
+
+fn write(offset: u32, value: u32) {
+    unsafe { ((get_params().soc.toprgu() + offset) as *mut u32).write_volatile(value) };
+}
+
+fn set_mode(mode: u32) {
+    write(WDT_MODE, WDT_MODE_KEY | (mode & !0xff000000));
+}
+
+pub fn kick() {
+    write(WDT_RESTART, WDT_RESTART_KEY);
+}
+
+pub fn disable() {
+    set_mode(read(WDT_MODE) & !WDT_MODE_EN);
+}
+
+pub fn enable(timeout_ms: u32) {
+    let ticks = (timeout_ms.saturating_mul(64) / 1000).clamp(1, WDT_LENGTH_MAX_TICKS);
+    write(WDT_LENGTH, (ticks << 5) | WDT_LENGTH_KEY);
+    kick();
+    set_mode(read(WDT_MODE) | WDT_MODE_EN);
+}
+
+/// Reset the device right away
+pub fn reset() {
+    write(WDT_SWRST, WDT_SWRST_KEY);
+}
+
+struct Mmio;
+
+impl Registers for Mmio {
+    type Error = Infallible;
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Self::Error> {
+        Ok(unsafe { (addr as *const u32).read_volatile() })
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Self::Error> {
+        unsafe { (addr as *mut u32).write_volatile(value) };
+        Ok(())
+    }
+}
+
+/// Reset the device into `target`, returns the error if it doesn't happen
+pub fn reset_to(target: ResetTarget) -> ProtocolError {
+    let Ok(prepared) = target.prepare(&mut Mmio, get_params().soc);
+    if !prepared {
+        return ProtocolError::NotSupported;
+    }
+
+    reset();
+    ProtocolError::Unreachable
+}
+
+/// Save the watchdog state and apply the policy, called once the payload starts
+pub fn init() {
+    unsafe {
+        SAVED = Some(Saved {
+            mode: read(WDT_MODE),
+            length: read(WDT_LENGTH),
+        })
+    };
+
+    match get_params().watchdog {
+        WatchdogPolicy::Keep => (),
+        WatchdogPolicy::Kick if keeps_alive() => kick(),
+        // the BootROM `recv` has no timeout, nothing could kick the watchdog while it waits
+        WatchdogPolicy::Kick | WatchdogPolicy::Disable => {
+            disable();
+            uart_println!("watchdog is off until the final jump");
+        }
+    }
+}
+
+/// The USB reads kick the watchdog while they wait for the host, only the preloader `recv`
+/// takes a timeout
+pub fn keeps_alive() -> bool {
+    cfg!(feature = "pl") && get_params().watchdog == WatchdogPolicy::Kick
+}
+
+/// Put back the watchdog state the payload was started with, the next stage expects it
+pub fn restore() {
+    if let Some(saved) = unsafe { SAVED.as_ref() } {
+        write(WDT_LENGTH, (saved.length & !0x1f) | WDT_LENGTH_KEY);
+        kick();
+        set_mode(saved.mode);
+        uart_printfln!("watchdog restored, mode {:#x}", saved.mode);
+    }
+}
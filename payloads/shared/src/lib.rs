@@ -86,3 +86,68 @@ macro_rules! search {
         crate::search_pattern($start, $end, PATTERN)
     }};
 }
+
+/// Clean and invalidate the whole D-cache by set/way
+pub unsafe fn clean_invalidate_dcache_all() {
+    let clidr: u32;
+    unsafe { asm!("mrc p15, 1, {}, c0, c0, 1", out(reg) clidr, options(nomem, nostack)) };
+
+    // level of coherency
+    let loc = (clidr >> 24) & 7;
+    for level in 0..loc {
+        // skip levels without data cache
+        if (clidr >> (level * 3)) & 7 < 2 {
+            continue;
+        }
+
+        let ccsidr: u32;
+        unsafe {
+            asm!("mcr p15, 2, {}, c0, c0, 0", in(reg) level << 1, options(nomem, nostack));
+            asm!("isb", options(nomem, nostack));
+            asm!("mrc p15, 1, {}, c0, c0, 0", out(reg) ccsidr, options(nomem, nostack));
+        }
+
+        let line_shift = (ccsidr & 7) + 4;
+        let ways = ((ccsidr >> 3) & 0x3ff) + 1;
+        let sets = ((ccsidr >> 13) & 0x7fff) + 1;
+        let way_shift = (ways - 1).leading_zeros();
+
+        for way in 0..ways {
+            for set in 0..sets {
+                let value =
+                    way.checked_shl(way_shift).unwrap_or(0) | (set << line_shift) | (level << 1);
+                // DCCISW
+                unsafe {
+                    asm!("mcr p15, 0, {}, c7, c14, 2", in(reg) value, options(nomem, nostack))
+                };
+            }
+        }
+    }
+
+    unsafe {
+        asm!("mcr p15, 2, {}, c0, c0, 0", in(reg) 0, options(nomem, nostack));
+        asm!("dsb", options(nomem, nostack));
+        asm!("isb", options(nomem, nostack));
+    }
+}
+
+/// Clean the caches, then disable MMU, D-cache and I-cache as Linux boot protocol wants
+pub unsafe fn disable_mmu_and_caches() {
+    unsafe {
+        clean_invalidate_dcache_all();
+
+        let mut sctlr: u32;
+        asm!("mrc p15, 0, {}, c1, c0, 0", out(reg) sctlr, options(nomem, nostack));
+        // M, C and I bits
+        sctlr &= !((1 << 0) | (1 << 2) | (1 << 12));
+        asm!("mcr p15, 0, {}, c1, c0, 0", in(reg) sctlr, options(nomem, nostack));
+        asm!("isb", options(nomem, nostack));
+
+        flush_icache();
+
+        // TLBIALL
+        asm!("mcr p15, 0, {}, c8, c7, 0", in(reg) 0, options(nomem, nostack));
+        asm!("dsb", options(nomem, nostack));
+        asm!("isb", options(nomem, nostack));
+    }
+}